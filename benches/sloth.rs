@@ -1,11 +1,10 @@
 use criterion::criterion_group;
 use criterion::criterion_main;
 use criterion::Criterion;
-use rug::integer::Order;
 use rug::{rand::RandState, Integer};
 use std::time::{SystemTime, UNIX_EPOCH};
 use subspace_core_rust::crypto;
-use subspace_core_rust::sloth::Sloth;
+use subspace_core_rust::sloth::{Decoder, Encoder, GmpSloth};
 use subspace_core_rust::PIECE_SIZE;
 
 pub fn criterion_benchmark(c: &mut Criterion) {
@@ -22,7 +21,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         let mut rand = RandState::new();
         rand.seed(&seed);
         let mut data = Integer::from(Integer::random_bits(prime_size as u32, &mut rand));
-        let sloth = Sloth::init(prime_size);
+        let sloth = GmpSloth::init(prime_size);
 
         group.bench_function(format!("{} bits/encode-block", prime_size), |b| {
             b.iter(|| {
@@ -38,15 +37,12 @@ pub fn criterion_benchmark(c: &mut Criterion) {
 
         let iv = crypto::random_bytes_32();
         let expanded_iv = crypto::expand_iv(iv);
-        let integer_expanded_iv = Integer::from_digits(&expanded_iv, Order::Lsf);
         let mut piece = crypto::generate_random_piece();
         let layers = PIECE_SIZE / sloth.block_size_bytes;
 
         group.bench_function(format!("{} bits/encode-piece", prime_size), |b| {
             b.iter(|| {
-                sloth
-                    .encode(&mut piece, &integer_expanded_iv, layers)
-                    .unwrap();
+                sloth.encode(&mut piece, expanded_iv, layers);
             })
         });
 
@@ -0,0 +1,50 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use subspace_core_rust::crypto;
+use subspace_core_rust::sloth::{Decoder, Encoder, Sloth256};
+use subspace_core_rust::PIECE_SIZE;
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Sloth256");
+    group.sample_size(10);
+
+    let sloth = Sloth256::init();
+    let mut block = crypto::random_bytes_32();
+    // clear the top bit, so that the block is smaller than the prime
+    block[31] &= 0x7f;
+
+    group.bench_function("encode-block", |b| {
+        b.iter(|| {
+            sloth.encode_block(&mut block).unwrap();
+        })
+    });
+
+    group.bench_function("decode-block", |b| {
+        b.iter(|| {
+            sloth.decode_block(&mut block);
+        })
+    });
+
+    let iv = crypto::random_bytes_32();
+    let expanded_iv = crypto::expand_iv(iv);
+    let mut piece = crypto::generate_random_piece();
+    let layers = PIECE_SIZE / 32;
+
+    group.bench_function("encode-piece", |b| {
+        b.iter(|| {
+            sloth.encode(&mut piece, expanded_iv, layers);
+        })
+    });
+
+    group.bench_function("decode-piece-single", |b| {
+        b.iter(|| {
+            sloth.decode(&mut piece, expanded_iv, layers);
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -1,7 +1,7 @@
-use crate::transaction::CoinbaseTx;
+use crate::transaction::{CoinbaseTx, TxId};
 use crate::{
-    crypto, sloth, state, BlockId, ContentId, ProofId, Tag, ENCODING_LAYERS_TEST,
-    PIECES_PER_STATE_BLOCK, TX_BLOCKS_PER_PROPOSER_BLOCK,
+    crypto, state, BlockHeaderId, BlockId, ContentId, ExpandedIV, PieceId, ProofId, Salt, Tag,
+    ENCODING_SCHEME, PIECES_PER_STATE_BLOCK, TX_BLOCKS_PER_PROPOSER_BLOCK,
 };
 use ed25519_dalek::{PublicKey, Signature};
 use log::{debug, error, warn};
@@ -13,7 +13,13 @@ pub struct Block {
     pub proof: Proof,
     pub coinbase_tx: CoinbaseTx,
     pub content: Content,
+    /// ids of all unseen tx blocks (proposer blocks) or all unseen txs (tx block), committed to
+    /// by `Content::refs_root`
+    /// first ref is always the coinbase tx for this block
+    pub refs: Vec<[u8; 32]>,
     pub data: Option<Data>,
+    /// signature of the block header with same public key
+    pub header_signature: Vec<u8>,
 }
 
 impl Block {
@@ -31,12 +37,65 @@ impl Block {
         crypto::digest_sha_256(&self.to_bytes())
     }
 
+    /// Header of this block, which can be checked and sent without the body
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            proof: self.proof.clone(),
+            content_id: self.content.get_id(),
+            parent_id: self.content.parent_id,
+            timestamp: self.content.timestamp,
+            tx_root: self.content.refs_root,
+            proof_signature: self.content.proof_signature.clone(),
+            signature: self.header_signature.clone(),
+        }
+    }
+
+    /// Everything but the header of this block
+    pub fn body(&self) -> BlockBody {
+        BlockBody {
+            coinbase_tx: self.coinbase_tx.clone(),
+            content: self.content.clone(),
+            refs: self.refs.clone(),
+            data: self.data.clone(),
+        }
+    }
+
+    /// Joins a header with a body received separately, `None` if the body doesn't belong to it
+    pub fn from_header_and_body(header: BlockHeader, body: BlockBody) -> Option<Self> {
+        if !header.matches(&body) {
+            return None;
+        }
+
+        Some(Block {
+            proof: header.proof,
+            coinbase_tx: body.coinbase_tx,
+            content: body.content,
+            refs: body.refs,
+            data: body.data,
+            header_signature: header.signature,
+        })
+    }
+
+    /// Merkle proof that `id` is one of the refs, checked against `Content::refs_root`
+    pub fn ref_proof(&self, id: &[u8; 32]) -> Option<Vec<u8>> {
+        let index = self.refs.iter().position(|ref_id| ref_id == id)?;
+        Some(crypto::create_merkle_tree(&self.refs).1.swap_remove(index))
+    }
+
+    /// Encoding of this block and the expanded IV to decode it with, `None` without auxiliary data
+    pub fn encoding_to_decode(&self) -> Option<(Vec<u8>, ExpandedIV)> {
+        let data = self.data.as_ref()?;
+        let id = crypto::digest_sha_256(&self.proof.public_key);
+        let expanded_iv = ENCODING_SCHEME.expanded_iv(id, self.proof.piece_index);
+        Some((data.encoding.clone(), expanded_iv))
+    }
+
+    /// Checks everything but the encoding, which is slow to decode, see `has_valid_encoding`
     pub fn is_valid(
         &self,
         state: &state::State,
         epoch_randomness: &[u8; 32],
         slot_challenge: &[u8; 32],
-        sloth: &sloth::Sloth,
     ) -> bool {
         // ensure we have the auxiliary data
         if self.data.is_none() {
@@ -50,18 +109,12 @@ impl Block {
             return false;
         }
 
-        let public_key = PublicKey::from_bytes(&self.proof.public_key).unwrap();
-        let proof_signature = Signature::from_bytes(&self.content.proof_signature).unwrap();
-
-        // is the proof signature valid?
-        if public_key
-            .verify_strict(&self.proof.get_id(), &proof_signature)
-            .is_err()
-        {
-            error!("Invalid block, proof signature is invalid!");
+        // are the header and proof signatures valid?
+        if !self.header().is_valid() {
             return false;
         }
 
+        let public_key = PublicKey::from_bytes(&self.proof.public_key).unwrap();
         let content_signature = Signature::from_bytes(&self.content.signature).unwrap();
         let mut content = self.content.clone();
         content.signature.clear();
@@ -75,6 +128,12 @@ impl Block {
             return false;
         }
 
+        // does the content commit to the refs?
+        if refs_root(&self.refs) != self.content.refs_root {
+            error!("Invalid block, refs do not match the refs root of the content!");
+            return false;
+        }
+
         // is coinbase tx valid
         if !self.coinbase_tx.is_valid(&self.proof) {
             error!("Invalid block, coinbase tx is invalid!");
@@ -82,8 +141,8 @@ impl Block {
         }
 
         // is coinbase first tx in the block
-        if self.coinbase_tx.get_id() != self.content.refs[0] {
-            error!("Invalid block, coinbase tx is not the first ref in content!");
+        if self.refs.first() != Some(&self.coinbase_tx.get_id()) {
+            error!("Invalid block, coinbase tx is not the first ref!");
             return false;
         }
 
@@ -145,18 +204,15 @@ impl Block {
         }
 
         // is the tag valid for the encoding and salt?
-        let derived_tag = crypto::create_hmac(
-            &self.data.as_ref().unwrap().encoding,
-            &self.proof.nonce.to_le_bytes(),
-        );
-        let derived_tag: Tag = derived_tag[0..8].try_into().unwrap();
+        let derived_tag =
+            crypto::create_tag(&self.data.as_ref().unwrap().encoding, self.proof.salt);
         if derived_tag != self.proof.tag {
             error!(
                 "Invalid block, tag is invalid: {} vs {}",
                 hex::encode(&self.proof.tag),
                 hex::encode(&derived_tag)
             );
-            // return false;
+            return false;
         }
 
         let state_block_index = self.proof.piece_index / PIECES_PER_STATE_BLOCK as u64;
@@ -179,22 +235,124 @@ impl Block {
             return false;
         }
 
-        // is the encoding valid for the public key and index?
-        let id = crypto::digest_sha_256(&self.proof.public_key);
-        let expanded_iv = crypto::expand_iv(id);
-        let layers = ENCODING_LAYERS_TEST;
-        let mut decoding = self.data.as_ref().unwrap().encoding.clone();
-
-        sloth.decode(decoding.as_mut(), expanded_iv, layers);
-        let decoding_hash = crypto::digest_sha_256(&decoding);
-        if &self.data.as_ref().unwrap().piece_hash != &decoding_hash {
-            error!("Invalid block, encoding is invalid");
-            // utils::compare_bytes(&proof.encoding, &proof.encoding, &decoding);
+        true
+    }
+
+    /// Is the encoding valid for the public key and index? `decoding_hash` is the hash of the
+    /// decoded `encoding_to_decode`, as computed by the `DecodingService`
+    pub fn has_valid_encoding(&self, decoding_hash: &PieceId) -> bool {
+        match &self.data {
+            Some(data) if &data.piece_hash == decoding_hash => true,
+            _ => {
+                error!("Invalid block, encoding is invalid");
+                false
+            }
+        }
+    }
+}
+
+/// Signed summary of a block: its proof and what it commits to of the content, small enough to be
+/// checked and propagated before the body
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct BlockHeader {
+    pub proof: Proof,
+    /// id of the content in the body
+    pub content_id: ContentId,
+    /// content id of parent block that is seen as head of the longest chain (only for proposer block)
+    pub parent_id: Option<ContentId>,
+    /// when this block was created (from Nodes local view)
+    pub timestamp: u64,
+    /// merkle root of the refs, as in the content
+    pub tx_root: [u8; 32],
+    /// signature of the proof with same public key
+    pub proof_signature: Vec<u8>,
+    /// signature of the header with same public key
+    pub signature: Vec<u8>,
+}
+
+impl BlockHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        bincode::deserialize(bytes).map_err(|error| {
+            warn!("Failed to deserialize BlockHeader: {}", error);
+        })
+    }
+
+    pub fn get_id(&self) -> BlockHeaderId {
+        crypto::digest_sha_256(&self.to_bytes())
+    }
+
+    /// Checks everything that doesn't need the body or the state: signatures
+    ///
+    /// The salt depends on the chain, the ledger checks it along with the randomness.
+    pub fn is_valid(&self) -> bool {
+        let public_key = match PublicKey::from_bytes(&self.proof.public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => {
+                error!("Invalid block header, public key is invalid!");
+                return false;
+            }
+        };
+
+        // is the proof signature valid?
+        let proof_signature = Signature::from_bytes(&self.proof_signature);
+        if proof_signature
+            .and_then(|signature| public_key.verify_strict(&self.proof.get_id(), &signature))
+            .is_err()
+        {
+            error!("Invalid block header, proof signature is invalid!");
+            return false;
+        }
+
+        // is the header signature valid?
+        let mut header = self.clone();
+        header.signature.clear();
+        let signature = Signature::from_bytes(&self.signature);
+        if signature
+            .and_then(|signature| public_key.verify_strict(&header.get_id(), &signature))
+            .is_err()
+        {
+            error!("Invalid block header, signature is invalid!");
             return false;
         }
 
         true
     }
+
+    /// Does the body carry the content this header commits to?
+    pub fn matches(&self, body: &BlockBody) -> bool {
+        body.content.get_id() == self.content_id
+            && body.content.proof_id == self.proof.get_id()
+            && body.content.parent_id == self.parent_id
+            && body.content.timestamp == self.timestamp
+            && body.content.proof_signature == self.proof_signature
+            && body.content.refs_root == self.tx_root
+            && refs_root(&body.refs) == self.tx_root
+    }
+}
+
+/// Everything of a block besides its header, can be sent separately and matched to the header
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct BlockBody {
+    pub coinbase_tx: CoinbaseTx,
+    pub content: Content,
+    pub refs: Vec<[u8; 32]>,
+    pub data: Option<Data>,
+}
+
+impl BlockBody {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        bincode::deserialize(bytes).map_err(|error| {
+            warn!("Failed to deserialize BlockBody: {}", error);
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -208,10 +366,10 @@ pub struct Proof {
     pub timeslot: u64,
     /// farmers public key
     pub public_key: [u8; 32],
-    /// hmac of encoding with a nonce
+    /// hmac of encoding with the salt
     pub tag: Tag,
-    /// nonce for salting the tag
-    pub nonce: u64,
+    /// salt of the eon the tag commits to
+    pub salt: Salt,
     /// index of piece for encoding
     pub piece_index: u64,
     // TODO: This property needs to be verified somehow when we receive a proof
@@ -245,9 +403,8 @@ pub struct Content {
     pub proof_signature: Vec<u8>,
     /// when this block was created (from Nodes local view)
     pub timestamp: u64,
-    /// ids of all unseen tx blocks (proposer blocks) or all unseen txs (tx block)
-    /// first ref is always the coinbase tx for this block
-    pub refs: Vec<[u8; 32]>,
+    /// merkle root of the refs of the block, see `refs_root`
+    pub refs_root: [u8; 32],
     // TODO: account for farmers who sign the same proof with two different contents
     /// signature of the content with same public key
     pub signature: Vec<u8>,
@@ -269,6 +426,56 @@ impl Content {
     }
 }
 
+/// Merkle root of the refs of a block, all zeroes without refs
+pub fn refs_root(refs: &[[u8; 32]]) -> [u8; 32] {
+    if refs.is_empty() {
+        return [0u8; 32];
+    }
+
+    crypto::create_merkle_tree(refs).0
+}
+
+/// Encodings of checked blocks with their expanded IVs, for decoding them in one batch
+pub fn encodings_to_decode<'a, I>(blocks: I) -> Vec<(Vec<u8>, ExpandedIV)>
+where
+    I: IntoIterator<Item = &'a Block>,
+{
+    blocks
+        .into_iter()
+        .map(|block| {
+            block
+                .encoding_to_decode()
+                .expect("Valid blocks have auxiliary data")
+        })
+        .collect()
+}
+
+/// Proof that a tx is in a tx block that a proposer block references, which can be checked with
+/// only the refs root of the proposer block (from its content or header)
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct TxInclusionProof {
+    pub tx_id: TxId,
+    /// content of the tx block with the tx, its id is what the proposer block refs
+    pub tx_block_content: Content,
+    /// merkle proof of the tx in the refs of the tx block
+    pub tx_proof: Vec<u8>,
+    /// content id of the proposer block that confirmed the tx block
+    pub proposer_block_content_id: ContentId,
+    /// merkle proof of the tx block in the refs of the proposer block
+    pub tx_block_proof: Vec<u8>,
+}
+
+impl TxInclusionProof {
+    pub fn is_valid(&self, proposer_block_refs_root: &[u8; 32]) -> bool {
+        crypto::validate_merkle_proof(self.tx_id, &self.tx_proof, &self.tx_block_content.refs_root)
+            && crypto::validate_merkle_proof(
+                self.tx_block_content.get_id(),
+                &self.tx_block_proof,
+                proposer_block_refs_root,
+            )
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Data {
     /// the encoding of the piece with public key
@@ -290,3 +497,101 @@ impl Data {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Block signed by random keys that refs its coinbase tx and `refs`, without auxiliary data
+    fn signed_block(refs: &[[u8; 32]]) -> Block {
+        let keys = crypto::gen_keys_random();
+        let proof = Proof {
+            randomness: crypto::random_bytes_32(),
+            epoch: 0,
+            timeslot: 0,
+            public_key: keys.public.to_bytes(),
+            tag: Tag::default(),
+            salt: crypto::genesis_salt(),
+            piece_index: 0,
+            solution_range: 0,
+        };
+        let proof_id = proof.get_id();
+        let coinbase_tx = CoinbaseTx::new(1, keys.public, proof_id);
+        let mut refs = refs.to_vec();
+        refs.insert(0, coinbase_tx.get_id());
+        let mut content = Content {
+            proof_id,
+            parent_id: Some(crypto::random_bytes_32()),
+            proof_signature: keys.sign(&proof_id).to_bytes().to_vec(),
+            timestamp: 0,
+            refs_root: refs_root(&refs),
+            signature: Vec::new(),
+        };
+        content.signature = keys.sign(&content.get_id()).to_bytes().to_vec();
+
+        let mut block = Block {
+            proof,
+            coinbase_tx,
+            content,
+            refs,
+            data: None,
+            header_signature: Vec::new(),
+        };
+        block.header_signature = keys.sign(&block.header().get_id()).to_bytes().to_vec();
+
+        block
+    }
+
+    #[test]
+    fn test_header_and_body() {
+        let block = signed_block(&[crypto::random_bytes_32()]);
+        let header = block.header();
+        assert!(header.is_valid());
+        assert_eq!(
+            Some(block.clone()),
+            Block::from_header_and_body(header.clone(), block.body())
+        );
+
+        // a header of a different block is still valid, but doesn't match the body
+        let other_header = signed_block(&[crypto::random_bytes_32()]).header();
+        assert!(other_header.is_valid());
+        assert_eq!(
+            None,
+            Block::from_header_and_body(other_header, block.body())
+        );
+
+        let mut body = block.body();
+        body.refs.pop();
+        assert_eq!(None, Block::from_header_and_body(header.clone(), body));
+
+        let mut forged_header = header;
+        forged_header.timestamp += 1;
+        assert!(!forged_header.is_valid());
+    }
+
+    #[test]
+    fn test_tx_inclusion_proof() {
+        let tx_ids: Vec<TxId> = (0..5).map(|_| crypto::random_bytes_32()).collect();
+        let tx_block = signed_block(&tx_ids);
+        let tx_block_id = tx_block.content.get_id();
+        let proposer_block = signed_block(&[crypto::random_bytes_32(), tx_block_id]);
+
+        for tx_id in tx_ids.iter() {
+            let proof = TxInclusionProof {
+                tx_id: *tx_id,
+                tx_block_content: tx_block.content.clone(),
+                tx_proof: tx_block.ref_proof(tx_id).unwrap(),
+                proposer_block_content_id: proposer_block.content.get_id(),
+                tx_block_proof: proposer_block.ref_proof(&tx_block_id).unwrap(),
+            };
+            assert!(proof.is_valid(&proposer_block.content.refs_root));
+            assert!(!proof.is_valid(&tx_block.content.refs_root));
+
+            let mut forged_proof = proof;
+            forged_proof.tx_id = crypto::random_bytes_32();
+            assert!(!forged_proof.is_valid(&proposer_block.content.refs_root));
+        }
+
+        assert_eq!(None, tx_block.ref_proof(&crypto::random_bytes_32()));
+    }
+}
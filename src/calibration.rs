@@ -0,0 +1,198 @@
+use crate::sloth::{Decoder, Encoder};
+use crate::{
+    crypto, ExpandedIV, Piece, ENCODING_SCHEME, MAX_LATE_TIMESLOTS, PLOT_SIZE,
+    RECOMMIT_GRACE_TIMESLOTS, SALT_UPDATE_DELAY_IN_TIMESLOTS, TIMESLOT_DURATION,
+};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How fast this machine encodes and decodes pieces with a given number of layers
+#[derive(Debug, Clone)]
+pub struct CalibrationReport {
+    pub layers: usize,
+    /// random pieces the times are averaged over
+    pub pieces: usize,
+    /// time to encode a single piece on one core
+    pub encode_time: Duration,
+    /// time to decode a single piece on one core
+    pub decode_time: Duration,
+    /// pieces encoded per second on all cores, as when plotting
+    pub plotting_rate: f64,
+}
+
+impl fmt::Display for CalibrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Layers: {}", self.layers)?;
+        writeln!(f, "Encode time per piece: {:?}", self.encode_time)?;
+        writeln!(f, "Decode time per piece: {:?}", self.decode_time)?;
+        writeln!(
+            f,
+            "Encode/decode ratio: {:.1}",
+            self.encode_time.as_secs_f64() / self.decode_time.as_secs_f64()
+        )?;
+        write!(
+            f,
+            "Plotting rate: {:.1} pieces/s on {} threads",
+            self.plotting_rate,
+            rayon::current_num_threads()
+        )
+    }
+}
+
+/// Encodes and decodes `pieces` random pieces with `layers` layers of the running encoding scheme
+pub fn calibrate(layers: usize, pieces: usize) -> CalibrationReport {
+    let sloth = ENCODING_SCHEME.sloth();
+    let node_id = crypto::random_bytes_32();
+    let pieces = pieces.max(1);
+    let originals: Vec<Piece> = (0..pieces)
+        .map(|_| crypto::generate_random_piece())
+        .collect();
+    let expanded_ivs: Vec<ExpandedIV> = (0..pieces as u64)
+        .map(|piece_index| ENCODING_SCHEME.expanded_iv(node_id, piece_index))
+        .collect();
+
+    let mut encodings = originals.clone();
+    let started = Instant::now();
+    for (encoding, &expanded_iv) in encodings.iter_mut().zip(expanded_ivs.iter()) {
+        sloth.encode(encoding, expanded_iv, layers);
+    }
+    let encode_time = started.elapsed() / pieces as u32;
+
+    let mut decodings = encodings.clone();
+    let started = Instant::now();
+    for (decoding, &expanded_iv) in decodings.iter_mut().zip(expanded_ivs.iter()) {
+        sloth.decode(decoding, expanded_iv, layers);
+    }
+    let decode_time = started.elapsed() / pieces as u32;
+
+    let mut encodings = originals;
+    let started = Instant::now();
+    sloth.encode_batch(&mut encodings, &expanded_ivs, layers);
+    let plotting_rate = pieces as f64 / started.elapsed().as_secs_f64();
+
+    CalibrationReport {
+        layers,
+        pieces,
+        encode_time,
+        decode_time,
+        plotting_rate,
+    }
+}
+
+/// Whether this machine could re-encode pieces on demand instead of storing them, i.e. keep only
+/// the tags of a plot and encode a piece once its tag wins a challenge
+#[derive(Debug, Clone)]
+pub struct OnDemandEncodingReport {
+    pub layers: usize,
+    /// time from a challenge until a block for it is no longer accepted, the timeslot itself and
+    /// `MAX_LATE_TIMESLOTS` after it
+    pub timeslot_budget: Duration,
+    /// time to re-encode the winning piece on one core
+    pub encode_time: Duration,
+    /// pieces that can be dropped from a plot and still be re-tagged on all cores every eon
+    ///
+    /// The salt of the next eon can't be known before the block that closes the current eon, so
+    /// all re-encoding has to happen after that block, before tags committed to the previous salt
+    /// are no longer accepted.
+    pub droppable_pieces: u64,
+    /// fewest layers that make re-encoding a single piece take longer than the timeslot budget
+    pub safe_layers: usize,
+}
+
+impl OnDemandEncodingReport {
+    pub fn from_calibration(report: &CalibrationReport) -> Self {
+        let timeslot_budget = Duration::from_millis((MAX_LATE_TIMESLOTS + 1) * TIMESLOT_DURATION);
+        let encode_time_per_layer = report.encode_time.as_secs_f64() / report.layers as f64;
+        let safe_layers = (timeslot_budget.as_secs_f64() / encode_time_per_layer).ceil() as usize;
+        let retagging_window = Duration::from_millis(
+            (SALT_UPDATE_DELAY_IN_TIMESLOTS + RECOMMIT_GRACE_TIMESLOTS) * TIMESLOT_DURATION,
+        );
+
+        OnDemandEncodingReport {
+            layers: report.layers,
+            timeslot_budget,
+            encode_time: report.encode_time,
+            droppable_pieces: (report.plotting_rate * retagging_window.as_secs_f64()) as u64,
+            safe_layers: safe_layers.max(1),
+        }
+    }
+
+    /// Re-encoding a winning piece fits into the timeslot, so storing its encoding is not needed
+    pub fn is_profitable(&self) -> bool {
+        self.encode_time < self.timeslot_budget
+    }
+}
+
+impl fmt::Display for OnDemandEncodingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Layers: {}", self.layers)?;
+        writeln!(
+            f,
+            "Timeslot budget: {:?} (the timeslot and {} late timeslots)",
+            self.timeslot_budget, MAX_LATE_TIMESLOTS
+        )?;
+        writeln!(
+            f,
+            "Re-encode time per piece: {:?} ({:.2}% of the timeslot budget)",
+            self.encode_time,
+            100.0 * self.encode_time.as_secs_f64() / self.timeslot_budget.as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            "Pieces that can be re-tagged between the next salt becoming known and the previous one expiring: {} ({:.1}% of a {} piece plot)",
+            self.droppable_pieces,
+            100.0 * self.droppable_pieces as f64 / PLOT_SIZE as f64,
+            PLOT_SIZE
+        )?;
+        if self.is_profitable() {
+            write!(
+                f,
+                "On-demand encoding is profitable on this machine, it takes at least {} layers to prevent it",
+                self.safe_layers
+            )
+        } else {
+            write!(f, "On-demand encoding is not profitable on this machine")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibrate() {
+        let report = calibrate(2, 2);
+        assert_eq!(2, report.layers);
+        assert_eq!(2, report.pieces);
+        assert!(report.encode_time > Duration::from_secs(0));
+        assert!(report.plotting_rate > 0.0);
+    }
+
+    #[test]
+    fn test_on_demand_encoding_report() {
+        let report = CalibrationReport {
+            layers: 2,
+            pieces: 1,
+            encode_time: Duration::from_millis(TIMESLOT_DURATION / 4),
+            decode_time: Duration::from_millis(1),
+            plotting_rate: 10.0,
+        };
+        let on_demand = OnDemandEncodingReport::from_calibration(&report);
+        assert!(on_demand.is_profitable());
+        // 8 layers fit into the timeslot and into each of the late timeslots
+        assert_eq!(8 * (MAX_LATE_TIMESLOTS as usize + 1), on_demand.safe_layers);
+        assert_eq!(
+            10 * (SALT_UPDATE_DELAY_IN_TIMESLOTS + RECOMMIT_GRACE_TIMESLOTS) * TIMESLOT_DURATION
+                / 1000,
+            on_demand.droppable_pieces
+        );
+
+        let report = CalibrationReport {
+            layers: 8,
+            encode_time: on_demand.timeslot_budget,
+            ..report
+        };
+        assert!(!OnDemandEncodingReport::from_calibration(&report).is_profitable());
+    }
+}
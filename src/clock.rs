@@ -0,0 +1,141 @@
+//! Source of time for the timer, ledger and manager
+//!
+//! Everything that reads the current time or sleeps until some point in time goes through a
+//! [`Clock`], so that time-dependent logic can be driven by [`ManualClock`] in tests and
+//! simulations instead of the wall clock. [`AdjustedClock`] corrects the local clock by the
+//! offset from the network estimated with [`ClockOffset`].
+
+mod offset;
+
+use futures::channel::oneshot;
+use futures::future;
+use futures::future::BoxFuture;
+pub use offset::{AdjustedClock, ClockOffset};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub trait Clock: Send + Sync {
+    /// Current time in milliseconds since UNIX epoch
+    fn now(&self) -> u64;
+
+    /// Resolves once the clock has reached `timestamp` (milliseconds since UNIX epoch)
+    fn sleep_until(&self, timestamp: u64) -> BoxFuture<'static, ()>;
+}
+
+/// Wall clock time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64
+    }
+
+    fn sleep_until(&self, timestamp: u64) -> BoxFuture<'static, ()> {
+        let duration = Duration::from_millis(timestamp.saturating_sub(self.now()));
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    now: u64,
+    sleepers: Vec<(u64, oneshot::Sender<()>)>,
+}
+
+/// Clock that only moves when told to, waking up sleepers whose time has come
+#[derive(Default)]
+pub struct ManualClock {
+    inner: Mutex<Inner>,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        ManualClock {
+            inner: Mutex::new(Inner {
+                now,
+                sleepers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Moves the clock to `timestamp`, the clock never goes backwards
+    pub fn set(&self, timestamp: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if timestamp <= inner.now {
+            return;
+        }
+        inner.now = timestamp;
+
+        let (ready, sleeping) = inner
+            .sleepers
+            .drain(..)
+            .partition(|(wake_at, _)| *wake_at <= timestamp);
+        inner.sleepers = sleeping;
+        drop(inner);
+
+        for (_, sender) in ready.into_iter() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Moves the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        let now = self.now();
+        self.set(now + duration.as_millis() as u64);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.inner.lock().unwrap().now
+    }
+
+    fn sleep_until(&self, timestamp: u64) -> BoxFuture<'static, ()> {
+        let mut inner = self.inner.lock().unwrap();
+        if timestamp <= inner.now {
+            return Box::pin(future::ready(()));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        inner.sleepers.push((timestamp, sender));
+
+        Box::pin(async move {
+            let _ = receiver.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_manual_clock_wakes_sleepers_in_time() {
+        let clock = ManualClock::new(1000);
+
+        let mut early = clock.sleep_until(1500);
+        let mut late = clock.sleep_until(3000);
+        assert!(clock.sleep_until(1000).now_or_never().is_some());
+
+        clock.advance(Duration::from_millis(499));
+        assert!((&mut early).now_or_never().is_none());
+
+        clock.advance(Duration::from_millis(1));
+        assert!((&mut early).now_or_never().is_some());
+        assert!((&mut late).now_or_never().is_none());
+
+        // going backwards is ignored
+        clock.set(0);
+        assert_eq!(clock.now(), 1500);
+
+        clock.set(5000);
+        assert!(late.now_or_never().is_some());
+    }
+}
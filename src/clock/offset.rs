@@ -0,0 +1,253 @@
+use super::{Clock, SharedClock};
+use crate::{MAX_EARLY_TIMESLOTS, MAX_LATE_TIMESLOTS, TIMESLOT_DURATION};
+use futures::future::BoxFuture;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+/// How many offsets derived from received blocks are kept, at most one per farmer
+const MAX_BLOCK_SAMPLES: usize = 32;
+/// How many of the offsets derived from received blocks may be of blocks from the same peer, such
+/// that a peer relaying blocks of many keys (its own or not) can't take all samples
+const MAX_BLOCK_SAMPLES_PER_PEER: usize = 4;
+
+#[derive(Default)]
+struct Samples {
+    /// latest offset measured against each peer
+    by_peer: HashMap<SocketAddr, i64>,
+    /// half of the latest ping round trip to each peer
+    delay_by_peer: HashMap<SocketAddr, u64>,
+    /// offsets derived from the most recent valid blocks, by the public key of their farmer and
+    /// the peer they were received from
+    from_blocks: VecDeque<([u8; 32], SocketAddr, i64)>,
+}
+
+impl Samples {
+    fn peer_median(&self) -> i64 {
+        median(self.by_peer.values().copied().collect())
+    }
+}
+
+/// Estimate of how far the local clock is from the network, in milliseconds
+///
+/// Positive offset means the local clock is behind the network, negative means it is ahead.
+#[derive(Default)]
+pub struct ClockOffset {
+    samples: Mutex<Samples>,
+}
+
+impl ClockOffset {
+    /// Records an offset measured with a ping that took `round_trip` ms, replacing any previous
+    /// sample for this peer
+    pub fn add_peer_sample(&self, peer_addr: SocketAddr, offset: i64, round_trip: u64) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.by_peer.insert(peer_addr, offset);
+        samples.delay_by_peer.insert(peer_addr, round_trip / 2);
+    }
+
+    /// Forgets the sample of a peer that could not be pinged
+    pub fn remove_peer_sample(&self, peer_addr: &SocketAddr) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.by_peer.remove(peer_addr);
+        samples.delay_by_peer.remove(peer_addr);
+    }
+
+    /// Records an offset derived from a valid block of `farmer` for the timeslot that started at
+    /// `timeslot_start` (network time), received from `peer_addr` at `received_at` (local time)
+    ///
+    /// Farmers propose some time after their timeslot started, so the block is assumed to be sent
+    /// in the middle of its timeslot and to take as long as a ping to arrive. Any previous sample
+    /// of the same farmer is replaced, a peer holds at most `MAX_BLOCK_SAMPLES_PER_PEER` samples,
+    /// and the sample is clamped to within a timeslot of the peer estimate, so that farmers
+    /// withholding blocks or sending them late can shift the estimate by at most a timeslot.
+    pub fn add_block_sample(
+        &self,
+        farmer: [u8; 32],
+        peer_addr: &SocketAddr,
+        timeslot_start: u64,
+        received_at: u64,
+    ) {
+        let mut samples = self.samples.lock().unwrap();
+        let delay = samples.delay_by_peer.get(peer_addr).copied().unwrap_or(0);
+        let peer_median = samples.peer_median();
+        let sent_at = timeslot_start + TIMESLOT_DURATION / 2;
+        let offset = (sent_at + delay) as i64 - received_at as i64;
+        let offset = offset
+            .max(peer_median - TIMESLOT_DURATION as i64)
+            .min(peer_median + TIMESLOT_DURATION as i64);
+
+        samples
+            .from_blocks
+            .retain(|(sampled_farmer, _, _)| *sampled_farmer != farmer);
+        let peer_samples = samples
+            .from_blocks
+            .iter()
+            .filter(|(_, sampled_peer, _)| sampled_peer == peer_addr)
+            .count();
+        if peer_samples == MAX_BLOCK_SAMPLES_PER_PEER {
+            let oldest = samples
+                .from_blocks
+                .iter()
+                .position(|(_, sampled_peer, _)| sampled_peer == peer_addr)
+                .expect("Peer has samples");
+            samples.from_blocks.remove(oldest);
+        } else if samples.from_blocks.len() == MAX_BLOCK_SAMPLES {
+            samples.from_blocks.pop_front();
+        }
+        samples.from_blocks.push_back((farmer, *peer_addr, offset));
+    }
+
+    /// Median of all samples, zero if there are none
+    pub fn median(&self) -> i64 {
+        let samples = self.samples.lock().unwrap();
+        median(
+            samples
+                .by_peer
+                .values()
+                .copied()
+                .chain(samples.from_blocks.iter().map(|(_, _, offset)| *offset))
+                .collect(),
+        )
+    }
+
+    /// Whether blocks produced with the local clock would be rejected by peers as too early or
+    /// too late, in which case the node should not farm
+    pub fn exceeds_max_drift(&self) -> bool {
+        let offset = self.median();
+        offset > (MAX_EARLY_TIMESLOTS * TIMESLOT_DURATION) as i64
+            || offset < -((MAX_LATE_TIMESLOTS * TIMESLOT_DURATION) as i64)
+    }
+}
+
+/// Local clock corrected by the median offset estimated from peers
+pub struct AdjustedClock {
+    local: SharedClock,
+    offset: ClockOffset,
+}
+
+impl AdjustedClock {
+    pub fn new(local: SharedClock) -> Self {
+        AdjustedClock {
+            local,
+            offset: ClockOffset::default(),
+        }
+    }
+
+    /// Time of the local clock without any correction applied
+    pub fn local_now(&self) -> u64 {
+        self.local.now()
+    }
+
+    pub fn offset(&self) -> &ClockOffset {
+        &self.offset
+    }
+}
+
+impl Clock for AdjustedClock {
+    fn now(&self) -> u64 {
+        apply_offset(self.local.now(), self.offset.median())
+    }
+
+    fn sleep_until(&self, timestamp: u64) -> BoxFuture<'static, ()> {
+        self.local
+            .sleep_until(apply_offset(timestamp, -self.offset.median()))
+    }
+}
+
+fn median(mut offsets: Vec<i64>) -> i64 {
+    if offsets.is_empty() {
+        return 0;
+    }
+
+    offsets.sort_unstable();
+    let middle = offsets.len() / 2;
+    if offsets.len() % 2 == 0 {
+        (offsets[middle - 1] + offsets[middle]) / 2
+    } else {
+        offsets[middle]
+    }
+}
+
+fn apply_offset(timestamp: u64, offset: i64) -> u64 {
+    if offset >= 0 {
+        timestamp.saturating_add(offset as u64)
+    } else {
+        timestamp.saturating_sub((-offset) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_adjusted_clock_follows_median_offset() {
+        let local = Arc::new(ManualClock::new(100_000));
+        let clock = AdjustedClock::new(local.clone());
+        assert_eq!(clock.now(), 100_000);
+
+        let peers: Vec<SocketAddr> = (0..3)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], 8000 + port)))
+            .collect();
+        clock.offset().add_peer_sample(peers[0], 400, 100);
+        clock.offset().add_peer_sample(peers[1], 500, 100);
+        // a single peer far off can't drag the estimate
+        clock.offset().add_peer_sample(peers[2], -60_000, 100);
+        assert_eq!(clock.offset().median(), 400);
+        assert_eq!(clock.now(), 100_400);
+        assert!(!clock.offset().exceeds_max_drift());
+
+        clock.offset().remove_peer_sample(&peers[2]);
+        assert_eq!(clock.offset().median(), 450);
+
+        for peer_addr in peers.iter() {
+            clock.offset().add_peer_sample(*peer_addr, -20_000, 100);
+        }
+        assert_eq!(clock.now(), 80_000);
+        assert!(clock.offset().exceeds_max_drift());
+    }
+
+    #[test]
+    fn test_block_samples_are_bounded() {
+        let offset = ClockOffset::default();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 8000));
+        offset.add_peer_sample(peer, 0, 100);
+
+        // a block sent in the middle of the timeslot that started 1 s ago, which took 50 ms to
+        // arrive
+        offset.add_block_sample([1u8; 32], &peer, 100_000, 101_050);
+        assert_eq!(offset.median(), -250);
+
+        // however many blocks a farmer sends with however late timeslots, it holds a single
+        // sample of at most a timeslot
+        for timeslot_start in 0..MAX_BLOCK_SAMPLES as u64 {
+            offset.add_block_sample([2u8; 32], &peer, timeslot_start, 100_550);
+        }
+        assert_eq!(
+            offset.samples.lock().unwrap().from_blocks,
+            vec![
+                ([1u8; 32], peer, -500),
+                ([2u8; 32], peer, -(TIMESLOT_DURATION as i64))
+            ]
+        );
+        assert_eq!(offset.median(), -500);
+
+        // a peer relaying blocks of however many keys holds only a few samples
+        let sybil = SocketAddr::from(([127, 0, 0, 1], 8001));
+        for key in 0..MAX_BLOCK_SAMPLES as u8 {
+            offset.add_block_sample([10 + key; 32], &sybil, 0, 100_550);
+        }
+        let samples = offset.samples.lock().unwrap();
+        assert_eq!(
+            samples
+                .from_blocks
+                .iter()
+                .filter(|(_, peer_addr, _)| *peer_addr == sybil)
+                .count(),
+            MAX_BLOCK_SAMPLES_PER_PEER
+        );
+        assert_eq!(samples.from_blocks.len(), 2 + MAX_BLOCK_SAMPLES_PER_PEER);
+    }
+}
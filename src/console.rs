@@ -118,7 +118,9 @@ pub struct AppState {
     pub connections: String,
     pub peers: String,
     pub pieces: String,
+    pub audits: String,
     pub blocks: String,
+    pub clock_offset: String,
 }
 
 pub struct App {
@@ -136,7 +138,9 @@ impl App {
                 connections: String::from(""),
                 peers: String::from(""),
                 pieces: String::from(""),
+                audits: String::from(""),
                 blocks: String::from(""),
+                clock_offset: String::from(""),
             },
             receiver,
         }
@@ -211,9 +215,15 @@ pub fn run(recever: Receiver<AppState>) -> Result<(), Box<dyn Error>> {
                 Text::raw(" Pieces         "),
                 Text::raw(String::from(&app.state.pieces)),
                 Text::raw("\n"),
+                Text::raw(" Audits         "),
+                Text::raw(String::from(&app.state.audits)),
+                Text::raw("\n"),
                 Text::raw(" Blocks         "),
                 Text::raw(String::from(&app.state.blocks)),
                 Text::raw("\n"),
+                Text::raw(" Clock Offset   "),
+                Text::raw(String::from(&app.state.clock_offset)),
+                Text::raw("\n"),
             ];
 
             let paragraph = Paragraph::new(text.iter())
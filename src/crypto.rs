@@ -1,6 +1,8 @@
 use crate::state::MerkleRoot;
-use crate::{ExpandedIV, Piece, IV, IV_SIZE, PRIME_SIZE_BYTES};
-use ed25519_dalek::Keypair;
+use crate::{
+    ExpandedIV, NodeID, Piece, PieceIndex, ProofId, Salt, Tag, IV, IV_SIZE, PRIME_SIZE_BYTES,
+};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
 use merkle_tree_binary::Tree;
 use rand::rngs::{OsRng, StdRng};
 use rand::Rng;
@@ -77,6 +79,15 @@ pub fn expand_iv(iv: IV) -> ExpandedIV {
     expanded_iv
 }
 
+/// IV a piece is encoded with by a node, node id xor little endian piece index
+pub fn piece_iv(node_id: NodeID, piece_index: PieceIndex) -> IV {
+    let mut iv = node_id;
+    for (byte, index_byte) in iv.iter_mut().zip(piece_index.to_le_bytes().iter()) {
+        *byte ^= index_byte;
+    }
+    iv
+}
+
 /// Returns a hash bashed message authentication code unique to a message and challenge.
 pub fn create_hmac(message: &[u8], challenge: &[u8]) -> [u8; 32] {
     let key = hmac::Key::new(hmac::HMAC_SHA256, challenge);
@@ -86,6 +97,25 @@ pub fn create_hmac(message: &[u8], challenge: &[u8]) -> [u8; 32] {
     array
 }
 
+/// Salt that plot tags of eon `eon_index` commit to, derived from the proof id of the block that
+/// closed the previous eon, such that tags can't be computed before that block was solved (unlike
+/// epoch randomness, which is known an epoch ahead)
+pub fn derive_salt(eon_index: u64, proof_id: &ProofId) -> Salt {
+    digest_sha_256(&[&b"salt"[..], &eon_index.to_le_bytes(), &proof_id[..]].concat())[0..8]
+        .try_into()
+        .unwrap()
+}
+
+/// Salt of the first eon, which has no previous eon to close it
+pub fn genesis_salt() -> Salt {
+    derive_salt(0, &[0u8; 32])
+}
+
+/// Commitment of an encoding to a salt, the tag a plot stores the encoding under
+pub fn create_tag(encoding: &[u8], salt: Salt) -> Tag {
+    create_hmac(encoding, &salt)[0..8].try_into().unwrap()
+}
+
 /// Returns a ED25519 key pair from a randomly generated seed.
 pub fn gen_keys_random() -> ed25519_dalek::Keypair {
     let mut csprng = OsRng {};
@@ -98,6 +128,26 @@ pub fn gen_keys_from_seed(seed: u64) -> ed25519_dalek::Keypair {
     Keypair::generate(&mut rng)
 }
 
+/// Returns the key pair of a farmer's plot from the key index recorded in its header, index 0 uses
+/// the wallet key pair itself so that every other plot gets a different node id derived from it
+pub fn derive_plot_keys(keys: &Keypair, plot_index: usize) -> ed25519_dalek::Keypair {
+    if plot_index == 0 {
+        return Keypair::from_bytes(&keys.to_bytes()).expect("Key pair bytes are valid");
+    }
+
+    let seed = digest_sha_256(
+        &[
+            &keys.secret.to_bytes()[..],
+            b"plot",
+            &(plot_index as u64).to_le_bytes()[..],
+        ]
+        .concat(),
+    );
+    let secret = SecretKey::from_bytes(&seed).expect("Any 32 bytes are a valid secret key");
+    let public = PublicKey::from(&secret);
+    Keypair { secret, public }
+}
+
 pub fn create_merkle_tree(ids: &[[u8; 32]]) -> (MerkleRoot, Vec<Vec<u8>>) {
     let vec_ids: Vec<Vec<u8>> = ids.iter().map(|id| id.to_vec()).collect();
     let merkle_tree = Tree::new(&vec_ids, digest_sha_256_simple);
@@ -153,4 +203,15 @@ mod tests {
 
         assert_eq!(genesis_data.len(), 4094)
     }
+
+    #[test]
+    fn salt_follows_closing_proof() {
+        let proof_id = random_bytes_32();
+        assert_eq!(derive_salt(1, &proof_id), derive_salt(1, &proof_id));
+        assert_ne!(derive_salt(1, &proof_id), derive_salt(2, &proof_id));
+        assert_ne!(
+            derive_salt(1, &proof_id),
+            derive_salt(1, &random_bytes_32())
+        );
+    }
 }
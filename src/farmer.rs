@@ -1,11 +1,16 @@
 use crate::manager::ProtocolMessage;
 use crate::plot::Plot;
-use crate::{Piece, Tag};
+use crate::{crypto, NodeID, Piece, Salt, Tag, AUDIT_HISTORY_TIMESLOTS};
 use async_std::sync::{Receiver, Sender};
+use async_std::task;
+use futures::future;
 use log::*;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
 use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Display;
+use std::sync::{Arc, Mutex};
 
 pub enum FarmerMessage {
     /// Challenge to farmer for evaluation
@@ -15,6 +20,8 @@ pub enum FarmerMessage {
         randomness: [u8; 32],
         slot_challenge: [u8; 32],
         solution_range: u64,
+        /// salt tags have to commit to at this timeslot
+        salt: Salt,
     },
     StartFarming,
     StopFarming,
@@ -34,6 +41,17 @@ impl Display for FarmerMessage {
     }
 }
 
+/// One of the plots managed by the farmer, encoded with its own node id
+#[derive(Clone)]
+pub struct FarmerPlot {
+    /// key index recorded in the plot header, used to derive its key pair from the wallet
+    pub index: usize,
+    pub node_id: NodeID,
+    /// maximum number of pieces in this plot
+    pub size: usize,
+    pub plot: Plot,
+}
+
 #[derive(Clone)]
 pub struct Solution {
     /// the epoch index for this block
@@ -44,8 +62,10 @@ pub struct Solution {
     pub randomness: [u8; 32],
     /// index of the piece as it appears in the state
     pub piece_index: u64,
-    /// tag for hmac(encoding||nonce) -> commitment
+    /// tag for hmac(encoding||salt) -> commitment
     pub tag: Tag,
+    /// salt of the eon the tag commits to
+    pub salt: Salt,
     /// the full encoding
     pub encoding: Piece,
     /// merkle proof that encoded piece is in the state chain
@@ -54,16 +74,96 @@ pub struct Solution {
     pub solution_range: u64,
     /// target for this challenge
     pub target: [u8; 8],
+    /// key index of the plot the solution was found in, selects the key pair that signs the block
+    pub plot_index: usize,
+}
+
+/// Outcome of auditing all plots for a single timeslot
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TimeslotAudit {
+    pub timeslot: u64,
+    /// tags within the solution range
+    pub candidates: usize,
+    /// candidates that could be read and were submitted as solutions
+    pub solutions: usize,
+    /// blocks created from the solutions
+    pub blocks_won: usize,
+}
+
+/// Audits of recent timeslots along with totals since the node started
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AuditSummary {
+    pub recent: VecDeque<TimeslotAudit>,
+    pub timeslots: u64,
+    pub candidates: u64,
+    pub solutions: u64,
+    pub blocks_won: u64,
+}
+
+impl Display for AuditSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} candidates, {} solutions, {} blocks in {} timeslots",
+            self.candidates, self.solutions, self.blocks_won, self.timeslots
+        )
+    }
+}
+
+/// Audit results shared between the farmer that audits plots and the manager that turns solutions
+/// into blocks
+#[derive(Clone, Default)]
+pub struct AuditStats {
+    summary: Arc<Mutex<AuditSummary>>,
+}
+
+impl AuditStats {
+    fn record_audit(&self, timeslot: u64, candidates: usize, solutions: usize) {
+        let mut summary = self.summary.lock().unwrap();
+        summary.timeslots += 1;
+        summary.candidates += candidates as u64;
+        summary.solutions += solutions as u64;
+        if summary.recent.len() == AUDIT_HISTORY_TIMESLOTS {
+            summary.recent.pop_front();
+        }
+        summary.recent.push_back(TimeslotAudit {
+            timeslot,
+            candidates,
+            solutions,
+            blocks_won: 0,
+        });
+    }
+
+    /// Records a block created from a solution found at `timeslot`
+    pub fn record_block_won(&self, timeslot: u64) {
+        let mut summary = self.summary.lock().unwrap();
+        summary.blocks_won += 1;
+        if let Some(audit) = summary
+            .recent
+            .iter_mut()
+            .rev()
+            .find(|audit| audit.timeslot == timeslot)
+        {
+            audit.blocks_won += 1;
+        }
+    }
+
+    pub fn summary(&self) -> AuditSummary {
+        self.summary.lock().unwrap().clone()
+    }
 }
 
 pub async fn run(
     timer_to_solver_rx: Receiver<FarmerMessage>,
     solver_to_main_tx: Sender<ProtocolMessage>,
-    plot: &Plot,
+    plots: &[FarmerPlot],
+    audit_stats: AuditStats,
 ) {
     let mut is_farming = true;
+    // plots that are being recommitted
+    let recommitting: Arc<Mutex<HashSet<usize>>> = Arc::default();
 
-    info!("Solve loop is running...");
+    info!("Solve loop is running for {} plot(s)...", plots.len());
     while let Ok(message) = timer_to_solver_rx.recv().await {
         match message {
             FarmerMessage::SlotChallenge {
@@ -72,26 +172,100 @@ pub async fn run(
                 randomness,
                 slot_challenge,
                 solution_range,
+                salt,
             } => {
                 if is_farming {
                     let target = slot_challenge[0..8].try_into().unwrap();
-                    let tags = plot.find_by_range(target, solution_range).await.unwrap();
-                    let mut solutions: Vec<Solution> = Vec::with_capacity(tags.len());
-                    for (tag, piece_index) in tags.into_iter() {
-                        let (encoding, merkle_proof) = plot.read(piece_index).await.unwrap();
-                        solutions.push(Solution {
-                            epoch_index,
-                            timeslot,
-                            randomness,
-                            piece_index: piece_index as u64,
-                            tag,
-                            encoding,
-                            merkle_proof,
-                            solution_range,
-                            target,
-                        });
+
+                    // recommitment reads the whole plot, so it runs in the background, until it is
+                    // done the plot is audited with the previous salt, which is accepted for a while
+                    for farmer_plot in plots.iter() {
+                        if farmer_plot.plot.salt() != salt
+                            && recommitting.lock().unwrap().insert(farmer_plot.index)
+                        {
+                            info!(
+                                "Recommitting plot {} to salt {}",
+                                farmer_plot.index,
+                                hex::encode(&salt)
+                            );
+                            let plot = farmer_plot.plot.clone();
+                            let plot_index = farmer_plot.index;
+                            let recommitting = Arc::clone(&recommitting);
+                            task::spawn(async move {
+                                if let Err(error) = plot.recommit(salt).await {
+                                    error!(
+                                        "Failed to recommit plot {}, retrying on next timeslot: {}",
+                                        plot_index, error
+                                    );
+                                }
+                                recommitting.lock().unwrap().remove(&plot_index);
+                            });
+                        }
                     }
 
+                    // audit all plots concurrently, they are usually on different disks
+                    let audits: Vec<(usize, Vec<Solution>)> = future::join_all(
+                        plots.iter().map(|farmer_plot| async move {
+                            let plot = &farmer_plot.plot;
+                            let salt = plot.salt();
+                            let tags = plot.find_by_range(target, solution_range).await.unwrap();
+                            let candidates = tags.len();
+                            // candidates are read concurrently, so that many of them still fit
+                            // into the timeslot
+                            let solutions = future::join_all(tags.into_iter().map(
+                                |(tag, piece_index)| async move {
+                                    let (encoding, merkle_proof) =
+                                        match plot.read(piece_index).await {
+                                            Ok(piece) => piece,
+                                            Err(error) => {
+                                                warn!(
+                                                    "Failed to read piece {} from plot {}: {}",
+                                                    piece_index, farmer_plot.index, error
+                                                );
+                                                return None;
+                                            }
+                                        };
+                                    // the tag may have been replaced since it was found, e.g. by
+                                    // recommitment, and would not make a valid block
+                                    if crypto::create_tag(&encoding, salt) != tag {
+                                        warn!(
+                                            "Tag of piece {} in plot {} does not match its encoding, skipping it",
+                                            piece_index, farmer_plot.index
+                                        );
+                                        return None;
+                                    }
+                                    Some(Solution {
+                                        epoch_index,
+                                        timeslot,
+                                        randomness,
+                                        piece_index: piece_index as u64,
+                                        tag,
+                                        salt,
+                                        encoding,
+                                        merkle_proof,
+                                        solution_range,
+                                        target,
+                                        plot_index: farmer_plot.index,
+                                    })
+                                },
+                            ))
+                            .await
+                            .into_iter()
+                            .flatten()
+                            .collect();
+
+                            (candidates, solutions)
+                        }),
+                    )
+                    .await;
+
+                    let candidates = audits.iter().map(|(candidates, _)| candidates).sum();
+                    let solutions: Vec<Solution> = audits
+                        .into_iter()
+                        .flat_map(|(_, solutions)| solutions)
+                        .collect();
+                    audit_stats.record_audit(timeslot, candidates, solutions.len());
+
                     debug!(
                         "Found {} solutions for challenge {:?} and solution range ±{} at timeslot {}",
                         solutions.len(),
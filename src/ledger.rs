@@ -1,22 +1,26 @@
-use crate::block::{Block, Content, Data, Proof};
+use crate::block::{self, Block, Content, Data, Proof, TxInclusionProof};
+use crate::clock::{AdjustedClock, Clock, SharedClock};
 use crate::farmer::Solution;
-use crate::timer::EpochTracker;
+use crate::timer::{self, EpochTracker};
 use crate::transaction::{
     AccountAddress, AccountState, CoinbaseTx, SimpleCreditTx, Transaction, TxId,
 };
 use crate::{
-    crypto, sloth, state, ContentId, ProofId, BLOCK_REWARD, CONFIRMATION_DEPTH,
-    ENCODING_LAYERS_TEST, EXPECTED_TIMESLOTS_PER_EON, INITIAL_SOLUTION_RANGE, MAX_EARLY_TIMESLOTS,
-    MAX_LATE_TIMESLOTS, PRIME_SIZE_BITS, PROPOSER_BLOCKS_PER_EON,
-    SOLUTION_RANGE_UPDATE_DELAY_IN_TIMESLOTS, TX_BLOCKS_PER_PROPOSER_BLOCK,
+    crypto, sloth, state, ContentId, ExpandedIV, PieceId, ProofId, Salt, BLOCK_REWARD,
+    CONFIRMATION_DEPTH, ENCODING_SCHEME, EXPECTED_TIMESLOTS_PER_EON, INITIAL_SOLUTION_RANGE,
+    MAX_EARLY_TIMESLOTS, MAX_LATE_TIMESLOTS, PROPOSER_BLOCKS_PER_EON, RECOMMIT_GRACE_TIMESLOTS,
+    SALT_UPDATE_DELAY_IN_TIMESLOTS, SOLUTION_RANGE_UPDATE_DELAY_IN_TIMESLOTS,
+    TX_BLOCKS_PER_PROPOSER_BLOCK, TX_INCLUSION_CACHE_SIZE,
 };
 
 use crate::manager::GenesisConfig;
 use crate::metablocks::{MetaBlock, MetaBlocks};
 use log::*;
+use lru::LruCache;
+use std::cmp::Reverse;
 use std::collections::{BTreeMap, HashMap, HashSet};
-use std::convert::TryInto;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::iter;
+use std::sync::Arc;
 
 /* TESTING
  * Piece count is always 256 for testing for the merkle tree
@@ -41,6 +45,7 @@ pub type Timeslot = u64;
 pub struct Head {
     block_height: u64,
     content_id: ContentId,
+    proof_id: ProofId,
 }
 
 pub struct SolutionRangeUpdate {
@@ -100,6 +105,8 @@ pub struct Ledger {
     pub current_solution_range: u64,
     /// solution range tracker for each eon
     pub solution_ranges_by_eon: HashMap<u64, u64>,
+    /// salt of each eon by the timeslot it takes effect at
+    salts: BTreeMap<Timeslot, Salt>,
     /// container for all txs
     pub txs: HashMap<TxId, Transaction>,
     /// tracker for txs that have not yet been referenced in a tx block
@@ -108,6 +115,8 @@ pub struct Ledger {
     pub unknown_tx_ids: HashSet<TxId>,
     /// tracker for all txs that have been applied to the ledger
     pub applied_tx_ids: HashSet<TxId>,
+    /// tx block and proposer block (content ids) that recently applied txs were first included by
+    pub tx_inclusions: LruCache<TxId, (ContentId, ContentId)>,
     /// tracker for tx blocks that have not yet been referenced in a proposer block
     pub unclaimed_tx_block_ids: HashSet<ContentId>,
     /// tracker for tx blocks that have been referenced in a proposer block buy have not been seen yet
@@ -119,21 +128,25 @@ pub struct Ledger {
     pub timer_is_running: bool,
     pub quality: u32,
     pub keys: ed25519_dalek::Keypair,
-    pub sloth: sloth::Sloth,
+    /// decodes block encodings off the async executor
+    pub decoding_service: sloth::DecodingService,
     pub genesis_timestamp: u64,
     pub genesis_challenge: [u8; 32],
     pub current_timeslot: u64,
+    /// source of time for block timestamps, corrected by the offset estimated from peers
+    pub clock: Arc<AdjustedClock>,
 }
 
 impl Ledger {
+    /// Creates a ledger that decodes block encodings on `decoding_service`, which may be shared
+    /// with other ledgers in the same process
     pub fn new(
         keys: ed25519_dalek::Keypair,
         epoch_tracker: EpochTracker,
         state: state::State,
+        clock: SharedClock,
+        decoding_service: sloth::DecodingService,
     ) -> Ledger {
-        // init sloth
-        let prime_size = PRIME_SIZE_BITS;
-        let sloth = sloth::Sloth::init(prime_size);
         let genesis_challenge = [0u8; 32];
 
         // TODO: all of these data structures need to be periodically truncated
@@ -150,10 +163,12 @@ impl Ledger {
             solution_range_update: SolutionRangeUpdate::new(0, 0, 0),
             current_solution_range: INITIAL_SOLUTION_RANGE,
             solution_ranges_by_eon: HashMap::new(),
+            salts: BTreeMap::new(),
             txs: HashMap::new(),
             unclaimed_tx_ids: HashSet::new(),
             unknown_tx_ids: HashSet::new(),
             applied_tx_ids: HashSet::new(),
+            tx_inclusions: LruCache::new(TX_INCLUSION_CACHE_SIZE),
             unclaimed_tx_block_ids: HashSet::new(),
             unknown_tx_block_ids: HashSet::new(),
             applied_tx_block_ids: HashSet::new(),
@@ -163,15 +178,17 @@ impl Ledger {
             quality: 0,
             state,
             epoch_tracker,
-            sloth,
+            decoding_service,
             keys,
             current_timeslot: 0,
             last_eon_close_timeslot: 0,
+            clock: Arc::new(AdjustedClock::new(clock)),
         };
 
         ledger
             .solution_ranges_by_eon
             .insert(0, INITIAL_SOLUTION_RANGE);
+        ledger.salts.insert(0, crypto::genesis_salt());
 
         ledger
     }
@@ -183,6 +200,30 @@ impl Ledger {
         self.genesis_timestamp = genesis_config.genesis_timestamp;
     }
 
+    /// Salt plot tags have to commit to at `timeslot`
+    pub fn salt_at(&self, timeslot: Timeslot) -> Salt {
+        *self
+            .salts
+            .range(..=timeslot)
+            .next_back()
+            .expect("Genesis salt takes effect at timeslot 0")
+            .1
+    }
+
+    /// Whether tags committed to `salt` are accepted at `timeslot`, which holds for the salt of the
+    /// previous eon as well for `RECOMMIT_GRACE_TIMESLOTS` after the salt changed
+    fn accepts_salt(&self, salt: &Salt, timeslot: Timeslot) -> bool {
+        let mut salts = self.salts.range(..=timeslot).rev();
+        match salts.next() {
+            Some((_, current_salt)) if current_salt == salt => true,
+            Some((changed_at, _)) => {
+                timeslot < changed_at + RECOMMIT_GRACE_TIMESLOTS
+                    && matches!(salts.next(), Some((_, previous_salt)) if previous_salt == salt)
+            }
+            None => false,
+        }
+    }
+
     /// Update the timeslot, then validates and stages all early blocks that have arrived
     pub async fn next_timeslot(&mut self) {
         self.current_timeslot += 1;
@@ -198,20 +239,26 @@ impl Ledger {
                 .insert(eon_index, self.current_solution_range);
         }
 
-        // apply all early blocks
-        if self
-            .early_blocks_by_timeslot
-            .contains_key(&self.current_timeslot)
-        {
-            for block in self
-                .early_blocks_by_timeslot
-                .get(&self.current_timeslot)
-                .unwrap()
-                .clone()
-            {
+        // apply all early blocks, the ones that pass the cheap checks are decoded at once
+        if let Some(blocks) = self.early_blocks_by_timeslot.remove(&self.current_timeslot) {
+            let mut checked_blocks = Vec::with_capacity(blocks.len());
+            for block in blocks {
+                if self.check_block(&block).await {
+                    checked_blocks.push(block);
+                }
+            }
+            let decoding_hashes = self
+                .decoding_service
+                .piece_hashes(block::encodings_to_decode(&checked_blocks))
+                .await;
+
+            for (block, decoding_hash) in checked_blocks.into_iter().zip(decoding_hashes) {
                 debug!("Timeslot has arrived for early block, validating and staging");
 
-                if self.validate_block_that_has_arrived(&block).await {
+                if self
+                    .validate_block_that_has_arrived(&block, decoding_hash)
+                    .await
+                {
                     // TODO: have to make sure we don't reference the block (just check for last timeslot in create block)
                     if block.content.parent_id.is_some() {
                         self.stage_proposer_block(&block).await;
@@ -220,8 +267,6 @@ impl Ledger {
                     }
                 }
             }
-
-            self.early_blocks_by_timeslot.remove(&self.current_timeslot);
         }
     }
 
@@ -252,28 +297,44 @@ impl Ledger {
                     .get_metablock_from_content_id(&block.content.get_id())
             })
             .for_each(|tx_metablock| {
-                tx_metablock
-                    .block
-                    .content
-                    .refs
-                    .iter()
-                    .skip(1)
-                    .for_each(|tx_id| {
-                        let tx = self.txs.get(tx_id).expect("Should have tx").clone();
-                        match tx {
-                            Transaction::Credit(tx) => {
-                                txs.insert(*tx_id, tx);
-                            }
-                            _ => {
-                                panic!("Coinbase tx should not be ref'd in a tx block!");
-                            }
+                tx_metablock.block.refs.iter().skip(1).for_each(|tx_id| {
+                    let tx = self.txs.get(tx_id).expect("Should have tx").clone();
+                    match tx {
+                        Transaction::Credit(tx) => {
+                            txs.insert(*tx_id, tx);
                         }
-                    })
+                        _ => {
+                            panic!("Coinbase tx should not be ref'd in a tx block!");
+                        }
+                    }
+                })
             });
 
         txs.into_values().collect()
     }
 
+    /// Returns a proof that an applied tx is in a tx block that a confirmed proposer block refs,
+    /// only the last `TX_INCLUSION_CACHE_SIZE` applied txs can be proven
+    pub fn get_tx_inclusion_proof(&self, tx_id: &TxId) -> Option<TxInclusionProof> {
+        let (tx_block_content_id, proposer_block_content_id) = self.tx_inclusions.peek(tx_id)?;
+        let tx_block = self
+            .metablocks
+            .get_metablock_from_content_id_as_option(tx_block_content_id)?
+            .block;
+        let proposer_block = self
+            .metablocks
+            .get_metablock_from_content_id_as_option(proposer_block_content_id)?
+            .block;
+
+        Some(TxInclusionProof {
+            tx_id: *tx_id,
+            tx_proof: tx_block.ref_proof(tx_id)?,
+            tx_block_content: tx_block.content,
+            proposer_block_content_id: *proposer_block_content_id,
+            tx_block_proof: proposer_block.ref_proof(tx_block_content_id)?,
+        })
+    }
+
     /// Add a block to a given timeslot, to allow other nodes to sync the ledger
     fn add_block_to_timeslot(&mut self, timeslot: Timeslot, proof_id: ProofId) {
         self.proof_ids_by_timeslot
@@ -283,7 +344,7 @@ impl Ledger {
     }
 
     /// returns the tip of the longest chain as seen by this node
-    fn get_head(&self) -> ContentId {
+    pub fn get_head(&self) -> ContentId {
         if self.heads.is_empty() {
             // TODO: replace with genesis seed
             self.genesis_challenge
@@ -292,38 +353,71 @@ impl Ledger {
         }
     }
 
-    /// updates an existing branch, setting to head if longest, or creates a new branch
+    /// updates an existing branch or creates a new branch, then moves the longest one to the front
     fn update_heads(
         &mut self,
         parent_content_id: ContentId,
         content_id: ContentId,
+        proof_id: ProofId,
         block_height: u64,
     ) {
-        for (index, head) in self.heads.iter_mut().enumerate() {
-            if head.content_id == parent_content_id {
+        match self
+            .heads
+            .iter_mut()
+            .find(|head| head.content_id == parent_content_id)
+        {
+            Some(head) => {
                 // updated existing head
                 head.block_height += 1;
                 head.content_id = content_id;
+                head.proof_id = proof_id;
+            }
+            None => {
+                self.heads.push(Head {
+                    content_id,
+                    proof_id,
+                    block_height,
+                });
 
-                // check if existing branch has overtaken the current head
-                if index != 0 && head.block_height > self.heads[0].block_height {
-                    self.heads.swap(0, index);
-                }
-                return;
+                debug!(
+                    "Added a new head at height: {} w/content_id: {}!",
+                    block_height,
+                    hex::encode(&content_id[0..8])
+                );
             }
         }
 
-        // else create a new branch -- cannot be longest head (unless first head)
-        self.heads.push(Head {
-            content_id,
-            block_height,
-        });
+        // ties go to the lowest proof id rather than the first branch to arrive, so that all
+        // nodes pick the same head whatever order they received blocks in
+        let longest_index = (0..self.heads.len())
+            .min_by_key(|&index| {
+                let head = &self.heads[index];
+                (Reverse(head.block_height), head.proof_id)
+            })
+            .expect("Just added a head");
+        self.heads.swap(0, longest_index);
+    }
 
-        debug!(
-            "Added a new head at height: {} w/content_id: {}!",
-            block_height,
-            hex::encode(&content_id[0..8])
-        );
+    /// Proof ids and epochs of the blocks of the longest chain, from its head back to genesis
+    fn longest_chain(&self) -> impl Iterator<Item = (ProofId, u64)> + '_ {
+        let head = self
+            .metablocks
+            .get_metablock_from_content_id_as_option(&self.get_head());
+        iter::successors(head, move |metablock| {
+            metablock.block.content.parent_id.and_then(|parent_id| {
+                self.metablocks
+                    .get_metablock_from_content_id_as_option(&parent_id)
+            })
+        })
+        .map(|metablock| (metablock.proof_id, metablock.block.proof.epoch))
+    }
+
+    /// Moves the epoch tracker to the next epoch, the epoch it closes gets its randomness from
+    /// its blocks on the longest chain that are confirmed at this point
+    pub async fn advance_epoch(&self) -> u64 {
+        self.epoch_tracker
+            .advance_epoch(|epoch_index| timer::confirmed_branch(self.longest_chain(), epoch_index))
+            .await
     }
 
     /// removes a branch that is equal to the current confirmed ledger
@@ -342,48 +436,54 @@ impl Ledger {
         self.heads.remove(remove_index);
     }
 
-    /// create a new block locally from a valid farming solution
+    /// Encodings of farming solutions with the expanded IVs of the plots they were found in, such
+    /// that all solutions of a timeslot are decoded at once before blocks are created from them
+    pub fn solution_encodings(&self, solutions: &[Solution]) -> Vec<(Vec<u8>, ExpandedIV)> {
+        solutions
+            .iter()
+            .map(|solution| {
+                let public_key = crypto::derive_plot_keys(&self.keys, solution.plot_index).public;
+                let id = crypto::digest_sha_256(public_key.as_bytes());
+                let expanded_iv = ENCODING_SCHEME.expanded_iv(id, solution.piece_index);
+                (solution.encoding.to_vec(), expanded_iv)
+            })
+            .collect()
+    }
+
+    /// create a new block locally from a valid farming solution, `piece_hash` is the hash of its
+    /// decoded encoding, see `solution_encodings`
+    ///
+    /// Returns `None` if the solution doesn't make a valid block, e.g. because its tag is committed
+    /// to a salt that is no longer accepted.
     pub async fn create_and_apply_local_block(
         &mut self,
         solution: Solution,
         sibling_content_ids: Vec<ContentId>,
-    ) -> Block {
+        piece_hash: PieceId,
+    ) -> Option<Block> {
+        // blocks are signed with the key pair of the plot the solution was found in
+        let keys = crypto::derive_plot_keys(&self.keys, solution.plot_index);
+
         let proof = Proof {
             randomness: solution.randomness,
             epoch: solution.epoch_index,
             timeslot: solution.timeslot,
-            public_key: self.keys.public.to_bytes(),
+            public_key: keys.public.to_bytes(),
             tag: solution.tag,
-            // TODO: Fix this
-            nonce: u64::from_le_bytes(
-                crypto::create_hmac(&solution.encoding, b"subspace")[0..8]
-                    .try_into()
-                    .unwrap(),
-            ),
+            salt: solution.salt,
             piece_index: solution.piece_index,
             solution_range: solution.solution_range,
         };
 
-        // TODO: either decode each time or store the piece id in plot
-        let id = crypto::digest_sha_256(&proof.public_key);
-        let expanded_iv = crypto::expand_iv(id);
-        let layers = ENCODING_LAYERS_TEST;
-        let mut decoding = solution.encoding.clone();
-        self.sloth.decode(&mut decoding, expanded_iv, layers);
-        let piece_id = crypto::digest_sha_256(&decoding);
-
         let data = Data {
             encoding: solution.encoding.to_vec(),
             merkle_proof: solution.merkle_proof,
-            piece_hash: piece_id,
+            piece_hash,
         };
 
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis() as u64;
+        let timestamp = self.clock.now();
 
-        // create the coinbase tx
+        // create the coinbase tx, rewards go to the wallet whatever plot the solution is from
         let proof_id = proof.get_id();
         let coinbase_tx = CoinbaseTx::new(BLOCK_REWARD, self.keys.public, proof_id);
         let mut refs = vec![coinbase_tx.get_id()];
@@ -467,43 +567,110 @@ impl Ledger {
         let mut content = Content {
             parent_id,
             proof_id,
-            proof_signature: self.keys.sign(&proof.get_id()).to_bytes().to_vec(),
+            proof_signature: keys.sign(&proof.get_id()).to_bytes().to_vec(),
             timestamp,
-            refs,
+            refs_root: block::refs_root(&refs),
             signature: Vec::new(),
         };
 
-        content.signature = self.keys.sign(&content.get_id()).to_bytes().to_vec();
+        content.signature = keys.sign(&content.get_id()).to_bytes().to_vec();
 
-        if content.parent_id.is_none() {
-            self.unclaimed_tx_block_ids.insert(content.get_id());
-        }
-
-        let block = Block {
+        let mut block = Block {
             proof,
             coinbase_tx,
             content,
+            refs,
             data: Some(data),
+            header_signature: Vec::new(),
         };
 
-        let is_valid = self.validate_block(&block).await;
-        assert!(is_valid, "Local block must always be valid");
+        block.header_signature = keys.sign(&block.header().get_id()).to_bytes().to_vec();
+
+        if !self.validate_block(&block, Some(piece_hash)).await {
+            warn!(
+                "Solution for piece {} at timeslot {} does not make a valid block, skipping it",
+                block.proof.piece_index, block.proof.timeslot
+            );
+
+            // refs are claimed by the next block instead
+            if block.content.parent_id.is_some() {
+                self.unclaimed_tx_block_ids
+                    .extend(block.refs.iter().skip(1).copied());
+            } else {
+                self.unclaimed_tx_ids
+                    .extend(block.refs.iter().skip(1).copied());
+            }
+
+            return None;
+        }
+
+        if block.content.parent_id.is_none() {
+            self.unclaimed_tx_block_ids.insert(block.content.get_id());
+        }
 
-        block
+        Some(block)
     }
 
-    /// Validates that a block is internally consistent
-    async fn validate_block(&self, block: &Block) -> bool {
+    /// Validates that a block is internally consistent, `decoding_hash` is the hash of its decoded
+    /// encoding if it is already known
+    async fn validate_block(&self, block: &Block, decoding_hash: Option<PieceId>) -> bool {
+        if !self.check_block(block).await {
+            return false;
+        }
+
+        // only decode once the cheap checks passed
+        let decoding_hash = match decoding_hash {
+            Some(decoding_hash) => decoding_hash,
+            None => {
+                let (encoding, expanded_iv) = block
+                    .encoding_to_decode()
+                    .expect("Valid blocks have auxiliary data");
+                self.decoding_service
+                    .piece_hash(encoding, expanded_iv)
+                    .await
+            }
+        };
+
+        if !block.has_valid_encoding(&decoding_hash) {
+            // TODO: block list this peer
+            return false;
+        }
+
+        true
+    }
+
+    /// Checks of `validate_block` that don't need the encoding to be decoded
+    async fn check_block(&self, block: &Block) -> bool {
         // TODO: how to validate the genesis block, which has no lookback?
 
         // get correct randomness for this block
-        let (epoch_randomness, slot_challenge) = self
+        let (epoch_randomness, slot_challenge) = match self
             .epoch_tracker
             .get_slot_challenge(block.proof.epoch, block.proof.timeslot)
-            .await;
+            .await
+        {
+            Ok(challenge) => challenge,
+            Err(error) => {
+                warn!(
+                    "Cannot validate block for timeslot {}: {:?}",
+                    block.proof.timeslot, error
+                );
+                return false;
+            }
+        };
+
+        // do tags commit to the salt of the eon, or to the previous one while plots are recommitted?
+        if !self.accepts_salt(&block.proof.salt, block.proof.timeslot) {
+            warn!(
+                "Block for timeslot {} has tags committed to salt {}, which is not accepted then",
+                block.proof.timeslot,
+                hex::encode(&block.proof.salt)
+            );
+            return false;
+        }
 
         // check if the block is valid
-        if !block.is_valid(&self.state, &epoch_randomness, &slot_challenge, &self.sloth) {
+        if !block.is_valid(&self.state, &epoch_randomness, &slot_challenge) {
             // TODO: block list this peer
             return false;
         }
@@ -511,8 +678,30 @@ impl Ledger {
         true
     }
 
-    /// Validates a proposer or tx block received via sync during startup
-    pub async fn validate_block_from_sync(
+    /// Whether a block was farmed with the randomness this node derived for the lookback epoch
+    /// of its epoch
+    pub async fn has_expected_randomness(&self, block: &Block) -> bool {
+        match self
+            .epoch_tracker
+            .get_slot_challenge(block.proof.epoch, block.proof.timeslot)
+            .await
+        {
+            Ok((epoch_randomness, _)) => epoch_randomness == block.proof.randomness,
+            Err(error) => {
+                warn!(
+                    "Cannot check randomness of block for timeslot {}: {:?}",
+                    block.proof.timeslot, error
+                );
+                false
+            }
+        }
+    }
+
+    /// Checks a proposer or tx block received via sync during startup, except for its encoding
+    ///
+    /// The caller decodes the encodings of all blocks of a timeslot that pass at once, without
+    /// holding the ledger, and checks them with `Block::has_valid_encoding`.
+    pub async fn check_block_from_sync(
         &mut self,
         block: &Block,
         current_timeslot: Timeslot,
@@ -567,16 +756,17 @@ impl Ledger {
             }
         }
 
-        // block is valid?
-        if !(self.validate_block(block).await) {
+        // block is valid, besides its encoding?
+        if !(self.check_block(block).await) {
             return false;
         }
 
         true
     }
 
-    /// Validates a proposer or tx block received via gossip
-    pub async fn validate_block_from_gossip(&mut self, block: &Block) -> bool {
+    /// Validates a proposer or tx block received via gossip, besides its encoding, so that blocks
+    /// gossiped together can be decoded at once without holding the ledger
+    pub async fn check_block_from_gossip(&mut self, block: &Block) -> bool {
         debug!(
             "Validating remote block for epoch: {} at timeslot {}",
             block.proof.epoch, block.proof.timeslot
@@ -670,16 +860,40 @@ impl Ledger {
             }
         }
 
-        // is the block valid?
-        if !(self.validate_block(block).await) {
+        // is the block valid, besides its encoding?
+        if !(self.check_block(block).await) {
             return false;
         }
 
         true
     }
 
+    /// Completes validation for cached blocks received via gossip whose parents have been staged,
+    /// the ones that pass the cheap checks are decoded at once, returns the valid ones
+    pub async fn validate_blocks_from_cache(&mut self, blocks: Vec<Block>) -> Vec<Block> {
+        let mut checked_blocks = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            if self.check_block(&block).await {
+                checked_blocks.push(block);
+            }
+        }
+        let decoding_hashes = self
+            .decoding_service
+            .piece_hashes(block::encodings_to_decode(&checked_blocks))
+            .await;
+
+        let mut valid_blocks = Vec::with_capacity(checked_blocks.len());
+        for (block, decoding_hash) in checked_blocks.into_iter().zip(decoding_hashes) {
+            if self.validate_block_from_cache(&block, decoding_hash).await {
+                valid_blocks.push(block);
+            }
+        }
+
+        valid_blocks
+    }
+
     /// Completes validation for a cached proposer block received via gossip whose parent has been staged
-    pub async fn validate_block_from_cache(&mut self, block: &Block) -> bool {
+    async fn validate_block_from_cache(&mut self, block: &Block, decoding_hash: PieceId) -> bool {
         if block.content.parent_id.is_some()
             && block.content.parent_id.unwrap() != self.genesis_challenge
         {
@@ -699,7 +913,7 @@ impl Ledger {
         }
 
         // is the block valid?
-        if !(self.validate_block(block).await) {
+        if !(self.validate_block(block, Some(decoding_hash)).await) {
             return false;
         }
 
@@ -707,9 +921,13 @@ impl Ledger {
     }
 
     /// Completes validation for a proposer block received via gossip that was ahead of the timeslot received in and has now arrived
-    pub async fn validate_block_that_has_arrived(&mut self, block: &Block) -> bool {
+    pub async fn validate_block_that_has_arrived(
+        &mut self,
+        block: &Block,
+        decoding_hash: PieceId,
+    ) -> bool {
         // block is valid
-        if !(self.validate_block(block).await) {
+        if !(self.validate_block(block, Some(decoding_hash)).await) {
             return false;
         }
 
@@ -757,7 +975,7 @@ impl Ledger {
     pub async fn stage_proposer_block(&mut self, block: &Block) {
         info!(
             "Staging a new proposer block with {} tx blocks",
-            block.content.refs.len() - 1
+            block.refs.len() - 1
         );
 
         let mut parent_content_id = block
@@ -781,7 +999,7 @@ impl Ledger {
         );
 
         // for each tx block, remove from unclaimed or add to unknown
-        for tx_block_id in block.content.refs.iter().skip(1) {
+        for tx_block_id in block.refs.iter().skip(1) {
             if self.metablocks.contains_content_id(tx_block_id) {
                 self.unclaimed_tx_block_ids.remove(tx_block_id);
             } else {
@@ -816,23 +1034,12 @@ impl Ledger {
 
         // update head of this branch
         // TODO: make sure the branch will not be below the current confirmed block height
-        self.update_heads(parent_content_id, metablock.content_id, metablock.height);
-
-        let parent_proof_id = if parent_content_id == self.genesis_challenge {
-            self.genesis_challenge
-        } else {
-            self.metablocks
-                .get_proof_id_from_content_id(&parent_content_id)
-        };
-
-        // add to epoch tracker
-        self.epoch_tracker
-            .add_proof_to_epoch(
-                metablock.block.proof.epoch,
-                parent_proof_id,
-                metablock.proof_id,
-            )
-            .await;
+        self.update_heads(
+            parent_content_id,
+            metablock.content_id,
+            metablock.proof_id,
+            metablock.height,
+        );
 
         // confirm the k-deep parent
         let mut confirmation_depth = 0;
@@ -891,7 +1098,7 @@ impl Ledger {
         }
 
         // add to unknown or remove from claimed txs
-        for tx_id in block.content.refs.iter().skip(1) {
+        for tx_id in block.refs.iter().skip(1) {
             if self.txs.contains_key(tx_id) {
                 self.unclaimed_tx_ids.remove(tx_id);
             } else {
@@ -910,17 +1117,15 @@ impl Ledger {
 
         while blocks.len() > 0 {
             let mut additional_blocks: Vec<Block> = Vec::new();
-            for block in blocks.drain(..) {
-                if self.validate_block_from_cache(&block.clone()).await {
-                    self.stage_proposer_block(&block.clone()).await;
-
-                    self.cached_proposer_blocks_by_parent_content_id
-                        .get(&block.content.get_id())
-                        .cloned()
-                        .unwrap_or_default()
-                        .iter()
-                        .for_each(|block| additional_blocks.push(block.clone()));
-                }
+            for block in self.validate_blocks_from_cache(blocks.split_off(0)).await {
+                self.stage_proposer_block(&block).await;
+
+                self.cached_proposer_blocks_by_parent_content_id
+                    .get(&block.content.get_id())
+                    .cloned()
+                    .unwrap_or_default()
+                    .iter()
+                    .for_each(|block| additional_blocks.push(block.clone()));
             }
 
             std::mem::swap(&mut blocks, &mut additional_blocks);
@@ -972,6 +1177,17 @@ impl Ledger {
                 (self.current_solution_range as f64 * range_adjustment) as u64,
             );
 
+            // the salt of the next eon can't be known before the block that closed this one was
+            // solved, like the solution range it takes effect a delay after confirmation, such
+            // that it never applies to timeslots that have already passed
+            self.salts.insert(
+                self.current_timeslot + SALT_UPDATE_DELAY_IN_TIMESLOTS,
+                crypto::derive_salt(
+                    proposer_metablock.height / PROPOSER_BLOCKS_PER_EON,
+                    &proposer_metablock.proof_id,
+                ),
+            );
+
             warn!(
                 "Eon has closed.
                 Expected timeslots elapsed is: {}
@@ -989,7 +1205,7 @@ impl Ledger {
         // first have to make sure that we have all tx blocks and txs, else we discard the block
         // also need to ensure that we have exactly one coinbase tx per block
         // if we already have applied the tx block we can skip checking for those txs
-        for (index, ref_id) in proposer_metablock.block.content.refs.iter().enumerate() {
+        for (index, ref_id) in proposer_metablock.block.refs.iter().enumerate() {
             // first ref is always the coinbase tx
             if index == 0 {
                 match self.txs.get(ref_id) {
@@ -1021,7 +1237,7 @@ impl Ledger {
                 {
                     Some(tx_block) => {
                         // do we have all txs referenced?
-                        for (index, tx_id) in tx_block.block.content.refs.iter().enumerate() {
+                        for (index, tx_id) in tx_block.block.refs.iter().enumerate() {
                             match self.txs.get(tx_id) {
                                 Some(tx) => {
                                     match tx {
@@ -1070,7 +1286,6 @@ impl Ledger {
         let mut tx_blocks_by_height: BTreeMap<Timeslot, Vec<ProofId>> = BTreeMap::new();
         proposer_metablock
             .block
-            .content
             .refs
             .iter()
             .skip(1)
@@ -1090,6 +1305,7 @@ impl Ledger {
                     .or_insert(vec![tx_block.proof.get_id()]);
             });
 
+        let proposer_block_content_id = proposer_metablock.block.content.get_id();
         for tx_block_proof_id in tx_blocks_by_height
             .values()
             .flatten()
@@ -1100,9 +1316,10 @@ impl Ledger {
                 .metablocks
                 .get_metablock_from_proof_id(tx_block_proof_id)
                 .block;
+            let tx_block_content_id = tx_block.content.get_id();
 
             // validate and apply each tx
-            for tx_id in tx_block.content.refs.iter() {
+            for tx_id in tx_block.refs.iter() {
                 match self.txs.get(tx_id).expect("Already checked") {
                     Transaction::Coinbase(tx) => {
                         // create or update account state
@@ -1170,22 +1387,27 @@ impl Ledger {
                 // then someone could upload a storage tx for free
                 // if we don't include the content we could deal with that
 
-                // track each applied tx
+                // track each applied tx and where it was included
                 self.applied_tx_ids.insert(*tx_id);
+                if !self.tx_inclusions.contains(tx_id) {
+                    self.tx_inclusions
+                        .put(*tx_id, (tx_block_content_id, proposer_block_content_id));
+                }
             }
 
-            // add tx block proof and content to state
+            // add tx block proof, content and refs to state
             self.state.add_data(tx_block.proof.to_bytes()).await;
             self.state.add_data(tx_block.content.to_bytes()).await;
+            self.state.add_data(tx_block.refs.concat()).await;
 
             // track each applied tx block
-            self.applied_tx_block_ids.insert(tx_block.content.get_id());
+            self.applied_tx_block_ids.insert(tx_block_content_id);
         }
 
         // add in the proposer block coinbase tx
         match self
             .txs
-            .get(&proposer_metablock.block.content.refs[0])
+            .get(&proposer_metablock.block.refs[0])
             .expect("Already checked")
         {
             Transaction::Coinbase(tx) => {
@@ -1205,13 +1427,16 @@ impl Ledger {
             }
         }
 
-        // add proposer block proof and content to state
+        // add proposer block proof, content and refs to state
         self.state
             .add_data(proposer_metablock.block.proof.to_bytes())
             .await;
         self.state
             .add_data(proposer_metablock.block.content.to_bytes())
             .await;
+        self.state
+            .add_data(proposer_metablock.block.refs.concat())
+            .await;
 
         self.confirmed_blocks.insert(proposer_metablock.proof_id);
 
@@ -10,6 +10,8 @@ use std::collections::HashMap;
 use std::time::Duration;
 
 pub mod block;
+pub mod calibration;
+pub mod clock;
 pub mod console;
 pub mod crypto;
 pub mod farmer;
@@ -22,8 +24,11 @@ pub mod plot;
 pub mod plotter;
 pub mod pseudo_wallet;
 pub mod rpc;
+pub mod simulator;
 pub mod sloth;
 pub mod state;
+#[cfg(test)]
+mod test_utils;
 pub mod timer;
 pub mod transaction;
 pub mod utils;
@@ -37,7 +42,9 @@ pub type ExpandedIV = [u8; PRIME_SIZE_BYTES];
 pub type PublicKey = [u8; 32];
 pub type NodeID = [u8; 32];
 pub type Tag = [u8; 8];
+pub type Salt = [u8; 8];
 pub type BlockId = [u8; 32];
+pub type BlockHeaderId = [u8; 32];
 pub type ProofId = [u8; 32];
 pub type ContentId = [u8; 32];
 pub type EpochRandomness = Arc<Mutex<HashMap<u64, [u8; 32]>>>;
@@ -52,22 +59,37 @@ pub const PIECE_COUNT: usize = 256;
 pub const BLOCKS_PER_ENCODING: usize = PIECE_SIZE / PRIME_SIZE_BYTES;
 pub const ENCODING_LAYERS_TEST: usize = 1;
 pub const ENCODING_LAYERS_PROD: usize = BLOCKS_PER_ENCODING;
+/// Sloth layers every piece is encoded with, a chain parameter that plots record and blocks are
+/// checked against
+pub const ENCODING_LAYERS: usize = ENCODING_LAYERS_TEST;
+/// How pieces are encoded, plots made with another scheme have to be re-plotted
+pub const ENCODING_SCHEME: sloth::EncodingScheme = sloth::EncodingScheme::PieceIv;
 pub const PLOT_UPDATE_INTERVAL: usize = 10000;
+/// Timeslots for which audit results are kept for the console and RPC
+pub const AUDIT_HISTORY_TIMESLOTS: usize = 100;
+/// Pieces written to the plot and synced to disk at once during plotting
+pub const PLOT_WRITE_BATCH_SIZE: usize = 256;
 pub const MIN_PEERS: usize = 1;
 pub const MAX_PEERS: usize = 20;
 pub const MIN_CONTACTS: usize = 0;
 pub const MAX_CONTACTS: usize = 100;
 pub const BLOCK_LIST_SIZE: usize = 100;
+/// Applied txs the ledger can prove inclusion of, the least recently used ones are forgotten first
+pub const TX_INCLUSION_CACHE_SIZE: usize = 100_000;
 // TODO: Is this a good value?
 pub const MAINTAIN_PEERS_INTERVAL: Duration = Duration::from_secs(60);
 pub const CONFIRMATION_DEPTH: usize = 6;
 pub const DEV_GATEWAY_ADDR: &str = "127.0.0.1:8081";
 pub const TEST_GATEWAY_ADDR: &str = "127.0.0.1:8080";
 pub const DEV_WS_ADDR: &str = "127.0.0.1:8880";
+/// Prometheus metrics are served on `GET /metrics` at this address along with the WebSocket RPC
+pub const DEV_METRICS_ADDR: &str = "127.0.0.1:8881";
 pub const CONSOLE: bool = false;
 pub const BLOCK_REWARD: u64 = 1;
 pub const MAX_EARLY_TIMESLOTS: u64 = 10;
 pub const MAX_LATE_TIMESLOTS: u64 = 10;
+/// How often peers are pinged to estimate local clock offset
+pub const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(30);
 pub const TIMESLOT_DURATION: u64 = 1000;
 pub const CHALLENGE_LOOKBACK_EPOCHS: u64 = 1;
 /// Time in epochs
@@ -83,6 +105,12 @@ pub const ADJUSTMENT_FACTOR: f64 = 1.105;
 pub const EXPECTED_TIMESLOTS_PER_EON: u64 = (TIMESLOTS_PER_PROPOSER_BLOCK as f64
     * PROPOSER_BLOCKS_PER_EON as f64
     * ADJUSTMENT_FACTOR) as u64;
+/// Timeslots after the block that closed an eon was confirmed before the salt of the next eon takes
+/// effect, such that plots can start recommitting before blocks need it
+pub const SALT_UPDATE_DELAY_IN_TIMESLOTS: u64 = 4 * TIMESLOTS_PER_EPOCH;
+/// Timeslots after the salt changed during which tags committed to the previous salt are still
+/// accepted, such that plots can be recommitted in the background
+pub const RECOMMIT_GRACE_TIMESLOTS: u64 = 32 * TIMESLOTS_PER_EPOCH;
 // TODO: compute dynamically from plot size for testing
 pub const INITIAL_SOLUTION_RANGE: u64 = u64::MAX / PLOT_SIZE as u64 / TIMESLOTS_PER_BLOCK;
 pub const SOLUTION_RANGE_UPDATE_DELAY_IN_TIMESLOTS: u64 = 10;
@@ -96,6 +124,8 @@ pub const PIECES_PER_STATE_BLOCK: usize = STATE_BLOCK_SIZE_IN_BYTES / PIECE_SIZE
 pub const GENESIS_STATE_BLOCKS: usize = (1024 * 1024) / STATE_BLOCK_SIZE_IN_BYTES;
 /// 256
 pub const GENESIS_PIECE_COUNT: usize = PIECES_PER_STATE_BLOCK * GENESIS_STATE_BLOCKS;
+/// Genesis pieces are derived from this seed, so every node can recompute them
+pub const GENESIS_STATE_SEED: &str = "SUBSPACE";
 
 pub const PLOT_SIZE: usize = GENESIS_PIECE_COUNT;
 pub const IPC_SOCKET_FILE: &str = "ipc.socket";
@@ -105,6 +135,8 @@ pub const IPC_SOCKET_FILE: &str = "ipc.socket";
 const_assert!(EPOCH_CLOSE_WAIT_TIME >= 1);
 // Epoch must be closed by the time we do lookback to it
 const_assert!(CHALLENGE_LOOKBACK_EPOCHS >= EPOCH_CLOSE_WAIT_TIME);
+// Pieces must be encoded at least once
+const_assert!(ENCODING_LAYERS >= 1);
 
 pub const EPOCH_GRACE_PERIOD: Duration =
     Duration::from_millis(TIMESLOTS_PER_EPOCH * TIMESLOT_DURATION);
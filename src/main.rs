@@ -5,26 +5,34 @@ use clap::{Clap, ValueHint};
 use console::AppState;
 use crossbeam_channel::unbounded;
 use daemonize_me::Daemon;
-use exitcode::{OK, SOFTWARE};
+use exitcode::{DATAERR, OK, SOFTWARE};
+use futures::lock::Mutex;
 use futures::StreamExt;
 use futures_lite::FutureExt;
 use log::LevelFilter;
 use log::*;
+use std::collections::HashSet;
 use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
-use subspace_core_rust::farmer::FarmerMessage;
+use subspace_core_rust::clock::SystemClock;
+use subspace_core_rust::farmer::{AuditStats, FarmerMessage, FarmerPlot};
 use subspace_core_rust::ipc::{IpcRequestMessage, IpcResponseMessage, IpcServer};
 use subspace_core_rust::ledger::Ledger;
 use subspace_core_rust::manager::ProtocolMessage;
 use subspace_core_rust::network::{Network, NodeType};
-use subspace_core_rust::plot::Plot;
+use subspace_core_rust::plot::{Plot, VerifyMode, VerifyOptions};
+use subspace_core_rust::plotter::PlotConfig;
 use subspace_core_rust::pseudo_wallet::Wallet;
+use subspace_core_rust::simulator::{FarmerBehaviour, NetworkDelay, SimulationConfig};
+use subspace_core_rust::sloth::DecodingService;
 use subspace_core_rust::timer::EpochTracker;
 use subspace_core_rust::{
-    console, farmer, ipc, manager, network, plotter, rpc, state, BLOCK_LIST_SIZE, CONSOLE,
-    DEV_GATEWAY_ADDR, GENESIS_PIECE_COUNT, IPC_SOCKET_FILE, MAINTAIN_PEERS_INTERVAL, MAX_CONTACTS,
-    MAX_PEERS, MIN_CONTACTS, MIN_PEERS,
+    calibration, console, crypto, farmer, ipc, manager, network, plotter, rpc, simulator, state,
+    BLOCK_LIST_SIZE, CONSOLE, DEV_GATEWAY_ADDR, ENCODING_LAYERS, ENCODING_SCHEME,
+    GENESIS_PIECE_COUNT, GENESIS_STATE_SEED, IPC_SOCKET_FILE, MAINTAIN_PEERS_INTERVAL,
+    MAX_CONTACTS, MAX_PEERS, MIN_CONTACTS, MIN_PEERS, PIECE_SIZE, PLOT_SIZE,
 };
 use tui_logger::{init_logger, set_default_level};
 
@@ -38,14 +46,12 @@ use tui_logger::{init_logger, set_default_level};
    - finish p2p network impl (Nazar)
 
    - refactor plot to work of the same method for startup and subsequent plotting
-   - refactor sloth into encoder/decoder
    - handle deep forks when there are only two blocks for the same first timeslot
 
    - Compute and enforce cost of storage
    - Storage accounts
    - Switch to Schnorr signatures
    - Improve tx script support
-   - Add nonce to tag computation
 
  Fixes
 
@@ -76,9 +82,20 @@ enum Command {
         /// Run in the background as daemon
         #[clap(long)]
         daemon: bool,
-        /// Run WebSocket RPC server
+        /// Run WebSocket RPC server, along with Prometheus metrics on `GET /metrics`
         #[clap(long)]
         ws_rpc_server: bool,
+        /// Plot to farm as `PATH[:SIZE]` with size in bytes or with K/M/G/T suffix, repeat for
+        /// several disks (defaults to a single plot in the data directory)
+        #[clap(long = "plot", value_hint = ValueHint::DirPath)]
+        plots: Vec<PlotConfig>,
+        /// Read plots with direct I/O that bypasses the page cache (Linux only)
+        #[clap(long)]
+        direct_io: bool,
+        /// Verify a sample of pieces of existing plots on startup and remove corrupted ones, such
+        /// that they are plotted or fetched from the network again
+        #[clap(long)]
+        repair_plots: bool,
     },
     /// Stop subspace node that was previously running as a daemon
     Stop {
@@ -104,6 +121,38 @@ enum Command {
         #[clap(long, value_hint = ValueHint::FilePath)]
         custom_path: Option<PathBuf>,
     },
+    /// Manage plots of a subspace node that is not running
+    Plot {
+        #[clap(subcommand)]
+        command: PlotCommand,
+    },
+    /// Simulate several farmers in-process on a virtual clock and report consensus metrics
+    Simulate {
+        /// Number of honest farmers
+        #[clap(long, default_value = "4")]
+        honest: usize,
+        /// Number of farmers that withhold their blocks before gossiping them
+        #[clap(long, default_value = "0")]
+        withholding: usize,
+        /// Number of timeslots a withholding farmer keeps its blocks private
+        #[clap(long, default_value = "3")]
+        release_after: u64,
+        /// Number of farmers that never gossip their blocks
+        #[clap(long, default_value = "0")]
+        silent: usize,
+        /// Number of timeslots to simulate
+        #[clap(long, default_value = "256")]
+        timeslots: u64,
+        /// Minimum gossip delay between two farmers in ms
+        #[clap(long, default_value = "50")]
+        min_delay: u64,
+        /// Maximum gossip delay between two farmers in ms
+        #[clap(long, default_value = "250")]
+        max_delay: u64,
+        /// Seed for farmer keys and network delays
+        #[clap(long, default_value = "0")]
+        seed: u64,
+    },
 }
 
 fn get_path(custom_path: Option<PathBuf>) -> PathBuf {
@@ -126,6 +175,189 @@ fn get_path(custom_path: Option<PathBuf>) -> PathBuf {
     path
 }
 
+#[derive(Debug, Clap)]
+enum PlotCommand {
+    /// Check that encodings, merkle proofs and tags of plots agree, optionally repairing them
+    Verify {
+        /// Use custom path for data storage instead of platform-specific default
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        custom_path: Option<PathBuf>,
+        /// Plots to verify, same as for `run`
+        #[clap(long = "plot", value_hint = ValueHint::DirPath)]
+        plots: Vec<PlotConfig>,
+        /// Only decode this many random pieces of each plot instead of all of them
+        #[clap(long)]
+        sample: Option<usize>,
+        /// Remove corrupted pieces, so that they are fetched from the network on next run, and fix
+        /// tags
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Rewrite plot files without space left by removed pieces
+    Compact {
+        /// Use custom path for data storage instead of platform-specific default
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        custom_path: Option<PathBuf>,
+        /// Plots to compact, same as for `run`
+        #[clap(long = "plot", value_hint = ValueHint::DirPath)]
+        plots: Vec<PlotConfig>,
+    },
+    /// Measure how long encoding a piece takes on this machine with a given number of layers
+    Calibrate {
+        /// Layers to encode with, defaults to the chain parameter
+        #[clap(long, validator = validate_layers)]
+        layers: Option<usize>,
+        /// Number of random pieces to average over
+        #[clap(long, default_value = "16")]
+        pieces: usize,
+    },
+    /// Check whether re-encoding pieces on demand instead of storing them pays off on this machine
+    OnDemand {
+        /// Layers to encode with, defaults to the chain parameter
+        #[clap(long, validator = validate_layers)]
+        layers: Option<usize>,
+        /// Number of random pieces to average over
+        #[clap(long, default_value = "16")]
+        pieces: usize,
+    },
+}
+
+/// Calibration reports time per layer, so it needs at least one layer to encode with
+fn validate_layers(layers: &str) -> Result<(), String> {
+    match layers.parse::<usize>() {
+        Ok(0) => Err(String::from("at least one layer is required")),
+        Ok(_) => Ok(()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Plots given on the command line or a single plot in the data directory
+fn plot_configs_or_default(plot_configs: Vec<PlotConfig>, path: &PathBuf) -> Vec<PlotConfig> {
+    if plot_configs.is_empty() {
+        vec![PlotConfig {
+            path: path.clone().into(),
+            size_in_bytes: (PLOT_SIZE * PIECE_SIZE) as u64,
+        }]
+    } else {
+        plot_configs
+    }
+}
+
+/// Key index of each plot, see `crypto::derive_plot_keys`
+///
+/// Existing plots keep the index recorded in their header wherever they are in the config, new
+/// plots get the lowest indexes that no existing plot uses.
+async fn plot_key_indexes(keys: &ed25519_dalek::Keypair, plot_configs: &[PlotConfig]) -> Vec<u32> {
+    let node_id = |key_index: u32| {
+        let plot_keys = crypto::derive_plot_keys(keys, key_index as usize);
+        crypto::digest_sha_256(&plot_keys.public.to_bytes())
+    };
+
+    let mut recorded = Vec::with_capacity(plot_configs.len());
+    for plot_config in plot_configs {
+        // plot with an unreadable header fails to open later with a proper error
+        let header = Plot::read_header(&plot_config.path).await.ok().flatten();
+        recorded.push(header.map(|header| {
+            if node_id(header.key_index) == header.node_id {
+                header.key_index
+            } else {
+                // headers written before the key index was recorded read as 0, those plots were
+                // keyed by their position in the config
+                (0..plot_configs.len() as u32)
+                    .find(|&key_index| node_id(key_index) == header.node_id)
+                    .unwrap_or(header.key_index)
+            }
+        }));
+    }
+
+    let used: HashSet<u32> = recorded.iter().flatten().copied().collect();
+    let mut unused = (0..).filter(|key_index| !used.contains(key_index));
+
+    recorded
+        .into_iter()
+        .map(|key_index| key_index.unwrap_or_else(|| unused.next().unwrap()))
+        .collect()
+}
+
+/// Opens plots of a node that is not running, in the same order and with the same keys as `run()`
+async fn open_plots(path: &PathBuf, plot_configs: Vec<PlotConfig>) -> Vec<(PlotConfig, Plot)> {
+    let wallet = Wallet::open_or_create(path).expect("Failed to init wallet");
+    let plot_configs = plot_configs_or_default(plot_configs, path);
+    let key_indexes = plot_key_indexes(&wallet.keypair, &plot_configs).await;
+
+    let mut plots = Vec::new();
+    for (plot_config, key_index) in plot_configs.into_iter().zip(key_indexes) {
+        let plot_keys = crypto::derive_plot_keys(&wallet.keypair, key_index as usize);
+        let plot_node_id = crypto::digest_sha_256(&plot_keys.public.to_bytes());
+        let plot = Plot::open_or_create(&plot_config.path, plot_node_id, key_index)
+            .await
+            .unwrap_or_else(|error| {
+                panic!("Failed to open plot {:?}: {:?}", plot_config.path, error)
+            });
+        plots.push((plot_config, plot));
+    }
+
+    plots
+}
+
+/// Drops the plot and waits for it to close, see shutdown in `run()`
+async fn close_plot(plot: Plot) {
+    let (close_sender, close_receiver) = async_oneshot::oneshot::<()>();
+    plot.on_close(move || {
+        let _ = close_sender.send(());
+    })
+    .detach();
+    drop(plot);
+    let _ = close_receiver.await;
+}
+
+async fn verify_plots(
+    path: PathBuf,
+    plot_configs: Vec<PlotConfig>,
+    mode: VerifyMode,
+    repair: bool,
+) {
+    // the state chain is only synced while the node runs, use `run --repair-plots` to check
+    // pieces of later state blocks
+    let merkle_roots = state::genesis_merkle_roots().await;
+
+    let mut all_ok = true;
+    for (plot_config, plot) in open_plots(&path, plot_configs).await {
+        let report = plot
+            .verify(VerifyOptions {
+                mode,
+                repair,
+                merkle_roots: merkle_roots.clone(),
+            })
+            .await
+            .expect("Failed to verify plot");
+
+        println!("Plot {:?}:\n{}", plot_config.path, report);
+        if report.repaired && !report.corrupted().is_empty() {
+            println!(
+                "{} corrupted pieces were removed and will be fetched from the network on next run",
+                report.corrupted().len()
+            );
+        }
+        all_ok &= report.is_ok() || report.repaired;
+
+        close_plot(plot).await;
+    }
+
+    if !all_ok {
+        std::process::exit(DATAERR);
+    }
+}
+
+async fn compact_plots(path: PathBuf, plot_configs: Vec<PlotConfig>) {
+    for (plot_config, plot) in open_plots(&path, plot_configs).await {
+        let reclaimed = plot.compact().await.expect("Failed to compact plot");
+        println!("Plot {:?}: reclaimed {} bytes", plot_config.path, reclaimed);
+
+        close_plot(plot).await;
+    }
+}
+
 #[async_std::main]
 async fn main() {
     let command: Command = Command::parse();
@@ -135,6 +367,9 @@ async fn main() {
             custom_path,
             daemon,
             ws_rpc_server,
+            plots,
+            direct_io,
+            repair_plots,
         } => {
             let path = get_path(custom_path);
             // TODO: Doesn't really work, see https://github.com/octetd/daemonize-me/issues/2
@@ -168,7 +403,16 @@ async fn main() {
                 // spawn a new thread to run the node else it will block the console
                 thread::spawn(move || {
                     task::spawn(async move {
-                        run(app_state_sender, node_type, path, ws_rpc_server).await;
+                        run(
+                            app_state_sender,
+                            node_type,
+                            path,
+                            ws_rpc_server,
+                            plots,
+                            direct_io,
+                            repair_plots,
+                        )
+                        .await;
                     });
                 });
 
@@ -177,7 +421,16 @@ async fn main() {
             } else {
                 // TODO: fix default log level and occasionally print state to the console
                 env_logger::init();
-                run(app_state_sender, node_type, path, ws_rpc_server).await;
+                run(
+                    app_state_sender,
+                    node_type,
+                    path,
+                    ws_rpc_server,
+                    plots,
+                    direct_io,
+                    repair_plots,
+                )
+                .await;
             }
         }
         Command::Stop { custom_path } => {
@@ -198,6 +451,88 @@ async fn main() {
             let path = get_path(custom_path);
             unimplemented!();
         }
+        Command::Plot {
+            command:
+                PlotCommand::Verify {
+                    custom_path,
+                    plots,
+                    sample,
+                    repair,
+                },
+        } => {
+            env_logger::init();
+
+            let path = get_path(custom_path);
+            let mode = sample.map(VerifyMode::Sample).unwrap_or(VerifyMode::Full);
+            verify_plots(path, plots, mode, repair).await;
+        }
+        Command::Plot {
+            command: PlotCommand::Compact { custom_path, plots },
+        } => {
+            env_logger::init();
+
+            compact_plots(get_path(custom_path), plots).await;
+        }
+        Command::Plot {
+            command: PlotCommand::Calibrate { layers, pieces },
+        } => {
+            let layers = layers.unwrap_or(ENCODING_LAYERS);
+            let report = task::spawn_blocking(move || calibration::calibrate(layers, pieces)).await;
+
+            println!("{}", report);
+            if layers != ENCODING_LAYERS {
+                println!(
+                    "Note: the chain encodes pieces with {} layers",
+                    ENCODING_LAYERS
+                );
+            }
+        }
+        Command::Plot {
+            command: PlotCommand::OnDemand { layers, pieces },
+        } => {
+            let layers = layers.unwrap_or(ENCODING_LAYERS);
+            let report = task::spawn_blocking(move || calibration::calibrate(layers, pieces)).await;
+
+            println!(
+                "{}",
+                calibration::OnDemandEncodingReport::from_calibration(&report)
+            );
+        }
+        Command::Simulate {
+            honest,
+            withholding,
+            release_after,
+            silent,
+            timeslots,
+            min_delay,
+            max_delay,
+            seed,
+        } => {
+            env_logger::init();
+
+            let mut farmers = vec![FarmerBehaviour::Honest; honest];
+            farmers.extend(vec![
+                FarmerBehaviour::Withholding {
+                    release_after_timeslots: release_after,
+                };
+                withholding
+            ]);
+            farmers.extend(vec![FarmerBehaviour::Silent; silent]);
+
+            let report = simulator::run(SimulationConfig {
+                seed,
+                timeslots,
+                farmers,
+                delay: NetworkDelay {
+                    min_ms: min_delay,
+                    max_ms: max_delay,
+                },
+                ..SimulationConfig::default()
+            })
+            .await;
+
+            println!("{}", report);
+        }
     }
 }
 
@@ -206,6 +541,9 @@ pub async fn run(
     node_type: NodeType,
     path: PathBuf,
     ws_rpc_server: bool,
+    plot_configs: Vec<PlotConfig>,
+    direct_io: bool,
+    repair_plots: bool,
 ) {
     let node_addr = "127.0.0.1:0".parse().unwrap();
 
@@ -220,7 +558,9 @@ pub async fn run(
     let node_id = wallet.node_id;
 
     // create the randomness tracker
-    let epoch_tracker = EpochTracker::new().await;
+    let epoch_tracker = EpochTracker::open_or_create(&path)
+        .await
+        .expect("Failed to open epoch randomness");
 
     // create channels between background tasks
     let (any_to_main_tx, any_to_main_rx) = channel::<ProtocolMessage>(128);
@@ -231,25 +571,76 @@ pub async fn run(
     // create the state
     let mut state = state::State::new(state_to_plotter_tx);
 
-    // create the plot
-    let plot: Plot = match node_type {
-        NodeType::Gateway => {
-            // create the genesis state
-            let state_bundle = state
-                .create_genesis_state("SUBSPACE", GENESIS_PIECE_COUNT)
-                .await;
+    // create the plots, each one is encoded for its own node id derived from the wallet
+    let plot_configs = plot_configs_or_default(plot_configs, &path);
 
-            // create the plot from genesis state
-            plotter::plot(path.clone().into(), node_id, state_bundle).await
-        }
-        _ => {
-            // create an empty plot
-            plotter::plot(path.clone().into(), node_id, vec![]).await
+    let piece_bundles = match node_type {
+        // create the genesis state
+        NodeType::Gateway => {
+            state
+                .create_genesis_state(GENESIS_STATE_SEED, GENESIS_PIECE_COUNT)
+                .await
         }
+        // start with empty plots
+        _ => vec![],
     };
 
-    // create the ledger
-    let ledger = Ledger::new(keys, epoch_tracker.clone(), state);
+    let key_indexes = plot_key_indexes(&keys, &plot_configs).await;
+    let mut plots: Vec<FarmerPlot> = Vec::with_capacity(plot_configs.len());
+    for (plot_config, key_index) in plot_configs.into_iter().zip(key_indexes) {
+        fs::create_dir_all(&plot_config.path).unwrap_or_else(|error| {
+            panic!(
+                "Failed to create plot directory {:?}: {:?}",
+                plot_config.path, error
+            )
+        });
+
+        let piece_count = plot_config.piece_count();
+        let plot_keys = crypto::derive_plot_keys(&keys, key_index as usize);
+        let plot_node_id = crypto::digest_sha_256(&plot_keys.public.to_bytes());
+        let plot = plotter::plot(
+            plot_config.path.clone(),
+            plot_node_id,
+            key_index,
+            piece_bundles.clone(),
+            piece_count,
+            // other nodes repair plots once they synced the state chain, see `manager::run()`
+            if repair_plots && node_type == NodeType::Gateway {
+                Some(state.merkle_roots())
+            } else {
+                None
+            },
+        )
+        .await;
+        if direct_io {
+            plot.set_direct_io(true).await.unwrap_or_else(|error| {
+                panic!(
+                    "Failed to enable direct I/O for plot {:?}: {}",
+                    plot_config.path, error
+                )
+            });
+        }
+
+        plots.push(FarmerPlot {
+            index: key_index as usize,
+            node_id: plot_node_id,
+            size: piece_count,
+            plot,
+        });
+    }
+
+    // audit results reported by the farmer and the manager
+    let audit_stats = AuditStats::default();
+
+    // create the ledger, with one decoding pool for all block and piece decodings
+    let decoding_service = DecodingService::new(Arc::new(ENCODING_SCHEME.sloth()), ENCODING_LAYERS);
+    let ledger = Arc::new(Mutex::new(Ledger::new(
+        keys,
+        epoch_tracker.clone(),
+        state,
+        Arc::new(SystemClock),
+        decoding_service,
+    )));
 
     // create the network
     let startup_network_fut = Network::new(
@@ -283,7 +674,13 @@ pub async fn run(
         .map(|value| value == "1".to_string())
         .unwrap_or(ws_rpc_server)
     {
-        rpc_server = Some(rpc::run(node_id, network.clone()));
+        rpc_server = Some(rpc::run(
+            node_id,
+            network.clone(),
+            plots.clone(),
+            audit_stats.clone(),
+            Arc::clone(&ledger),
+        ));
     }
 
     let (ipc_shutdown_request_sender, mut ipc_shutdown_request_receiver) =
@@ -314,22 +711,24 @@ pub async fn run(
     // create the manager
     let manager = manager::run(
         node_type,
-        node_id,
         ledger,
         any_to_main_rx,
         network,
         app_state_sender,
         timer_to_farmer_tx,
         epoch_tracker,
-        plot.clone(),
+        plots.clone(),
+        audit_stats.clone(),
+        repair_plots,
     );
 
     let mut app_handler = Some(async_std::task::spawn({
-        let plot = plot.clone();
+        let plots = plots.clone();
 
         async move {
             // create the farming loop
-            let farmer_fut = farmer::run(timer_to_farmer_rx, solver_to_main_tx, &plot);
+            let farmer_fut =
+                farmer::run(timer_to_farmer_rx, solver_to_main_tx, &plots, audit_stats);
 
             manager.race(farmer_fut).await;
         }
@@ -339,8 +738,8 @@ pub async fn run(
         .unwrap()
         .map(|_| ());
 
-    // Wrap into option, such that we can drop Plot when needed without dropping the whole variable
-    let mut plot = Some(plot);
+    // Wrap into option, such that we can drop plots when needed without dropping the whole variable
+    let mut plots = Some(plots);
     let mut already_shutting_down = false;
     while let Some(_) = ipc_shutdown_request_receiver
         .next()
@@ -357,22 +756,29 @@ pub async fn run(
         // Stop RPC server
         rpc_server.take();
         if let Some(app_handler) = app_handler.take() {
-            let plot = plot.take().unwrap();
+            let plots = plots.take().unwrap();
             async_std::task::spawn(async move {
                 // TODO: We probably want to do something more sophisticated than just canceling it
                 app_handler.cancel().await;
 
-                let (close_sender, close_receiver) = async_oneshot::oneshot::<()>();
-                plot.on_close(move || {
-                    let _ = close_sender.send(());
-                })
-                .detach();
+                let mut close_receivers = Vec::with_capacity(plots.len());
+                for farmer_plot in plots {
+                    let (close_sender, close_receiver) = async_oneshot::oneshot::<()>();
+                    farmer_plot
+                        .plot
+                        .on_close(move || {
+                            let _ = close_sender.send(());
+                        })
+                        .detach();
+                    close_receivers.push(close_receiver);
+                }
 
-                // We wait for plot to close gracefully, otherwise we may get this message:
+                // We wait for plots to close gracefully, otherwise we may get this message:
                 // pure virtual method called
                 // terminate called without an active exception
-                drop(plot);
-                let _ = close_receiver.await;
+                for close_receiver in close_receivers {
+                    let _ = close_receiver.await;
+                }
 
                 std::process::exit(OK);
             });
@@ -1,42 +1,44 @@
-use crate::block::Block;
+use crate::block::{self, Block};
+use crate::clock::{Clock, SharedClock};
 use crate::console::AppState;
-use crate::farmer::{FarmerMessage, Solution};
+use crate::farmer::{AuditStats, FarmerMessage, FarmerPlot, Solution};
 use crate::ledger::Ledger;
 use crate::network::messages::{
-    BlockRequestByContentId, BlockRequestByProofId, BlockResponseByContentId,
-    BlockResponseByProofId, BlocksRequest, BlocksResponse, GenesisConfigRequest,
-    GenesisConfigResponse, GossipMessage, PieceRequestById, PieceRequestByIndex,
-    PieceResponseByIndex, RequestMessage, ResponseMessage, StateBlockRequestByHeight,
-    StateBlockRequestById, StateBlockResponseByHeight, StateBlockResponseById, TxRequestById,
-    TxResponseById,
+    BlockBodyRequestByProofId, BlockBodyResponseByProofId, BlockRequestByContentId,
+    BlockRequestByProofId, BlockResponseByContentId, BlockResponseByProofId, BlocksRequest,
+    BlocksResponse, GenesisConfigRequest, GenesisConfigResponse, GossipMessage, PieceRequestById,
+    PieceRequestByIndex, PieceResponseByIndex, PingRequest, PingResponse, RequestMessage,
+    ResponseMessage, StateBlockRequestByHeight, StateBlockRequestById, StateBlockResponseByHeight,
+    StateBlockResponseById, TxRequestById, TxResponseById,
 };
 use crate::network::{Network, NodeType};
-use crate::plot::Plot;
-use crate::state::StateBundle;
+use crate::plot::PieceWrite;
+use crate::state::{PieceBundle, StateBundle};
 use crate::timer::EpochTracker;
 use crate::transaction::Transaction;
 use crate::{
-    crypto, sloth, timer, ContentId, NodeID, CONSOLE, ENCODING_LAYERS_TEST, GENESIS_STATE_BLOCKS,
-    MIN_PEERS, PIECES_PER_STATE_BLOCK, PLOT_SIZE, PRIME_SIZE_BITS, TIMESLOTS_PER_EPOCH,
-    TIMESLOT_DURATION,
+    crypto, plotter, timer, ContentId, CLOCK_SYNC_INTERVAL, CONSOLE, ENCODING_LAYERS,
+    ENCODING_SCHEME, GENESIS_STATE_BLOCKS, MIN_PEERS, PIECES_PER_STATE_BLOCK,
+    PLOT_WRITE_BATCH_SIZE, TIMESLOTS_PER_EPOCH, TIMESLOT_DURATION,
 };
 use async_std::sync::{Receiver, Sender};
 use async_std::task;
 use futures::lock::Mutex;
 use log::*;
-use rug::integer::Order;
-use rug::Integer;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Display;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct GenesisConfig {
     pub genesis_timestamp: u64,
     pub genesis_challenge: [u8; 32],
+    /// `ENCODING_LAYERS` of the node that created genesis
+    pub encoding_layers: u32,
 }
 
 pub enum ProtocolMessage {
@@ -73,6 +75,7 @@ pub fn start_timer(
     genesis_timestamp: u64,
     next_timeslot: u64,
     ledger: SharedLedger,
+    clock: SharedClock,
 ) {
     async_std::task::spawn(timer::run(
         timer_to_farmer_tx,
@@ -81,22 +84,24 @@ pub fn start_timer(
         genesis_timestamp,
         next_timeslot,
         ledger,
+        clock,
     ));
 }
 
 /// Starts the manager process, a broker loop that acts as the central async message hub for the node
 pub async fn run(
     node_type: NodeType,
-    node_id: NodeID,
-    ledger: Ledger,
+    ledger: SharedLedger,
     any_to_main_rx: Receiver<ProtocolMessage>,
     network: Network,
     state_sender: crossbeam_channel::Sender<AppState>,
     timer_to_farmer_tx: Sender<FarmerMessage>,
     epoch_tracker: EpochTracker,
-    plot: Plot,
+    plots: Vec<FarmerPlot>,
+    audit_stats: AuditStats,
+    repair_plots: bool,
 ) {
-    let ledger: SharedLedger = Arc::new(Mutex::new(ledger));
+    let clock = Arc::clone(&ledger.lock().await.clock);
 
     let gossip_handling = {
         let network = network.clone();
@@ -104,70 +109,186 @@ pub async fn run(
 
         async move {
             let gossip_receiver = network.get_gossip_receiver().unwrap();
-            while let Ok((peer_addr, message)) = gossip_receiver.recv().await {
-                match message {
-                    GossipMessage::BlockProposal { block } => {
-                        info!("Received a new block via gossip");
-                        let mut locked_ledger = ledger.lock().await;
+            while let Ok(message) = gossip_receiver.recv().await {
+                // blocks gossiped while the previous ones were handled are decoded together
+                let mut messages = vec![message];
+                while let Ok(message) = gossip_receiver.try_recv() {
+                    messages.push(message);
+                }
 
-                        if locked_ledger.validate_block_from_gossip(&block).await {
-                            network
-                                .regossip(
-                                    &peer_addr,
-                                    GossipMessage::BlockProposal {
-                                        block: block.clone(),
-                                    },
-                                )
-                                .await;
+                let mut blocks = Vec::new();
+                for (peer_addr, message) in messages {
+                    match message {
+                        GossipMessage::BlockProposal { header } => {
+                            info!("Received a new block header via gossip");
 
-                            if block.content.parent_id.is_some() {
-                                // stage the proposer block
-                                locked_ledger.stage_proposer_block(&block).await;
+                            // the body is only fetched for new headers with valid signatures
+                            if !header.is_valid() {
+                                warn!("Received an invalid block header via gossip, ignoring");
+                                continue;
+                            }
+                            let proof_id = header.proof.get_id();
+                            if ledger.lock().await.recent_proof_ids.contains(&proof_id) {
+                                debug!(
+                                    "Received a block header via gossip for known block, ignoring"
+                                );
+                                continue;
+                            }
 
-                                // stage any cached children
-                                locked_ledger
-                                    .stage_cached_children(block.content.get_id())
+                            // peers only gossip headers of blocks whose body they can serve
+                            let body = match network
+                                .request_block_body_by_proof_id(&peer_addr, proof_id)
+                                .await
+                            {
+                                Ok(Some(body)) => body,
+                                Ok(None) => {
+                                    warn!(
+                                        "Peer {} has no body for the block header it gossiped",
+                                        peer_addr
+                                    );
+                                    continue;
+                                }
+                                Err(error) => {
+                                    warn!(
+                                        "Failed to request block body from {}: {:?}",
+                                        peer_addr, error
+                                    );
+                                    continue;
+                                }
+                            };
+                            let block = match Block::from_header_and_body(header, body) {
+                                Some(block) => block,
+                                None => {
+                                    warn!(
+                                        "Block body from {} does not match the header it gossiped",
+                                        peer_addr
+                                    );
+                                    continue;
+                                }
+                            };
+
+                            blocks.push((peer_addr, block));
+                        }
+                        GossipMessage::TxProposal { tx } => {
+                            let tx_id = tx.get_id();
+                            let mut locked_ledger = ledger.lock().await;
+
+                            // check to see if we already have the tx
+                            if locked_ledger.txs.contains_key(&tx_id) {
+                                warn!("Received a duplicate tx via gossip, ignoring");
+                                continue;
+                            }
+
+                            // only validate and gossip if synced
+                            if locked_ledger.timer_is_running {
+                                // validate the tx
+                                let from_account_state =
+                                    locked_ledger.balances.get(&tx.from_address);
+                                // TODO: validate without account state
+                                if !tx.is_valid(from_account_state) {
+                                    warn!("Received an invalid tx via gossip, ignoring");
+                                    continue;
+                                }
+
+                                // re-gossip transaction
+                                network
+                                    .regossip(
+                                        &peer_addr,
+                                        GossipMessage::TxProposal { tx: tx.clone() },
+                                    )
                                     .await;
+                            }
+
+                            // add to tx database and mempool
+                            locked_ledger.txs.insert(tx_id, Transaction::Credit(tx));
+
+                            if locked_ledger.unknown_tx_ids.contains(&tx_id) {
+                                locked_ledger.unknown_tx_ids.remove(&tx_id);
                             } else {
-                                // stage the tx block
-                                locked_ledger.stage_tx_block(&block).await;
+                                locked_ledger.unclaimed_tx_ids.insert(tx_id);
                             }
                         }
                     }
-                    GossipMessage::TxProposal { tx } => {
-                        let tx_id = tx.get_id();
-                        let mut locked_ledger = ledger.lock().await;
+                }
 
-                        // check to see if we already have the tx
-                        if locked_ledger.txs.contains_key(&tx_id) {
-                            warn!("Received a duplicate tx via gossip, ignoring");
+                // blocks are checked once their parents from the same batch are staged
+                while !blocks.is_empty() {
+                    let content_ids: HashSet<ContentId> = blocks
+                        .iter()
+                        .map(|(_, block)| block.content.get_id())
+                        .collect();
+                    let (ready, waiting): (Vec<_>, Vec<_>) =
+                        blocks.into_iter().partition(|(_, block)| {
+                            !matches!(
+                                block.content.parent_id,
+                                Some(parent_id) if content_ids.contains(&parent_id)
+                            )
+                        });
+                    blocks = waiting;
+
+                    // run the cheap checks first, only blocks that pass them are decoded
+                    let mut locked_ledger = ledger.lock().await;
+                    let mut checked_blocks = Vec::with_capacity(blocks.len());
+                    for (peer_addr, block) in ready {
+                        if locked_ledger.check_block_from_gossip(&block).await {
+                            checked_blocks.push((peer_addr, block));
+                        }
+                    }
+
+                    // decode them at once on the decoding pool, without holding the ledger
+                    let decoding_service = locked_ledger.decoding_service.clone();
+                    drop(locked_ledger);
+                    let decoding_hashes = decoding_service
+                        .piece_hashes(block::encodings_to_decode(
+                            checked_blocks.iter().map(|(_, block)| block),
+                        ))
+                        .await;
+
+                    for ((peer_addr, block), decoding_hash) in
+                        checked_blocks.into_iter().zip(decoding_hashes)
+                    {
+                        if !block.has_valid_encoding(&decoding_hash) {
+                            // TODO: block list this peer
                             continue;
                         }
 
-                        // only validate and gossip if synced
+                        let mut locked_ledger = ledger.lock().await;
                         if locked_ledger.timer_is_running {
-                            // validate the tx
-                            let from_account_state = locked_ledger.balances.get(&tx.from_address);
-                            // TODO: validate without account state
-                            if !tx.is_valid(from_account_state) {
-                                warn!("Received an invalid tx via gossip, ignoring");
-                                continue;
-                            }
-
-                            // re-gossip transaction
-                            network
-                                .regossip(&peer_addr, GossipMessage::TxProposal { tx: tx.clone() })
-                                .await;
+                            // the timestamp in the content is whatever the farmer claims, the
+                            // start of the timeslot of the proof is not
+                            let timeslot_start = locked_ledger.genesis_timestamp
+                                + block.proof.timeslot * TIMESLOT_DURATION;
+                            locked_ledger.clock.offset().add_block_sample(
+                                block.proof.public_key,
+                                &peer_addr,
+                                timeslot_start,
+                                locked_ledger.clock.local_now(),
+                            );
                         }
 
-                        // add to tx database and mempool
-                        locked_ledger.txs.insert(tx_id, Transaction::Credit(tx));
+                        // staged before the header is regossiped, so that the body can be served
+                        if block.content.parent_id.is_some() {
+                            // stage the proposer block
+                            locked_ledger.stage_proposer_block(&block).await;
 
-                        if locked_ledger.unknown_tx_ids.contains(&tx_id) {
-                            locked_ledger.unknown_tx_ids.remove(&tx_id);
+                            // stage any cached children
+                            locked_ledger
+                                .stage_cached_children(block.content.get_id())
+                                .await;
                         } else {
-                            locked_ledger.unclaimed_tx_ids.insert(tx_id);
+                            // stage the tx block
+                            locked_ledger.stage_tx_block(&block).await;
                         }
+                        drop(locked_ledger);
+
+                        network
+                            .regossip(
+                                &peer_addr,
+                                GossipMessage::BlockProposal {
+                                    header: block.header(),
+                                },
+                            )
+                            .await;
                     }
                 }
             }
@@ -177,15 +298,15 @@ pub async fn run(
     let requests_handling = {
         let network = network.clone();
         let ledger = Arc::clone(&ledger);
-        // let plot = Arc::clone(&plot);
-        let plot = plot.clone();
+        let plots = plots.clone();
+        let clock = Arc::clone(&clock);
 
         async move {
             let requests_receiver = network.get_requests_receiver().unwrap();
             while let Ok((message, response_sender)) = requests_receiver.recv().await {
                 let ledger = Arc::clone(&ledger);
-                // let plot = Arc::clone(&plot);
-                let plot = plot.clone();
+                let plots = plots.clone();
+                let clock = Arc::clone(&clock);
 
                 async_std::task::spawn(async move {
                     match message {
@@ -230,6 +351,17 @@ pub async fn run(
                                 BlockResponseByProofId { block },
                             )));
                         }
+                        RequestMessage::BlockBodyByProofId(BlockBodyRequestByProofId { id }) => {
+                            let locked_ledger = ledger.lock().await;
+                            let body = locked_ledger
+                                .metablocks
+                                .get_metablock_from_proof_id_as_option(&id)
+                                .map(|metablock| metablock.block.body());
+
+                            drop(response_sender.send(ResponseMessage::BlockBodyByProofId(
+                                BlockBodyResponseByProofId { body },
+                            )));
+                        }
                         RequestMessage::TransactionById(TxRequestById { id }) => {
                             let locked_ledger = ledger.lock().await;
                             let transaction: Option<Transaction> = match locked_ledger.txs.get(&id)
@@ -266,7 +398,17 @@ pub async fn run(
                             )));
                         }
                         RequestMessage::PieceByIndex(PieceRequestByIndex { index }) => {
-                            let piece_bundle = plot.get_piece_bundle_by_index(index, node_id).await;
+                            // any plot will do, the requester decodes with the node id we send
+                            let mut piece_bundle = None;
+                            for farmer_plot in plots.iter() {
+                                piece_bundle = farmer_plot
+                                    .plot
+                                    .get_piece_bundle_by_index(index, farmer_plot.node_id)
+                                    .await;
+                                if piece_bundle.is_some() {
+                                    break;
+                                }
+                            }
 
                             drop(response_sender.send(ResponseMessage::PieceByIndex(
                                 PieceResponseByIndex { piece_bundle },
@@ -280,28 +422,80 @@ pub async fn run(
                             let genesis_config = GenesisConfig {
                                 genesis_timestamp: locked_ledger.genesis_timestamp,
                                 genesis_challenge: locked_ledger.genesis_challenge,
+                                encoding_layers: ENCODING_LAYERS as u32,
                             };
 
                             drop(response_sender.send(ResponseMessage::GenesisConfig(
                                 GenesisConfigResponse { genesis_config },
                             )));
                         }
+                        RequestMessage::Ping(PingRequest {}) => {
+                            // the uncorrected clock, so that offsets estimated by peers don't feed
+                            // back into each other
+                            let timestamp = clock.local_now();
+
+                            drop(
+                                response_sender
+                                    .send(ResponseMessage::Ping(PingResponse { timestamp })),
+                            );
+                        }
                     }
                 });
             }
         }
     };
 
+    let clock_sync = {
+        let network = network.clone();
+        let clock = Arc::clone(&clock);
+
+        async move {
+            loop {
+                for peer_addr in network.get_peer_addresses().await {
+                    let sent_at = clock.local_now();
+                    match network.ping(&peer_addr).await {
+                        Ok(peer_timestamp) => {
+                            // assume the peer read its clock halfway through the round trip
+                            let received_at = clock.local_now();
+                            let local_timestamp = sent_at + received_at.saturating_sub(sent_at) / 2;
+                            clock.offset().add_peer_sample(
+                                peer_addr,
+                                peer_timestamp as i64 - local_timestamp as i64,
+                                received_at.saturating_sub(sent_at),
+                            );
+                        }
+                        Err(error) => {
+                            debug!("Failed to ping {} for clock sync: {:?}", peer_addr, error);
+                            clock.offset().remove_peer_sample(&peer_addr);
+                        }
+                    }
+                }
+
+                let offset = clock.offset().median();
+                if clock.offset().exceeds_max_drift() {
+                    warn!(
+                        "Local clock is {} ms off from the network, farming is paused until it is back in sync",
+                        offset
+                    );
+                } else {
+                    debug!(
+                        "Estimated local clock offset from the network: {} ms",
+                        offset
+                    );
+                }
+
+                task::sleep(CLOCK_SYNC_INTERVAL).await;
+            }
+        }
+    };
+
     let protocol_listener = async {
         info!("Main protocol loop is running...");
 
         // if gateway init the genesis block set and then start the timer
         if node_type == NodeType::Gateway {
             // set genesis time
-            let genesis_timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis() as u64;
+            let genesis_timestamp = clock.now();
 
             // start the timer
             start_timer(
@@ -311,6 +505,7 @@ pub async fn run(
                 genesis_timestamp,
                 1,
                 Arc::clone(&ledger),
+                Arc::clone(&clock),
             );
 
             {
@@ -328,27 +523,47 @@ pub async fn run(
                 Ok(message) => match message {
                     ProtocolMessage::BlockSolutions { solutions } => {
                         if !solutions.is_empty() {
+                            // solutions from all plots are decoded at once on the decoding pool
+                            let (decoding_service, encodings) = {
+                                let locked_ledger = ledger.lock().await;
+                                (
+                                    locked_ledger.decoding_service.clone(),
+                                    locked_ledger.solution_encodings(&solutions),
+                                )
+                            };
+                            let piece_hashes = decoding_service.piece_hashes(encodings).await;
+
                             let mut content_ids: Vec<ContentId> = Vec::new();
-                            for solution in solutions.into_iter() {
-                                let block = ledger
+                            for (solution, piece_hash) in solutions.into_iter().zip(piece_hashes) {
+                                let block = match ledger
                                     .lock()
                                     .await
-                                    .create_and_apply_local_block(solution, content_ids.clone())
-                                    .await;
-                                network
-                                    .gossip(GossipMessage::BlockProposal {
-                                        block: block.clone(),
-                                    })
-                                    .await;
-
-                                info!("Gossiping a new valid block to all peers");
+                                    .create_and_apply_local_block(
+                                        solution,
+                                        content_ids.clone(),
+                                        piece_hash,
+                                    )
+                                    .await
+                                {
+                                    Some(block) => block,
+                                    None => continue,
+                                };
+                                audit_stats.record_block_won(block.proof.timeslot);
 
+                                // staged first, peers request the body once they get the header
                                 if block.content.parent_id.is_some() {
                                     ledger.lock().await.stage_proposer_block(&block).await;
                                     content_ids.push(block.content.get_id());
                                 } else {
                                     ledger.lock().await.stage_tx_block(&block).await;
                                 }
+
+                                info!("Gossiping a new valid block to all peers");
+                                network
+                                    .gossip(GossipMessage::BlockProposal {
+                                        header: block.header(),
+                                    })
+                                    .await;
                             }
                         }
                     }
@@ -359,7 +574,20 @@ pub async fn run(
                         if state_bundle.state_block.height >= GENESIS_STATE_BLOCKS as u64 {
                             // TODO: only plot pieces that evict other pieces
                             warn!("Plotting pieces from new confirmed state");
-                            plot.plot_pieces(node_id, state_bundle.piece_bundles).await;
+                            for farmer_plot in plots.iter() {
+                                let piece_bundles = state_bundle
+                                    .piece_bundles
+                                    .iter()
+                                    .filter(|piece_bundle| {
+                                        piece_bundle.piece_index < farmer_plot.size as u64
+                                    })
+                                    .cloned()
+                                    .collect();
+                                farmer_plot
+                                    .plot
+                                    .plot_pieces(farmer_plot.node_id, piece_bundles)
+                                    .await;
+                            }
                         }
                     }
                 },
@@ -418,13 +646,25 @@ pub async fn run(
                    6. Read, encode, and plot the piece once it arrives in the queue
                 */
 
-                // sync the genesis config
-                match network.request_genesis_config().await {
-                    Ok(genesis_config) => {
-                        ledger.lock().await.set_genesis_config(genesis_config);
-                    }
-                    Err(error) => {
-                        panic!("Failed to request genesis config: {:?}", error);
+                // sync the genesis config, peers on a chain this node can't validate are dropped
+                loop {
+                    match network.request_genesis_config().await {
+                        Ok((peer_addr, genesis_config)) => {
+                            // blocks of the chain can't be validated with another layer count
+                            if genesis_config.encoding_layers as usize != ENCODING_LAYERS {
+                                warn!(
+                                    "Peer {} encodes pieces with {} layers, but this node with {}, disconnecting",
+                                    peer_addr, genesis_config.encoding_layers, ENCODING_LAYERS
+                                );
+                                network.add_to_block_list(peer_addr).await;
+                                continue;
+                            }
+                            ledger.lock().await.set_genesis_config(genesis_config);
+                            break;
+                        }
+                        Err(error) => {
+                            panic!("Failed to request genesis config: {:?}", error);
+                        }
                     }
                 }
 
@@ -466,93 +706,136 @@ pub async fn run(
                 }
                 info!("Synced the state chain!");
 
+                // pieces removed here are fetched again below
+                if repair_plots {
+                    let merkle_roots = locked_ledger.state.merkle_roots();
+                    for farmer_plot in plots.iter() {
+                        match plotter::repair_plot(&farmer_plot.plot, merkle_roots.clone()).await {
+                            Ok(report) => {
+                                if !report.is_ok() {
+                                    warn!(
+                                        "Plot {} had problems and was repaired, run `plot verify` for a full check:\n{}",
+                                        farmer_plot.index, report
+                                    );
+                                }
+                            }
+                            Err(error) => {
+                                error!("Failed to verify plot {}: {}", farmer_plot.index, error);
+                            }
+                        }
+                    }
+                }
+
                 // TODO: Handle the edge case where ...
                 // TODO: what if the we sync the state chain, then a new state block is encoded before and we try to sync those pieces with no merkle root to validate against
 
-                // sync state and plot
+                // sync state and plot, pieces are fetched, decoded and plotted in batches
                 let mut piece_index = 0;
-                let sloth = sloth::Sloth::init(PRIME_SIZE_BITS);
-                loop {
-                    match network.request_piece_by_index(piece_index).await {
-                        Ok(piece_bundle) => {
-                            match piece_bundle {
-                                Some(piece_bundle) => {
-                                    // decode the piece
-                                    let expanded_iv = crypto::expand_iv(piece_bundle.node_id);
-                                    let mut decoding = piece_bundle.encoding.clone();
-                                    sloth.decode(
-                                        decoding.as_mut(),
-                                        expanded_iv,
-                                        ENCODING_LAYERS_TEST,
-                                    );
-
-                                    // compute the piece hash
-                                    let decoding_hash = crypto::digest_sha_256(&decoding);
-
-                                    // use the piece index to compute the state block and get merkle root
-                                    let state_block_index =
-                                        piece_index / PIECES_PER_STATE_BLOCK as u64;
-
-                                    let merkle_root = locked_ledger
-                                        .state
-                                        .get_state_block_by_height(state_block_index)
-                                        .unwrap()
-                                        .piece_merkle_root;
-
-                                    // verify the merkle proof
-                                    if !crypto::validate_merkle_proof(
-                                        decoding_hash,
-                                        &piece_bundle.piece_proof,
-                                        &merkle_root,
-                                    ) {
-                                        panic!("Invalid piece received via sync, merkle proof is invalid!");
-                                    }
+                let synced_piece_count = state_block_height * PIECES_PER_STATE_BLOCK as u64;
+                let sloth = ENCODING_SCHEME.sloth();
+                let decoding_service = locked_ledger.decoding_service.clone();
+                let mut is_synced = false;
+                while !is_synced {
+                    // fetched pieces, each with positions of the plots that are missing it
+                    let mut fetched = Vec::with_capacity(PLOT_WRITE_BATCH_SIZE);
+                    while fetched.len() < PLOT_WRITE_BATCH_SIZE {
+                        // only fetch pieces some plot is missing, e.g. corrupted ones removed on startup
+                        let mut missing_plots = Vec::new();
+                        for (position, farmer_plot) in plots.iter().enumerate() {
+                            if piece_index < farmer_plot.size as u64
+                                && !farmer_plot.plot.contains(piece_index).await
+                            {
+                                missing_plots.push(position);
+                            }
+                        }
+                        if missing_plots.is_empty() && piece_index < synced_piece_count {
+                            piece_index += 1;
+                            continue;
+                        }
 
-                                    // encode with node_id
-                                    let expanded_iv = crypto::expand_iv(node_id);
-                                    let integer_expanded_iv =
-                                        Integer::from_digits(&expanded_iv, Order::Lsf);
-                                    let mut encoding = decoding[..].try_into().unwrap();
-
-                                    sloth
-                                        .encode(
-                                            &mut encoding,
-                                            &integer_expanded_iv,
-                                            ENCODING_LAYERS_TEST,
-                                        )
-                                        .unwrap();
-
-                                    // compute the nonce
-                                    let nonce = u64::from_le_bytes(
-                                        crypto::create_hmac(&encoding, b"subspace")[0..8]
-                                            .try_into()
-                                            .unwrap(),
-                                    );
+                        match network.request_piece_by_index(piece_index).await {
+                            Ok(Some(piece_bundle)) => {
+                                fetched.push((piece_index, piece_bundle, missing_plots));
+                                piece_index += 1;
+                            }
+                            Ok(None) => {
+                                is_synced = true;
+                                break;
+                            }
+                            Err(error) => {
+                                panic!(
+                                    "Failed to request piece for index {}: {:?}",
+                                    piece_index, error
+                                );
+                            }
+                        }
+                    }
+                    if fetched.is_empty() {
+                        continue;
+                    }
 
-                                    // add to the plot
-                                    let result = plot
-                                        .write(
-                                            encoding,
-                                            nonce,
-                                            piece_index,
-                                            piece_bundle.piece_proof,
-                                        )
-                                        .await;
+                    // decode the whole batch at once on the decoding pool
+                    let decodings = decoding_service
+                        .decode(
+                            fetched
+                                .iter()
+                                .map(|(piece_index, piece_bundle, _)| {
+                                    (
+                                        piece_bundle.encoding.clone(),
+                                        ENCODING_SCHEME
+                                            .expanded_iv(piece_bundle.node_id, *piece_index),
+                                    )
+                                })
+                                .collect(),
+                        )
+                        .await;
+
+                    let mut plot_piece_bundles: Vec<Vec<PieceBundle>> =
+                        plots.iter().map(|_| Vec::new()).collect();
+                    for ((piece_index, piece_bundle, missing_plots), decoding) in
+                        fetched.into_iter().zip(decodings)
+                    {
+                        // compute the piece hash
+                        let decoding_hash = crypto::digest_sha_256(&decoding);
+
+                        // use the piece index to compute the state block and get merkle root
+                        let state_block_index = piece_index / PIECES_PER_STATE_BLOCK as u64;
+
+                        let merkle_root = locked_ledger
+                            .state
+                            .get_state_block_by_height(state_block_index)
+                            .unwrap()
+                            .piece_merkle_root;
+
+                        // verify the merkle proof
+                        if !crypto::validate_merkle_proof(
+                            decoding_hash,
+                            &piece_bundle.piece_proof,
+                            &merkle_root,
+                        ) {
+                            panic!("Invalid piece received via sync, merkle proof is invalid!");
+                        }
 
-                                    if let Err(error) = result {
-                                        panic!("{}", error);
-                                    }
+                        let piece = decoding[..].try_into().unwrap();
+                        for position in missing_plots {
+                            plot_piece_bundles[position].push(PieceBundle {
+                                piece,
+                                piece_id: decoding_hash,
+                                piece_index,
+                                piece_proof: piece_bundle.piece_proof.clone(),
+                            });
+                        }
+                    }
 
-                                    piece_index += 1;
-                                }
-                                None => break,
-                            }
+                    // encode with the node id of each plot and add to it
+                    for (farmer_plot, piece_bundles) in plots.iter().zip(plot_piece_bundles) {
+                        if piece_bundles.is_empty() {
+                            continue;
                         }
-                        Err(error) => {
-                            panic!(
-                                "Failed to request piece for index {}: {:?}",
-                                piece_index, error
-                            );
+                        let writes =
+                            PieceWrite::encode_batch(&sloth, farmer_plot.node_id, piece_bundles);
+                        if let Err(error) = farmer_plot.plot.write_many(writes).await {
+                            panic!("{}", error);
                         }
                     }
                 }
@@ -566,6 +849,23 @@ pub async fn run(
                         Ok(bundle) => {
                             // let mut locked_ledger = ledger.lock().await;
 
+                            // a peer whose blocks were farmed with other epoch randomness follows
+                            // a different chain, drop the whole bundle and ask another peer
+                            let mut has_expected_randomness = true;
+                            for block in bundle.0.iter() {
+                                if !locked_ledger.has_expected_randomness(block).await {
+                                    has_expected_randomness = false;
+                                    break;
+                                }
+                            }
+                            if !has_expected_randomness {
+                                error!(
+                                    "Rejecting blocks received via sync for timeslot {}, epoch randomness does not match, requesting them again",
+                                    timeslot
+                                );
+                                continue;
+                            }
+
                             for tx in bundle.1.iter() {
                                 let tx_id = tx.get_id();
 
@@ -589,18 +889,42 @@ pub async fn run(
                                 }
                             }
 
+                            if timeslot == 0 {
+                                // start the timer from genesis time
+                                // TODO: start timer at node init once we have canonical genesis time
+                            }
+
+                            // run the cheap checks first, only blocks that pass them are decoded
+                            let mut checked_blocks = Vec::with_capacity(bundle.0.len());
                             for block in bundle.0.iter() {
-                                if timeslot == 0 {
-                                    // start the timer from genesis time
-                                    // TODO: start timer at node init once we have canonical genesis time
+                                if locked_ledger.check_block_from_sync(&block, timeslot).await {
+                                    checked_blocks.push(block);
+                                } else {
+                                    // TODO: start over with a different peer
+                                    error!(
+                                        "Not staging invalid block received via sync for timeslot {}",
+                                        timeslot
+                                    );
                                 }
+                            }
 
-                                // validate the block
-                                if !locked_ledger
-                                    .validate_block_from_sync(&block, timeslot)
-                                    .await
-                                {
+                            // decode them at once on the decoding pool, without holding the ledger
+                            let encodings =
+                                block::encodings_to_decode(checked_blocks.iter().copied());
+                            drop(locked_ledger);
+                            let decoding_hashes = decoding_service.piece_hashes(encodings).await;
+                            locked_ledger = ledger.lock().await;
+
+                            for (block, decoding_hash) in
+                                checked_blocks.into_iter().zip(decoding_hashes)
+                            {
+                                if !block.has_valid_encoding(&decoding_hash) {
                                     // TODO: start over with a different peer
+                                    error!(
+                                        "Not staging block with invalid encoding received via sync for timeslot {}",
+                                        timeslot
+                                    );
+                                    continue;
                                 }
 
                                 // stage the block
@@ -611,21 +935,17 @@ pub async fn run(
                                 }
                             }
 
-                            let next_timeslot_arrival_time = Duration::from_millis(
-                                ((timeslot + 1) * TIMESLOT_DURATION)
-                                    + locked_ledger.genesis_timestamp as u64,
-                            );
+                            let next_timeslot_arrival_time = ((timeslot + 1) * TIMESLOT_DURATION)
+                                + locked_ledger.genesis_timestamp as u64;
 
-                            let time_now = SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .expect("Time went backwards");
+                            let time_now = clock.now();
 
                             // check if we have arrived at the next timeslot
                             if next_timeslot_arrival_time < time_now {
                                 // increment the epoch on boundary
                                 if (timeslot + 1) % TIMESLOTS_PER_EPOCH as u64 == 0 {
                                     // create new epoch
-                                    let current_epoch = epoch_tracker.advance_epoch().await;
+                                    let current_epoch = locked_ledger.advance_epoch().await;
 
                                     debug!(
                                         "Closed randomness for epoch {} during sync",
@@ -655,6 +975,7 @@ pub async fn run(
                                     locked_ledger.genesis_timestamp,
                                     timeslot,
                                     Arc::clone(&ledger),
+                                    Arc::clone(&clock),
                                 );
 
                                 // apply any proposer blocks from cached gossip
@@ -670,12 +991,18 @@ pub async fn run(
                                     .drain()
                                     .collect();
 
-                                for (content_id, tx_block) in cached_tx_blocks.iter() {
-                                    if !locked_ledger.metablocks.contains_content_id(content_id) {
-                                        if locked_ledger.validate_block_from_cache(tx_block).await {
-                                            locked_ledger.stage_tx_block(tx_block).await;
-                                        }
-                                    }
+                                let cached_tx_blocks = cached_tx_blocks
+                                    .into_iter()
+                                    .filter(|(content_id, _)| {
+                                        !locked_ledger.metablocks.contains_content_id(content_id)
+                                    })
+                                    .map(|(_, tx_block)| tx_block)
+                                    .collect();
+                                for tx_block in locked_ledger
+                                    .validate_blocks_from_cache(cached_tx_blocks)
+                                    .await
+                                {
+                                    locked_ledger.stage_tx_block(&tx_block).await;
                                 }
 
                                 break;
@@ -700,11 +1027,19 @@ pub async fn run(
                 state.node_type = node_type.to_string();
                 state.peers = state.peers + "/" + &MIN_PEERS.to_string()[..];
                 state.blocks = "TODO".to_string();
-                state.pieces = match node_type {
-                    NodeType::Gateway => PLOT_SIZE.to_string(),
-                    NodeType::Farmer => PLOT_SIZE.to_string(),
-                    NodeType::Peer => 0.to_string(),
-                };
+                state.clock_offset = format!("{} ms", clock.offset().median());
+                state.pieces = format!(
+                    "{}/{}",
+                    plots
+                        .iter()
+                        .map(|farmer_plot| farmer_plot.plot.stats().piece_count)
+                        .sum::<u64>(),
+                    plots
+                        .iter()
+                        .map(|farmer_plot| farmer_plot.size)
+                        .sum::<usize>()
+                );
+                state.audits = audit_stats.summary().to_string();
                 state_sender.send(state).unwrap();
 
                 task::sleep(Duration::from_millis(1000)).await;
@@ -715,6 +1050,7 @@ pub async fn run(
     futures::join!(
         gossip_handling,
         requests_handling,
+        clock_sync,
         protocol_listener,
         protocol_startup
     );
@@ -75,12 +75,13 @@
 pub(crate) mod messages;
 mod nodes_container;
 
-use crate::block::Block;
+use crate::block::{Block, BlockBody};
 use crate::manager::GenesisConfig;
 use crate::network::messages::{
-    BlockRequestByContentId, BlockRequestByProofId, GenesisConfigRequest, InternalRequestMessage,
-    InternalResponseMessage, PieceRequestById, PieceRequestByIndex, StateBlockRequestByHeight,
-    StateBlockRequestById, TxRequestById,
+    BlockBodyRequestByProofId, BlockRequestByContentId, BlockRequestByProofId,
+    GenesisConfigRequest, InternalRequestMessage, InternalResponseMessage, PieceRequestById,
+    PieceRequestByIndex, PingRequest, StateBlockRequestByHeight, StateBlockRequestById,
+    TxRequestById,
 };
 use crate::network::nodes_container::{NodesContainer, Peer, PendingPeer};
 use crate::state::{
@@ -1217,6 +1218,26 @@ impl Network {
         }
     }
 
+    /// Requests the body of a block whose header was gossiped by `peer_addr` from that peer
+    pub(crate) async fn request_block_body_by_proof_id(
+        &self,
+        peer_addr: &SocketAddr,
+        id: ProofId,
+    ) -> Result<Option<BlockBody>, RequestError> {
+        let peer = self.peer(peer_addr).await.ok_or(RequestError::NoPeers)?;
+        let response = self
+            .request_from_peer(
+                peer,
+                RequestMessage::BlockBodyByProofId(BlockBodyRequestByProofId { id }),
+            )
+            .await?;
+
+        match response {
+            ResponseMessage::BlockBodyByProofId(response) => Ok(response.body),
+            _ => Err(RequestError::BadResponse),
+        }
+    }
+
     pub(crate) async fn _request_tx_by_id(
         &self,
         id: TxId,
@@ -1289,17 +1310,46 @@ impl Network {
         }
     }
 
-    pub(crate) async fn request_genesis_config(&self) -> Result<GenesisConfig, RequestError> {
+    /// Requests genesis config from a random peer, returns it along with address of that peer
+    pub(crate) async fn request_genesis_config(
+        &self,
+    ) -> Result<(SocketAddr, GenesisConfig), RequestError> {
+        let peer = self.random_peer().await.ok_or(RequestError::NoPeers)?;
+        let peer_addr = *peer.address();
         let response = self
-            .request(RequestMessage::GenesisConfig(GenesisConfigRequest {}))
+            .request_from_peer(peer, RequestMessage::GenesisConfig(GenesisConfigRequest {}))
             .await?;
 
         match response {
-            ResponseMessage::GenesisConfig(response) => Ok(response.genesis_config),
+            ResponseMessage::GenesisConfig(response) => Ok((peer_addr, response.genesis_config)),
             _ => Err(RequestError::BadResponse),
         }
     }
 
+    /// Asks a specific peer for its current time, used to estimate local clock offset
+    pub(crate) async fn ping(&self, peer_addr: &SocketAddr) -> Result<u64, RequestError> {
+        let peer = self.peer(peer_addr).await.ok_or(RequestError::NoPeers)?;
+
+        let response = self
+            .request_from_peer(peer, RequestMessage::Ping(PingRequest {}))
+            .await?;
+
+        match response {
+            ResponseMessage::Ping(response) => Ok(response.timestamp),
+            _ => Err(RequestError::BadResponse),
+        }
+    }
+
+    pub(crate) async fn get_peer_addresses(&self) -> Vec<SocketAddr> {
+        self.inner
+            .nodes_container
+            .lock()
+            .await
+            .get_peers()
+            .map(|peer| *peer.address())
+            .collect()
+    }
+
     pub(crate) fn get_gossip_receiver(
         &self,
     ) -> Option<async_channel::Receiver<(SocketAddr, GossipMessage)>> {
@@ -1331,7 +1381,9 @@ impl Network {
             connections: connections.to_string(),
             peers: "".to_string(),
             pieces: String::from(""),
+            audits: String::from(""),
             blocks: String::from(""),
+            clock_offset: String::from(""),
         }
     }
 
@@ -1360,6 +1412,39 @@ impl Network {
 
     /// Non-generic method to avoid significant duplication in final binary
     async fn request(&self, message: RequestMessage) -> Result<ResponseMessage, RequestError> {
+        match self.random_peer().await {
+            Some(peer) => self.request_from_peer(peer, message).await,
+            None => Err(RequestError::NoPeers),
+        }
+    }
+
+    async fn peer(&self, peer_addr: &SocketAddr) -> Option<Peer> {
+        self.inner
+            .nodes_container
+            .lock()
+            .await
+            .get_peers()
+            .find(|peer| peer.address() == peer_addr)
+            .cloned()
+    }
+
+    async fn random_peer(&self) -> Option<Peer> {
+        (self
+            .inner
+            .nodes_container
+            .lock()
+            .await
+            .get_peers()
+            // This is just for IDE that can't figure out type otherwise
+            .choose(&mut rand::thread_rng()) as Option<&Peer>)
+            .cloned()
+    }
+
+    async fn request_from_peer(
+        &self,
+        peer: Peer,
+        message: RequestMessage,
+    ) -> Result<ResponseMessage, RequestError> {
         let id;
         let (response_sender, response_receiver) = async_oneshot::oneshot();
         let requests_container = &self.inner.requests_container;
@@ -1380,22 +1465,9 @@ impl Network {
             return Err(RequestError::MessageTooLong);
         }
 
-        let peer = (self
-            .inner
-            .nodes_container
-            .lock()
-            .await
-            .get_peers()
-            // This is just for IDE that can't figure out type otherwise
-            .choose(&mut rand::thread_rng()) as Option<&Peer>)
-            .cloned();
-        if let Some(peer) = peer {
-            async_std::task::spawn(async move {
-                peer.send(message).await;
-            });
-        } else {
-            return Err(RequestError::NoPeers);
-        }
+        async_std::task::spawn(async move {
+            peer.send(message).await;
+        });
 
         future::or(
             async move {
@@ -1620,7 +1692,7 @@ mod tests {
     use crate::block::{Block, Content, Proof};
     use crate::network::messages::BlocksResponse;
     use crate::transaction::{AccountAddress, CoinbaseTx, SimpleCreditTx};
-    use crate::{ContentId, ProofId, Tag};
+    use crate::{ContentId, ProofId, Salt, Tag};
     use futures::executor;
     use std::fs;
     use std::ops::Deref;
@@ -1667,7 +1739,7 @@ mod tests {
                 timeslot: 0,
                 public_key: [0u8; 32],
                 tag: Tag::default(),
-                nonce: 0,
+                salt: Salt::default(),
                 piece_index: 0,
                 solution_range: 0,
             },
@@ -1676,14 +1748,16 @@ mod tests {
                 parent_id: Some(ContentId::default()),
                 proof_signature: vec![],
                 timestamp: 0,
-                refs: vec![],
+                refs_root: [0u8; 32],
                 signature: vec![],
             },
+            refs: vec![],
             coinbase_tx: CoinbaseTx {
                 reward: 0,
                 to_address: AccountAddress::default(),
                 proof_id: ProofId::default(),
             },
+            header_signature: vec![],
         }
     }
 
@@ -1751,7 +1825,7 @@ mod tests {
 
                 gateway_network
                     .gossip(GossipMessage::BlockProposal {
-                        block: fake_block(),
+                        header: fake_block().header(),
                     })
                     .await;
                 assert_eq!(
@@ -1764,7 +1838,7 @@ mod tests {
                     .regossip(
                         &"127.0.0.1:0".parse().unwrap(),
                         GossipMessage::BlockProposal {
-                            block: fake_block(),
+                            header: fake_block().header(),
                         },
                     )
                     .await;
@@ -1835,7 +1909,7 @@ mod tests {
                     async_std::task::spawn(async move {
                         peer_network
                             .gossip(GossipMessage::BlockProposal {
-                                block: fake_block(),
+                                header: fake_block().header(),
                             })
                             .await;
                     });
@@ -1856,7 +1930,7 @@ mod tests {
                             .regossip(
                                 &"127.0.0.1:0".parse().unwrap(),
                                 GossipMessage::BlockProposal {
-                                    block: fake_block(),
+                                    header: fake_block().header(),
                                 },
                             )
                             .await;
@@ -1,4 +1,4 @@
-use crate::block::Block;
+use crate::block::{Block, BlockBody, BlockHeader};
 use crate::manager::GenesisConfig;
 use crate::state::{
     BlockHeight, NetworkPieceBundleById, NetworkPieceBundleByIndex, StateBlock, StateBlockId,
@@ -15,8 +15,13 @@ use std::net::SocketAddr;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum GossipMessage {
-    BlockProposal { block: Block },
-    TxProposal { tx: SimpleCreditTx },
+    /// Header of a new block, peers fetch the body from the sender once the header checks out
+    BlockProposal {
+        header: BlockHeader,
+    },
+    TxProposal {
+        tx: SimpleCreditTx,
+    },
 }
 
 impl Display for GossipMessage {
@@ -63,6 +68,16 @@ pub(crate) struct BlockResponseByProofId {
     pub(crate) block: Option<Block>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BlockBodyRequestByProofId {
+    pub(crate) id: ProofId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BlockBodyResponseByProofId {
+    pub(crate) body: Option<BlockBody>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct TxRequestById {
     pub(crate) id: TxId,
@@ -121,17 +136,28 @@ pub(crate) struct GenesisConfigResponse {
     pub(crate) genesis_config: GenesisConfig,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PingRequest {}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PingResponse {
+    /// responder's current time in milliseconds since UNIX epoch
+    pub(crate) timestamp: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum RequestMessage {
     Blocks(BlocksRequest),
     BlockByContentId(BlockRequestByContentId),
     BlockByProofId(BlockRequestByProofId),
+    BlockBodyByProofId(BlockBodyRequestByProofId),
     TransactionById(TxRequestById),
     PieceById(PieceRequestById),
     PieceByIndex(PieceRequestByIndex),
     StateById(StateBlockRequestById),
     StateByHeight(StateBlockRequestByHeight),
     GenesisConfig(GenesisConfigRequest),
+    Ping(PingRequest),
 }
 
 impl Display for RequestMessage {
@@ -143,12 +169,14 @@ impl Display for RequestMessage {
                 Self::Blocks { .. } => "Blocks",
                 Self::BlockByContentId { .. } => "BlockByContentId",
                 Self::BlockByProofId { .. } => "BlockByProofId",
+                Self::BlockBodyByProofId { .. } => "BlockBodyByProofId",
                 Self::TransactionById { .. } => "Transaction",
                 Self::PieceById { .. } => "PieceById",
                 Self::PieceByIndex { .. } => "PieceByIndex",
                 Self::StateById { .. } => "StateById",
                 Self::StateByHeight { .. } => "StateByHeight",
                 Self::GenesisConfig { .. } => "GenesisConfig",
+                Self::Ping { .. } => "Ping",
             }
         )
     }
@@ -159,12 +187,14 @@ pub(crate) enum ResponseMessage {
     Blocks(BlocksResponse),
     BlockByContentId(BlockResponseByContentId),
     BlockByProofId(BlockResponseByProofId),
+    BlockBodyByProofId(BlockBodyResponseByProofId),
     TransactionById(TxResponseById),
     PieceById(PieceResponseById),
     PieceByIndex(PieceResponseByIndex),
     StateById(StateBlockResponseById),
     StateByHeight(StateBlockResponseByHeight),
     GenesisConfig(GenesisConfigResponse),
+    Ping(PingResponse),
 }
 
 impl Display for ResponseMessage {
@@ -176,12 +206,14 @@ impl Display for ResponseMessage {
                 Self::Blocks { .. } => "Blocks",
                 Self::BlockByContentId { .. } => "BlockByContentId",
                 Self::BlockByProofId { .. } => "BlockByProofId",
+                Self::BlockBodyByProofId { .. } => "BlockBodyByProofId",
                 Self::TransactionById { .. } => "Transaction",
                 Self::PieceById { .. } => "PieceById",
                 Self::PieceByIndex { .. } => "PieceByIndex",
                 Self::StateById { .. } => "StateById",
                 Self::StateByHeight { .. } => "StateByHeight",
                 Self::GenesisConfig { .. } => "GenesisConfig",
+                Self::Ping { .. } => "Ping",
             }
         )
     }
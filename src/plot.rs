@@ -1,35 +1,57 @@
+mod commitment;
+mod compaction;
+mod header;
+mod reader;
+mod recovery;
+mod stats;
+mod verify;
+
 use crate::state::{NetworkPieceBundleByIndex, PieceBundle};
 use crate::{
-    crypto, sloth, NodeID, Piece, PieceId, PieceIndex, Tag, ENCODING_LAYERS_TEST, PIECE_SIZE,
-    PRIME_SIZE_BITS,
+    crypto, sloth, ExpandedIV, NodeID, Piece, PieceId, PieceIndex, Salt, Tag, ENCODING_LAYERS,
+    ENCODING_SCHEME, PIECE_SIZE, PLOT_WRITE_BATCH_SIZE,
 };
-use async_std::fs::OpenOptions;
+use async_std::fs::{File, OpenOptions};
 use async_std::path::PathBuf;
+use async_std::sync::RwLock;
 use async_std::task;
 use event_listener_primitives::{Bag, HandlerId};
 use futures::channel::mpsc as async_mpsc;
 use futures::channel::oneshot;
 use futures::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SinkExt, StreamExt};
+pub use header::{PlotHeader, PlotHeaderError, HEADER_SIZE, PLOT_FORMAT_VERSION};
 use log::*;
+use rand::seq::SliceRandom;
+use reader::PlotReader;
 use rocksdb::IteratorMode;
-use rocksdb::DB;
-use rug::integer::Order;
-use rug::Integer;
+use rocksdb::{WriteBatch, WriteOptions, DB};
+use stats::{LatencyRecorder, SizeCounters};
+pub use stats::{LatencyStats, PlotStats};
+use std::collections::{BTreeSet, HashMap};
 use std::convert::TryInto;
 use std::io;
 use std::io::SeekFrom;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
+pub use verify::{VerifyMode, VerifyOptions, VerifyReport};
 
 /*
-   Plot File -> all encodings
+   Plot File -> header, then all encodings
    Map DB -> (K: index, V: (position, merkle_proof))
-   Tags DB -> (K: tag_prefix, V: index)
+   Tags DB -> (K: tag_prefix, V: index), tags commit to the salt in `plot.salt`
 
    FindByRange(target, range) -> Vec<Tag, index>
-   Read(index) -> Encoding
-   Write(encoding, nonce, index) -> Result()
+   Stats() -> stats, sizes are stored by the loop after every write
+   Read(index) -> Encoding, done directly with positioned reads, concurrently with the rest
+   Verify(options) -> Result(report), reads like Read, only its repair is a request
+   Write(Vec<(encoding, index)>) -> Result()
    Remove(index) -> Result()
+   Resize(piece_count) -> Result(evicted)
+   Compact() -> Result(reclaimed bytes)
+   Repair(repair) -> Result()
+   Recommit(salt) -> Result()
 */
 
 #[derive(Debug)]
@@ -38,6 +60,7 @@ pub enum PlotCreationError {
     PlotMapOpen(rocksdb::Error),
     PlotTagsOpen(rocksdb::Error),
     MapRead(io::Error),
+    Header(PlotHeaderError),
 }
 
 #[derive(Debug)]
@@ -45,9 +68,9 @@ enum ReadRequests {
     IsEmpty {
         result_sender: oneshot::Sender<bool>,
     },
-    ReadEncoding {
+    Contains {
         index: u64,
-        result_sender: oneshot::Sender<io::Result<(Piece, Vec<u8>)>>,
+        result_sender: oneshot::Sender<bool>,
     },
     FindByRange {
         target: Tag,
@@ -58,17 +81,86 @@ enum ReadRequests {
 
 #[derive(Debug)]
 enum WriteRequests {
-    WriteEncoding {
-        encoding: Piece,
-        nonce: u64,
-        index: u64,
-        merkle_proof: Vec<u8>,
+    WriteEncodings {
+        writes: Vec<PieceWrite>,
         result_sender: oneshot::Sender<io::Result<()>>,
     },
     RemoveEncoding {
         index: u64,
         result_sender: oneshot::Sender<io::Result<()>>,
     },
+    Resize {
+        piece_count: u64,
+        result_sender: oneshot::Sender<io::Result<u64>>,
+    },
+    Repair {
+        repair: verify::Repair,
+        result_sender: oneshot::Sender<io::Result<()>>,
+    },
+    Compact {
+        result_sender: oneshot::Sender<io::Result<u64>>,
+    },
+    Recommit {
+        recommitment: commitment::Recommitment,
+        result_sender: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+/// Encoded piece to be written to the plot by index, will overwrite if piece exists (updates)
+#[derive(Debug)]
+pub struct PieceWrite {
+    pub encoding: Piece,
+    pub index: u64,
+    pub merkle_proof: Vec<u8>,
+}
+
+impl PieceWrite {
+    /// Encodes the piece for the plot of `node_id` with `ENCODING_SCHEME`
+    pub fn encode(
+        encoder: &dyn sloth::Encoder,
+        node_id: NodeID,
+        piece_bundle: PieceBundle,
+    ) -> Self {
+        let mut encoding = piece_bundle.piece;
+        encoder.encode(
+            &mut encoding,
+            ENCODING_SCHEME.expanded_iv(node_id, piece_bundle.piece_index),
+            ENCODING_LAYERS,
+        );
+
+        PieceWrite {
+            encoding,
+            index: piece_bundle.piece_index,
+            merkle_proof: piece_bundle.piece_proof,
+        }
+    }
+
+    /// Encodes many pieces for the plot in parallel
+    pub fn encode_batch(
+        encoder: &dyn sloth::Encoder,
+        node_id: NodeID,
+        piece_bundles: Vec<PieceBundle>,
+    ) -> Vec<Self> {
+        let mut encodings: Vec<Piece> = piece_bundles
+            .iter()
+            .map(|piece_bundle| piece_bundle.piece)
+            .collect();
+        let expanded_ivs: Vec<ExpandedIV> = piece_bundles
+            .iter()
+            .map(|piece_bundle| ENCODING_SCHEME.expanded_iv(node_id, piece_bundle.piece_index))
+            .collect();
+        encoder.encode_batch(&mut encodings, &expanded_ivs, ENCODING_LAYERS);
+
+        encodings
+            .into_iter()
+            .zip(piece_bundles)
+            .map(|(encoding, piece_bundle)| PieceWrite {
+                encoding,
+                index: piece_bundle.piece_index,
+                merkle_proof: piece_bundle.piece_proof,
+            })
+            .collect()
+    }
 }
 
 #[derive(Default)]
@@ -78,6 +170,20 @@ struct Handlers {
 
 pub struct Inner {
     handlers: Arc<Handlers>,
+    header: PlotHeader,
+    /// salt tags are currently committed to, as little endian `u64`
+    salt: Arc<AtomicU64>,
+    map_db: Arc<DB>,
+    tags_db: Arc<DB>,
+    plot_file_path: std::path::PathBuf,
+    /// shared by reads, taken exclusively before freed slots can be reused or the plot file is
+    /// replaced, so that a read never sees another piece at the position it looked up
+    reader: Arc<RwLock<Arc<PlotReader>>>,
+    sizes: Arc<SizeCounters>,
+    read_latency: LatencyRecorder,
+    write_latency: LatencyRecorder,
+    /// requests sent to the loop that processes them, but not taken from the queue yet
+    pending_requests: Arc<AtomicUsize>,
     any_requests_sender: async_mpsc::Sender<()>,
     read_requests_sender: async_mpsc::UnboundedSender<ReadRequests>,
     write_requests_sender: async_mpsc::UnboundedSender<WriteRequests>,
@@ -88,7 +194,6 @@ pub struct Inner {
  * Return result for solve()
  * Delete entire plot (perhaps with script) for testing
  * Extend tests
- * Resize plot by removing the last x indices and adjusting struct params
 */
 
 #[derive(Clone)]
@@ -98,15 +203,14 @@ pub struct Plot {
 
 impl Plot {
     /// Creates a new plot for persisting encoded pieces to disk
-    pub async fn open_or_create(path: &PathBuf) -> Result<Plot, PlotCreationError> {
-        let mut plot_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path.join("plot.bin"))
-            .await
-            .map_err(PlotCreationError::PlotOpen)?;
-
+    ///
+    /// Existing plot is only opened if its header matches `node_id` and the running configuration,
+    /// a new plot records `key_index` of the key pair `node_id` was derived from in its header.
+    pub async fn open_or_create(
+        path: &PathBuf,
+        node_id: NodeID,
+        key_index: u32,
+    ) -> Result<Plot, PlotCreationError> {
         let map_db = Arc::new(
             // DB::open_default(path.join("plot-map.rocksdb").to_str().unwrap())
             DB::open_default(path.join("plot-map")).map_err(PlotCreationError::PlotMapOpen)?,
@@ -117,6 +221,63 @@ impl Plot {
             DB::open_default(path.join("plot-tags")).map_err(PlotCreationError::PlotTagsOpen)?,
         );
 
+        // Compaction may have been interrupted, in which case it replaces the plot file
+        compaction::recover(path, &map_db)
+            .await
+            .map_err(PlotCreationError::PlotOpen)?;
+
+        let mut plot_file = open_plot_file(&path.join("plot.bin"))
+            .await
+            .map_err(PlotCreationError::PlotOpen)?;
+
+        let header = read_or_write_header(&mut plot_file, PlotHeader::new(node_id, key_index))
+            .await
+            .map_err(PlotCreationError::Header)?;
+
+        let std_path: std::path::PathBuf = path.clone().into();
+        let plot_file_path = std_path.join("plot.bin");
+
+        // Plot that wasn't closed cleanly may have writes that didn't complete
+        let dirty_marker_path = std_path.join(recovery::DIRTY_MARKER_FILE);
+        if dirty_marker_path.exists() {
+            let salt = commitment::read_salt(&std_path)
+                .map_err(PlotCreationError::PlotOpen)?
+                .unwrap_or_else(crypto::genesis_salt);
+            let report = recovery::recover(&plot_file_path, &map_db, &tags_db, salt)
+                .map_err(PlotCreationError::PlotOpen)?;
+            warn!(
+                "Plot {:?} wasn't closed cleanly, recovered: {:?}",
+                path, report
+            );
+        }
+
+        // Recommitment to a new salt may have been interrupted
+        let mut salt = commitment::resume(&std_path, &plot_file_path, &map_db, &tags_db)
+            .map_err(PlotCreationError::PlotOpen)?;
+        let current_salt = Arc::new(AtomicU64::new(u64::from_le_bytes(salt)));
+
+        std::fs::File::create(&dirty_marker_path)
+            .and_then(|marker| marker.sync_all())
+            .map_err(PlotCreationError::PlotOpen)?;
+
+        let reader = Arc::new(RwLock::new(Arc::new(
+            PlotReader::open(&plot_file_path, false).map_err(PlotCreationError::PlotOpen)?,
+        )));
+
+        // Positions in the plot file that aren't used by any piece and can be written to
+        let mut free_slots = find_free_slots(
+            &map_db,
+            plot_file
+                .metadata()
+                .await
+                .map_err(PlotCreationError::PlotOpen)?
+                .len(),
+        );
+        let path = path.clone();
+
+        let sizes = Arc::new(SizeCounters::default());
+        store_sizes(&sizes, &plot_file, free_slots.len(), &tags_db).await;
+
         // Channel with at most single element to throttle loop below if there are no updates
         let (any_requests_sender, mut any_requests_receiver) = async_mpsc::channel::<()>(1);
         let (read_requests_sender, mut read_requests_receiver) =
@@ -125,10 +286,18 @@ impl Plot {
             async_mpsc::unbounded::<WriteRequests>();
 
         let handlers = Arc::new(Handlers::default());
+        let pending_requests = Arc::new(AtomicUsize::new(0));
 
         // TODO: Handle drop nicer: when read is dropped, make sure writes still all finish
         task::spawn({
             let handlers = Arc::clone(&handlers);
+            let current_salt = Arc::clone(&current_salt);
+            let map_db = Arc::clone(&map_db);
+            let tags_db = Arc::clone(&tags_db);
+            let plot_file_path = plot_file_path.clone();
+            let reader = Arc::clone(&reader);
+            let pending_requests = Arc::clone(&pending_requests);
+            let sizes = Arc::clone(&sizes);
 
             async move {
                 let mut did_nothing = true;
@@ -145,6 +314,9 @@ impl Plot {
                     // Process as many read requests as there is
                     while let Ok(read_request) = read_requests_receiver.try_next() {
                         did_nothing = false;
+                        if read_request.is_some() {
+                            pending_requests.fetch_sub(1, Ordering::SeqCst);
+                        }
 
                         match read_request {
                             Some(ReadRequests::IsEmpty { result_sender }) => {
@@ -158,36 +330,19 @@ impl Plot {
                                     .await,
                                 );
                             }
-                            Some(ReadRequests::ReadEncoding {
+                            Some(ReadRequests::Contains {
                                 index,
                                 result_sender,
                             }) => {
-                                // TODO: Remove unwrap
-                                let piece_data: Option<(u64, Vec<u8>)> = task::spawn_blocking({
-                                    let map_db = Arc::clone(&map_db);
-                                    move || map_db.get(index.to_le_bytes())
-                                })
-                                .await
-                                .unwrap()
-                                .map(|raw_piece_data| {
-                                    let position = u64::from_le_bytes(
-                                        raw_piece_data[0..8].try_into().unwrap(),
-                                    );
-                                    let merkle_proof = raw_piece_data[8..].to_vec();
-                                    (position, merkle_proof)
-                                });
-
-                                let _ = result_sender.send(match piece_data {
-                                    Some((position, merkle_proof)) => {
-                                        try {
-                                            plot_file.seek(SeekFrom::Start(position)).await?;
-                                            let mut buffer = [0u8; PIECE_SIZE];
-                                            plot_file.read_exact(&mut buffer).await?;
-                                            (buffer, merkle_proof)
+                                let _ = result_sender.send(
+                                    task::spawn_blocking({
+                                        let map_db = Arc::clone(&map_db);
+                                        move || {
+                                            matches!(map_db.get(index.to_le_bytes()), Ok(Some(_)))
                                         }
-                                    }
-                                    None => Err(io::Error::from(io::ErrorKind::NotFound)),
-                                });
+                                    })
+                                    .await,
+                                );
                             }
                             None => {
                                 break 'outer;
@@ -278,66 +433,240 @@ impl Plot {
                     if write_request.is_ok() {
                         did_nothing = false;
                     }
+                    let wrote = matches!(write_request, Ok(Some(_)));
+                    if wrote {
+                        pending_requests.fetch_sub(1, Ordering::SeqCst);
+                    }
                     // Process at most write request since reading is higher priority
                     match write_request {
-                        Ok(Some(WriteRequests::WriteEncoding {
+                        Ok(Some(WriteRequests::WriteEncodings {
+                            writes,
+                            result_sender,
+                        })) => {
+                            let result: io::Result<()> = try {
+                                // Replaced pieces are only freed once new ones are committed
+                                let replaced = task::spawn_blocking({
+                                    let map_db = Arc::clone(&map_db);
+                                    let plot_reader = Arc::clone(&*reader.read().await);
+                                    let indexes: Vec<u64> =
+                                        writes.iter().map(|write| write.index).collect();
+                                    move || find_replaced(&map_db, &plot_reader, indexes, salt)
+                                })
+                                .await
+                                .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+                                // Slots freed by other pieces are reused before the file
+                                // grows, appended pieces are written at once
+                                let end = plot_file.seek(SeekFrom::End(0)).await?;
+                                let mut appended = Vec::new();
+                                let mut positions = Vec::with_capacity(writes.len());
+                                for write in writes.iter() {
+                                    match free_slots.pop_first() {
+                                        Some(position) => {
+                                            positions.push(position);
+                                            plot_file.seek(SeekFrom::Start(position)).await?;
+                                            plot_file.write_all(&write.encoding).await?;
+                                        }
+                                        None => {
+                                            positions.push(end + appended.len() as u64);
+                                            appended.extend_from_slice(&write.encoding);
+                                        }
+                                    }
+                                }
+                                if !appended.is_empty() {
+                                    plot_file.seek(SeekFrom::Start(end)).await?;
+                                    plot_file.write_all(&appended).await?;
+                                }
+                                plot_file.sync_data().await?;
+
+                                let old_positions: Vec<u64> =
+                                    replaced.iter().map(|(position, _)| *position).collect();
+                                let old_tags: Vec<(Tag, u64)> = replaced
+                                    .into_iter()
+                                    .filter_map(|(_, old_tag)| old_tag)
+                                    .collect();
+                                task::spawn_blocking({
+                                    let map_db = Arc::clone(&map_db);
+                                    let tags_db = Arc::clone(&tags_db);
+                                    move || {
+                                        commit_writes(
+                                            &map_db, &tags_db, writes, positions, old_tags, salt,
+                                        )
+                                    }
+                                })
+                                .await
+                                .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+                                if !old_positions.is_empty() {
+                                    let _reads = reader.write().await;
+                                    free_slots.extend(old_positions);
+                                }
+                            };
+
+                            // Slots taken for the batch are free again unless it made it into
+                            // the map, which is the only one to know after a failed commit
+                            if result.is_err() {
+                                let plot_file_size =
+                                    plot_file.metadata().await.map(|metadata| metadata.len());
+                                if let Ok(plot_file_size) = plot_file_size {
+                                    let _reads = reader.write().await;
+                                    free_slots = task::spawn_blocking({
+                                        let map_db = Arc::clone(&map_db);
+                                        move || find_free_slots(&map_db, plot_file_size)
+                                    })
+                                    .await;
+                                }
+                            }
+
+                            let _ = result_sender.send(result);
+                        }
+                        Ok(Some(WriteRequests::RemoveEncoding {
                             index,
-                            nonce,
-                            encoding,
-                            merkle_proof,
                             result_sender,
                         })) => {
-                            // TODO: remove unwrap
-                            task::spawn_blocking({
-                                let map_db = Arc::clone(&map_db);
-                                move || map_db.delete(index.to_le_bytes())
-                            })
-                            .await
-                            .unwrap();
+                            let result: io::Result<()> = try {
+                                let replaced = task::spawn_blocking({
+                                    let map_db = Arc::clone(&map_db);
+                                    let tags_db = Arc::clone(&tags_db);
+                                    let plot_reader = Arc::clone(&*reader.read().await);
+                                    move || {
+                                        let replaced = find_replaced(
+                                            &map_db,
+                                            &plot_reader,
+                                            vec![index],
+                                            salt,
+                                        )?;
+                                        remove_piece(&map_db, &tags_db, index, &replaced)?;
+                                        Ok::<_, rocksdb::Error>(replaced)
+                                    }
+                                })
+                                .await
+                                .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+                                if !replaced.is_empty() {
+                                    let _reads = reader.write().await;
+                                    free_slots
+                                        .extend(replaced.iter().map(|(position, _)| *position));
+                                }
+                            };
+
+                            let _ = result_sender.send(result);
+                        }
+                        Ok(Some(WriteRequests::Resize {
+                            piece_count,
+                            result_sender,
+                        })) => {
+                            let _ = result_sender.send(
+                                try {
+                                    let (evicted, remaining) = task::spawn_blocking({
+                                        let map_db = Arc::clone(&map_db);
+                                        let tags_db = Arc::clone(&tags_db);
+                                        move || evict_pieces(&map_db, &tags_db, piece_count)
+                                    })
+                                    .await
+                                    .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+                                    // Evicted and removed pieces leave dead space
+                                    let plot_file_size = plot_file.metadata().await?.len();
+                                    if evicted > 0
+                                        && plot_file_size
+                                            > (HEADER_SIZE + remaining * PIECE_SIZE) as u64
+                                    {
+                                        let mut reader = reader.write().await;
+                                        plot_file =
+                                            compaction::compact(&path, &mut plot_file, &map_db)
+                                                .await?;
+                                        *reader = reopen_reader(&plot_file_path, &reader).await?;
+                                        free_slots.clear();
+                                    }
 
+                                    evicted
+                                },
+                            );
+                        }
+                        Ok(Some(WriteRequests::Repair {
+                            repair,
+                            result_sender,
+                        })) => {
                             let _ = result_sender.send(
                                 try {
-                                    let position = plot_file.seek(SeekFrom::End(0)).await?;
-                                    plot_file.write_all(&encoding).await?;
+                                    // tags were checked against the salt of the time
+                                    if repair.salt != salt {
+                                        Err(io::Error::new(
+                                            io::ErrorKind::Other,
+                                            "Plot was recommitted to another salt during verify",
+                                        ))?;
+                                    }
 
-                                    // TODO: remove unwrap
                                     task::spawn_blocking({
                                         let map_db = Arc::clone(&map_db);
                                         let tags_db = Arc::clone(&tags_db);
-                                        let tag =
-                                            crypto::create_hmac(&encoding, &nonce.to_le_bytes());
-                                        move || {
-                                            tags_db.put(&tag[0..8], index.to_le_bytes()).and_then(
-                                                |_| {
-                                                    // TODO: may want to put version field of the plotting software here
-                                                    let value = &[
-                                                        &position.to_le_bytes()[..],
-                                                        &merkle_proof[..],
-                                                    ]
-                                                    .concat();
-                                                    map_db.put(index.to_le_bytes(), value)
-                                                },
-                                            )
-                                        }
+                                        move || verify::repair(&map_db, &tags_db, repair)
                                     })
                                     .await
-                                    .unwrap();
+                                    .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+                                    let plot_file_size = plot_file.metadata().await?.len();
+                                    let _reads = reader.write().await;
+                                    free_slots = task::spawn_blocking({
+                                        let map_db = Arc::clone(&map_db);
+                                        move || find_free_slots(&map_db, plot_file_size)
+                                    })
+                                    .await;
                                 },
                             );
                         }
-                        Ok(Some(WriteRequests::RemoveEncoding {
-                            index,
+                        Ok(Some(WriteRequests::Compact { result_sender })) => {
+                            let _ = result_sender.send(
+                                try {
+                                    let plot_file_size = plot_file.metadata().await?.len();
+                                    let mut reader = reader.write().await;
+                                    plot_file =
+                                        compaction::compact(&path, &mut plot_file, &map_db).await?;
+                                    *reader = reopen_reader(&plot_file_path, &reader).await?;
+                                    free_slots.clear();
+
+                                    plot_file_size - plot_file.metadata().await?.len()
+                                },
+                            );
+                        }
+                        Ok(Some(WriteRequests::Recommit {
+                            recommitment,
                             result_sender,
                         })) => {
-                            // TODO: remove unwrap
-                            task::spawn_blocking({
-                                let map_db = Arc::clone(&map_db);
-                                move || map_db.delete(index.to_le_bytes())
-                            })
-                            .await
-                            .unwrap();
-
-                            let _ = result_sender.send(Ok(()));
+                            let _ = result_sender.send(
+                                try {
+                                    let new_salt = recommitment.salt;
+                                    if new_salt != salt {
+                                        let tags = task::spawn_blocking({
+                                            let std_path = std_path.clone();
+                                            let plot_file_path = plot_file_path.clone();
+                                            let map_db = Arc::clone(&map_db);
+                                            let tags_db = Arc::clone(&tags_db);
+                                            move || {
+                                                commitment::recommit(
+                                                    &std_path,
+                                                    &plot_file_path,
+                                                    &map_db,
+                                                    &tags_db,
+                                                    recommitment,
+                                                )
+                                            }
+                                        })
+                                        .await?;
+
+                                        salt = new_salt;
+                                        current_salt
+                                            .store(u64::from_le_bytes(salt), Ordering::SeqCst);
+                                        debug!(
+                                            "Recommitted {} tags of plot {:?} to salt {}",
+                                            tags,
+                                            path,
+                                            hex::encode(&salt)
+                                        );
+                                    }
+                                },
+                            );
                         }
                         Ok(None) => {
                             break 'outer;
@@ -346,8 +675,14 @@ impl Plot {
                             // Ignore
                         }
                     }
+
+                    if wrote {
+                        store_sizes(&sizes, &plot_file, free_slots.len(), &tags_db).await;
+                    }
                 }
 
+                let clean = plot_file.sync_all().await.is_ok();
+
                 std::thread::spawn({
                     let handlers = Arc::clone(&handlers);
 
@@ -355,6 +690,10 @@ impl Plot {
                         drop(map_db);
                         drop(tags_db);
 
+                        if clean {
+                            let _ = std::fs::remove_file(&dirty_marker_path);
+                        }
+
                         handlers.close.call_once_simple();
                     }
                 });
@@ -363,6 +702,16 @@ impl Plot {
 
         let inner = Inner {
             handlers,
+            header,
+            salt: current_salt,
+            map_db,
+            tags_db,
+            plot_file_path,
+            reader,
+            sizes,
+            read_latency: LatencyRecorder::default(),
+            write_latency: LatencyRecorder::default(),
+            pending_requests,
             any_requests_sender,
             read_requests_sender,
             write_requests_sender,
@@ -373,9 +722,35 @@ impl Plot {
         })
     }
 
+    /// Reads the header of the plot at `path` without opening it, `None` if the plot wasn't
+    /// created yet
+    pub async fn read_header(path: &PathBuf) -> Result<Option<PlotHeader>, PlotHeaderError> {
+        let plot_file_path = path.join("plot.bin");
+        if !plot_file_path.exists().await {
+            return Ok(None);
+        }
+
+        let mut plot_file = File::open(plot_file_path)
+            .await
+            .map_err(PlotHeaderError::Io)?;
+        let plot_file_size = plot_file
+            .metadata()
+            .await
+            .map_err(PlotHeaderError::Io)?
+            .len();
+        if plot_file_size == 0 {
+            return Ok(None);
+        }
+
+        read_existing_header(&mut plot_file, plot_file_size)
+            .await
+            .map(Some)
+    }
+
     pub async fn is_empty(&self) -> bool {
         let (result_sender, result_receiver) = oneshot::channel();
 
+        self.pending_requests.fetch_add(1, Ordering::SeqCst);
         self.read_requests_sender
             .clone()
             .send(ReadRequests::IsEmpty { result_sender })
@@ -390,25 +765,31 @@ impl Plot {
             .expect("Read result sender was dropped")
     }
 
-    /// Reads a piece from plot by index
+    /// Reads a piece from plot by index, any number of reads can run at once
     pub async fn read(&self, index: u64) -> io::Result<(Piece, Vec<u8>)> {
-        let (result_sender, result_receiver) = oneshot::channel();
-
-        self.read_requests_sender
-            .clone()
-            .send(ReadRequests::ReadEncoding {
-                index,
-                result_sender,
-            })
-            .await
-            .expect("Failed sending read encoding request");
+        // Held until the piece is read, such that its slot can't be reused in the meantime
+        let started = Instant::now();
+        let reads = self.reader.read().await;
+
+        let result = task::spawn_blocking({
+            let map_db = Arc::clone(&self.map_db);
+            let reader = Arc::clone(&reads);
+            move || {
+                let value = map_db
+                    .get(index.to_le_bytes())
+                    .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                let encoding = reader.read(position_of(&value))?;
+
+                Ok((encoding, value[8..].to_vec()))
+            }
+        })
+        .await;
 
-        // If fails - it is either full or disconnected, we don't care either way, so ignore result
-        let _ = self.any_requests_sender.clone().try_send(());
+        drop(reads);
+        self.read_latency.record(started.elapsed());
 
-        result_receiver
-            .await
-            .expect("Read encoding result sender was dropped")
+        result
     }
 
     pub async fn get_piece_bundle_by_index(
@@ -435,6 +816,7 @@ impl Plot {
     pub async fn find_by_range(&self, target: [u8; 8], range: u64) -> io::Result<Vec<(Tag, u64)>> {
         let (result_sender, result_receiver) = oneshot::channel();
 
+        self.pending_requests.fetch_add(1, Ordering::SeqCst);
         self.read_requests_sender
             .clone()
             .send(ReadRequests::FindByRange {
@@ -457,95 +839,372 @@ impl Plot {
     pub async fn write(
         &self,
         encoding: Piece,
-        nonce: u64,
         index: u64,
         merkle_proof: Vec<u8>,
     ) -> io::Result<()> {
+        self.write_many(vec![PieceWrite {
+            encoding,
+            index,
+            merkle_proof,
+        }])
+        .await
+    }
+
+    /// Writes pieces to the plot as a single batch, that either gets committed as a whole or is
+    /// dropped on next open if the node crashes in the middle
+    ///
+    /// Piece data is synced to disk before the batch is committed.
+    pub async fn write_many(&self, writes: Vec<PieceWrite>) -> io::Result<()> {
+        let started = Instant::now();
         let (result_sender, result_receiver) = oneshot::channel();
 
+        self.pending_requests.fetch_add(1, Ordering::SeqCst);
         self.write_requests_sender
             .clone()
-            .send(WriteRequests::WriteEncoding {
-                encoding,
-                nonce,
+            .send(WriteRequests::WriteEncodings {
+                writes,
+                result_sender,
+            })
+            .await
+            .expect("Failed sending write encodings request");
+
+        // If fails - it is either full or disconnected, we don't care either way, so ignore result
+        let _ = self.any_requests_sender.clone().try_send(());
+
+        let result = result_receiver
+            .await
+            .expect("Write encodings result sender was dropped");
+        self.write_latency.record(started.elapsed());
+
+        result
+    }
+
+    pub async fn plot_pieces(&self, node_id: NodeID, piece_bundles: Vec<PieceBundle>) {
+        let sloth = ENCODING_SCHEME.sloth();
+
+        let writes =
+            task::spawn_blocking(move || PieceWrite::encode_batch(&sloth, node_id, piece_bundles))
+                .await;
+
+        if let Err(error) = self.write_many(writes).await {
+            warn!("{}", error);
+        }
+    }
+
+    /// Removes a piece from the plot by index, by deleting its tag and its index from the map
+    pub async fn remove(&self, index: u64) -> io::Result<()> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.pending_requests.fetch_add(1, Ordering::SeqCst);
+        self.write_requests_sender
+            .clone()
+            .send(WriteRequests::RemoveEncoding {
                 index,
                 result_sender,
-                merkle_proof,
             })
             .await
-            .expect("Failed sending write encoding request");
+            .expect("Failed sending remove encoding request");
 
         // If fails - it is either full or disconnected, we don't care either way, so ignore result
         let _ = self.any_requests_sender.clone().try_send(());
 
         result_receiver
             .await
-            .expect("Write encoding result sender was dropped")
+            .expect("Remove encoding result sender was dropped")
     }
 
-    pub async fn plot_pieces(&self, node_id: NodeID, piece_bundles: Vec<PieceBundle>) {
-        let expanded_iv = crypto::expand_iv(node_id);
-        let integer_expanded_iv = Arc::new(Integer::from_digits(&expanded_iv, Order::Lsf));
-        let sloth = Arc::new(sloth::Sloth::init(PRIME_SIZE_BITS));
+    /// Checks whether a piece with this index is in the plot
+    pub async fn contains(&self, index: u64) -> bool {
+        let (result_sender, result_receiver) = oneshot::channel();
 
-        for piece_bundle in piece_bundles.into_iter() {
-            let mut piece = piece_bundle.piece;
+        self.pending_requests.fetch_add(1, Ordering::SeqCst);
+        self.read_requests_sender
+            .clone()
+            .send(ReadRequests::Contains {
+                index,
+                result_sender,
+            })
+            .await
+            .expect("Failed sending contains request");
+
+        // If fails - it is either full or disconnected, we don't care either way, so ignore result
+        let _ = self.any_requests_sender.clone().try_send(());
+
+        result_receiver
+            .await
+            .expect("Contains result sender was dropped")
+    }
 
-            // TODO: encode pieces first, then add to plot
+    /// Shrinks the plot to `piece_count` pieces by evicting every piece with a higher index, then
+    /// compacts the plot file if any were evicted, returns the number of evicted pieces
+    ///
+    /// The plot grows by simply writing pieces with new indexes.
+    pub async fn resize(&self, piece_count: u64) -> io::Result<u64> {
+        let (result_sender, result_receiver) = oneshot::channel();
 
-            let piece = task::spawn_blocking({
+        self.pending_requests.fetch_add(1, Ordering::SeqCst);
+        self.write_requests_sender
+            .clone()
+            .send(WriteRequests::Resize {
+                piece_count,
+                result_sender,
+            })
+            .await
+            .expect("Failed sending resize request");
+
+        // If fails - it is either full or disconnected, we don't care either way, so ignore result
+        let _ = self.any_requests_sender.clone().try_send(());
+
+        result_receiver
+            .await
+            .expect("Resize result sender was dropped")
+    }
+
+    /// Checks that the plot file, map and tags agree, optionally removing corrupted pieces so that
+    /// they can be plotted again
+    ///
+    /// Pieces are read and decoded alongside other requests, only the repair waits for writes.
+    pub async fn verify(&self, options: VerifyOptions) -> io::Result<VerifyReport> {
+        let salt = self.salt();
+        let mut indexes = task::spawn_blocking({
+            let map_db = Arc::clone(&self.map_db);
+            move || list_indexes(&map_db)
+        })
+        .await;
+
+        if let VerifyMode::Sample(count) = options.mode {
+            indexes = indexes
+                .choose_multiple(&mut rand::thread_rng(), count)
+                .copied()
+                .collect();
+        }
+
+        let node_id = self.header.node_id;
+        let sloth = Arc::new(ENCODING_SCHEME.sloth());
+        let merkle_roots = Arc::new(options.merkle_roots);
+        let mut report = VerifyReport::default();
+        let mut checked = HashMap::new();
+        let mut checked_tags = HashMap::new();
+        let mut missing_tags = Vec::new();
+
+        for index in indexes {
+            // Held until the piece is read, such that its slot can't be reused in the meantime
+            let reads = self.reader.read().await;
+            let entry = task::spawn_blocking({
+                let map_db = Arc::clone(&self.map_db);
+                let reader = Arc::clone(&reads);
+                move || verify::read_entry(&map_db, &reader, index)
+            })
+            .await?;
+            drop(reads);
+
+            let (value, encoding) = match entry {
+                verify::Entry::Removed => {
+                    continue;
+                }
+                verify::Entry::Unreadable(value) => {
+                    report.checked += 1;
+                    report.unreadable.push(index);
+                    checked.insert(index, value);
+                    continue;
+                }
+                verify::Entry::Readable(value, encoding) => (value, encoding),
+            };
+            report.checked += 1;
+
+            let tag = crypto::create_tag(&encoding, salt);
+            let (value, status, has_tag) = task::spawn_blocking({
                 let sloth = Arc::clone(&sloth);
-                let integer_expanded_iv = Arc::clone(&integer_expanded_iv);
+                let merkle_roots = Arc::clone(&merkle_roots);
+                let tags_db = Arc::clone(&self.tags_db);
                 move || {
-                    sloth
-                        .encode(&mut piece, &integer_expanded_iv, ENCODING_LAYERS_TEST)
-                        .unwrap();
-                    piece
+                    let status = verify::check_piece(
+                        &*sloth,
+                        node_id,
+                        index,
+                        encoding,
+                        &value[8..],
+                        &merkle_roots,
+                    );
+                    let has_tag = verify::has_tag(&tags_db, &tag, index);
+                    (value, status, has_tag)
                 }
             })
             .await;
+            checked.insert(index, value);
 
-            // TODO: Replace challenge here and in other places
-            let nonce = u64::from_le_bytes(
-                crypto::create_hmac(&piece, b"subspace")[0..8]
-                    .try_into()
-                    .unwrap(),
-            );
-
-            let result = self
-                .write(
-                    piece,
-                    nonce,
-                    piece_bundle.piece_index,
-                    piece_bundle.piece_proof,
-                )
-                .await;
+            match status {
+                verify::PieceStatus::Invalid => {
+                    report.invalid.push(index);
+                    continue;
+                }
+                verify::PieceStatus::Unverified => {
+                    report.unverified += 1;
+                }
+                verify::PieceStatus::Valid => {}
+            }
 
-            if let Err(error) = result {
-                warn!("{}", error);
+            checked_tags.insert(index, tag);
+            if !has_tag {
+                report.missing_tags.push(index);
+                missing_tags.push((tag, index));
             }
         }
+
+        let stale_tags = if options.mode == VerifyMode::Full {
+            task::spawn_blocking({
+                let map_db = Arc::clone(&self.map_db);
+                let tags_db = Arc::clone(&self.tags_db);
+                move || verify::find_stale_tags(&tags_db, &map_db, &checked_tags)
+            })
+            .await
+        } else {
+            Vec::new()
+        };
+        report.stale_tags = stale_tags.len();
+
+        if options.repair && !report.is_ok() {
+            let (result_sender, result_receiver) = oneshot::channel();
+
+            self.pending_requests.fetch_add(1, Ordering::SeqCst);
+            self.write_requests_sender
+                .clone()
+                .send(WriteRequests::Repair {
+                    repair: verify::Repair {
+                        salt,
+                        checked,
+                        corrupted: report.corrupted(),
+                        stale_tags,
+                        missing_tags,
+                    },
+                    result_sender,
+                })
+                .await
+                .expect("Failed sending repair request");
+
+            // If fails - it is either full or disconnected, we don't care either way, so ignore result
+            let _ = self.any_requests_sender.clone().try_send(());
+
+            result_receiver
+                .await
+                .expect("Repair result sender was dropped")?;
+            report.repaired = true;
+        }
+
+        Ok(report)
     }
 
-    /// Removes a piece from the plot by index, by deleting its index from the map
-    pub async fn remove(&self, index: u64) -> io::Result<()> {
+    /// Rewrites the plot file without space left by removed pieces, returns reclaimed bytes
+    pub async fn compact(&self) -> io::Result<u64> {
         let (result_sender, result_receiver) = oneshot::channel();
 
+        self.pending_requests.fetch_add(1, Ordering::SeqCst);
         self.write_requests_sender
             .clone()
-            .send(WriteRequests::RemoveEncoding {
-                index,
+            .send(WriteRequests::Compact { result_sender })
+            .await
+            .expect("Failed sending compact request");
+
+        // If fails - it is either full or disconnected, we don't care either way, so ignore result
+        let _ = self.any_requests_sender.clone().try_send(());
+
+        result_receiver
+            .await
+            .expect("Compact result sender was dropped")
+    }
+
+    /// Replaces tags of all pieces with tags committed to `salt`, does nothing if they already are
+    ///
+    /// New tags are computed alongside other requests, pieces keep being found by tags committed to
+    /// the previous salt until the new tags replace them at once.
+    pub async fn recommit(&self, salt: Salt) -> io::Result<()> {
+        if salt == self.salt() {
+            return Ok(());
+        }
+
+        let indexes = task::spawn_blocking({
+            let map_db = Arc::clone(&self.map_db);
+            move || list_indexes(&map_db)
+        })
+        .await;
+
+        let mut tags = HashMap::with_capacity(indexes.len());
+        for indexes in indexes.chunks(PLOT_WRITE_BATCH_SIZE) {
+            // Held while pieces are read, such that their slots can't be reused in the meantime
+            let reads = self.reader.read().await;
+            let computed_tags = task::spawn_blocking({
+                let map_db = Arc::clone(&self.map_db);
+                let reader = Arc::clone(&reads);
+                let indexes = indexes.to_vec();
+                move || commitment::compute_tags(&map_db, &reader, &indexes, salt)
+            })
+            .await
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+            drop(reads);
+
+            tags.extend(
+                computed_tags
+                    .into_iter()
+                    .map(|(index, value, tag)| (index, (value, tag))),
+            );
+        }
+
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.pending_requests.fetch_add(1, Ordering::SeqCst);
+        self.write_requests_sender
+            .clone()
+            .send(WriteRequests::Recommit {
+                recommitment: commitment::Recommitment { salt, tags },
                 result_sender,
             })
             .await
-            .expect("Failed sending remove encoding request");
+            .expect("Failed sending recommit request");
 
         // If fails - it is either full or disconnected, we don't care either way, so ignore result
         let _ = self.any_requests_sender.clone().try_send(());
 
         result_receiver
             .await
-            .expect("Remove encoding result sender was dropped")
+            .expect("Recommit result sender was dropped")
+    }
+
+    /// Switches reads to direct I/O that bypasses the page cache (Linux only), which keeps large
+    /// plots from evicting everything else from memory
+    pub async fn set_direct_io(&self, direct_io: bool) -> io::Result<()> {
+        let mut reader = self.reader.write().await;
+        *reader = Arc::new(
+            task::spawn_blocking({
+                let plot_file_path = self.plot_file_path.clone();
+                move || PlotReader::open(&plot_file_path, direct_io)
+            })
+            .await?,
+        );
+
+        Ok(())
+    }
+
+    /// Piece count, disk usage, latencies of reads and writes and number of requests waiting to
+    /// be processed
+    ///
+    /// Sizes are as of the last processed write, so this never waits behind queued requests.
+    pub fn stats(&self) -> PlotStats {
+        PlotStats {
+            reads: self.read_latency.stats(),
+            writes: self.write_latency.stats(),
+            pending_requests: self.pending_requests.load(Ordering::SeqCst),
+            ..self.sizes.stats(HEADER_SIZE as u64, PIECE_SIZE as u64)
+        }
+    }
+
+    /// Salt tags of the plot are committed to
+    pub fn salt(&self) -> Salt {
+        self.inner.salt.load(Ordering::SeqCst).to_le_bytes()
+    }
+
+    /// Parameters the plot was created with
+    pub fn header(&self) -> &PlotHeader {
+        &self.inner.header
     }
 
     pub fn on_close<F: FnOnce() + Send + 'static>(&self, callback: F) -> HandlerId {
@@ -561,43 +1220,257 @@ impl Deref for Plot {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::crypto;
-    use async_std::path::PathBuf;
-    use rand::prelude::*;
-    use std::fs;
-    use std::time::Duration;
+/// Stores sizes of the plot for `Plot::stats`, sizes that can't be read are left as they were
+async fn store_sizes(sizes: &SizeCounters, plot_file: &File, free_slots: usize, tags_db: &Arc<DB>) {
+    let plot_file_size = match plot_file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(error) => {
+            warn!("Failed to read plot file size: {}", error);
+            return;
+        }
+    };
+    let tags_db_size = task::spawn_blocking({
+        let tags_db = Arc::clone(tags_db);
+        move || tags_db.property_int_value("rocksdb.total-sst-files-size")
+    })
+    .await
+    .unwrap_or_else(|error| {
+        warn!("Failed to read tags database size: {}", error);
+        None
+    });
+
+    sizes.store(
+        plot_file_size,
+        free_slots as u64,
+        tags_db_size.unwrap_or_default(),
+    );
+}
+
+async fn open_plot_file(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .await
+}
+
+/// Opens the plot file again with the same options, for when it was replaced
+async fn reopen_reader(
+    plot_file_path: &std::path::Path,
+    reader: &PlotReader,
+) -> io::Result<Arc<PlotReader>> {
+    let plot_file_path = plot_file_path.to_path_buf();
+    let direct_io = reader.direct_io();
+    let reader = task::spawn_blocking(move || PlotReader::open(&plot_file_path, direct_io)).await?;
+
+    Ok(Arc::new(reader))
+}
+
+/// Writes `expected` header into an empty plot file, otherwise reads the header and checks that the
+/// plot was created for the same node id and with the same encoding parameters
+async fn read_or_write_header(
+    plot_file: &mut File,
+    expected: PlotHeader,
+) -> Result<PlotHeader, PlotHeaderError> {
+    let plot_file_size = plot_file
+        .metadata()
+        .await
+        .map_err(PlotHeaderError::Io)?
+        .len();
+
+    if plot_file_size == 0 {
+        let result: io::Result<()> = try {
+            plot_file.write_all(&expected.to_bytes()).await?;
+            plot_file.sync_data().await?;
+        };
+        result.map_err(PlotHeaderError::Io)?;
 
-    struct TargetDirectory {
-        path: PathBuf,
+        return Ok(expected);
     }
 
-    impl Drop for TargetDirectory {
-        fn drop(&mut self) {
-            drop(fs::remove_dir_all(&self.path));
+    let header = read_existing_header(plot_file, plot_file_size).await?;
+    header.check_compatible(&expected)?;
+
+    Ok(header)
+}
+
+/// Reads the header of a plot file of `plot_file_size` bytes that isn't empty
+async fn read_existing_header(
+    plot_file: &mut File,
+    plot_file_size: u64,
+) -> Result<PlotHeader, PlotHeaderError> {
+    if plot_file_size < HEADER_SIZE as u64 {
+        return Err(PlotHeaderError::MissingHeader);
+    }
+
+    let mut bytes = [0u8; HEADER_SIZE];
+    let result: io::Result<()> = try {
+        plot_file.seek(SeekFrom::Start(0)).await?;
+        plot_file.read_exact(&mut bytes).await?;
+    };
+    result.map_err(PlotHeaderError::Io)?;
+
+    PlotHeader::from_bytes(&bytes)
+}
+
+/// Positions of pieces that are about to be overwritten along with their tags, if they can still
+/// be read
+fn find_replaced(
+    map_db: &DB,
+    plot_reader: &PlotReader,
+    indexes: Vec<u64>,
+    salt: Salt,
+) -> Result<Vec<(u64, Option<(Tag, u64)>)>, rocksdb::Error> {
+    let mut replaced = Vec::new();
+    for index in indexes {
+        if let Some(value) = map_db.get(index.to_le_bytes())? {
+            let position = position_of(&value);
+            // Tag of a piece that can't be read is left for verify to remove
+            let old_tag = plot_reader
+                .read(position)
+                .ok()
+                .map(|encoding| (crypto::create_tag(&encoding, salt), index));
+            replaced.push((position, old_tag));
         }
     }
 
-    impl Deref for TargetDirectory {
-        type Target = PathBuf;
+    Ok(replaced)
+}
 
-        fn deref(&self) -> &Self::Target {
-            &self.path
+/// Commits written pieces, map first, such that a piece can be found by its tag only once it is
+/// in the map
+///
+/// Tags of replaced pieces are deleted in the same batch as new tags are added.
+fn commit_writes(
+    map_db: &DB,
+    tags_db: &DB,
+    writes: Vec<PieceWrite>,
+    positions: Vec<u64>,
+    old_tags: Vec<(Tag, u64)>,
+    salt: Salt,
+) -> Result<(), rocksdb::Error> {
+    let mut map_batch = WriteBatch::default();
+    let mut tags_batch = WriteBatch::default();
+    for (old_tag, index) in old_tags {
+        // the same tag may have been taken by another piece since
+        if matches!(tags_db.get(old_tag)?, Some(tag_index) if tag_index[..] == index.to_le_bytes())
+        {
+            tags_batch.delete(old_tag)?;
         }
     }
+    for (write, position) in writes.into_iter().zip(positions) {
+        let value = [&position.to_le_bytes()[..], &write.merkle_proof[..]].concat();
+        map_batch.put(write.index.to_le_bytes(), value)?;
 
-    impl TargetDirectory {
-        fn new(test_name: &str) -> Self {
-            let path = PathBuf::from("target").join(test_name);
+        let tag = crypto::create_tag(&write.encoding, salt);
+        tags_batch.put(tag, write.index.to_le_bytes())?;
+    }
 
-            fs::create_dir_all(&path).unwrap();
+    let mut write_options = WriteOptions::default();
+    write_options.set_sync(true);
+    map_db.write_opt(map_batch, &write_options)?;
+    tags_db.write_opt(tags_batch, &write_options)
+}
 
-            Self { path }
+/// Deletes a removed piece from the tags and map, tags first like when evicting pieces
+fn remove_piece(
+    map_db: &DB,
+    tags_db: &DB,
+    index: u64,
+    replaced: &[(u64, Option<(Tag, u64)>)],
+) -> Result<(), rocksdb::Error> {
+    let mut write_options = WriteOptions::default();
+    write_options.set_sync(true);
+
+    for (old_tag, _) in replaced.iter().filter_map(|(_, old_tag)| *old_tag) {
+        // the same tag may have been taken by another piece since
+        if matches!(tags_db.get(old_tag)?, Some(tag_index) if tag_index[..] == index.to_le_bytes())
+        {
+            tags_db.delete_opt(old_tag, &write_options)?;
         }
     }
 
+    map_db.delete_opt(index.to_le_bytes(), &write_options)
+}
+
+/// Indexes of all pieces in the map
+fn list_indexes(map_db: &DB) -> Vec<u64> {
+    map_db
+        .iterator(IteratorMode::Start)
+        .map(|(key, _)| u64::from_le_bytes(key[..].try_into().unwrap()))
+        .collect()
+}
+
+/// Positions between the header and the end of the plot file that no map entry points to
+fn find_free_slots(map_db: &DB, plot_file_size: u64) -> BTreeSet<u64> {
+    let mut free_slots: BTreeSet<u64> = (HEADER_SIZE as u64..plot_file_size)
+        .step_by(PIECE_SIZE)
+        .collect();
+    for (_, value) in map_db.iterator(IteratorMode::Start) {
+        free_slots.remove(&position_of(&value));
+    }
+
+    free_slots
+}
+
+fn position_of(value: &[u8]) -> u64 {
+    u64::from_le_bytes(value[0..8].try_into().unwrap())
+}
+
+/// Deletes pieces with index at or above `piece_count` from the tags and map, returns how many
+/// pieces were evicted and how many are left
+///
+/// Tags are deleted first, such that a crash in between leaves map entries without tags, which
+/// recovery tags again, and never tags of pieces that are no longer in the map.
+fn evict_pieces(
+    map_db: &DB,
+    tags_db: &DB,
+    piece_count: u64,
+) -> Result<(u64, usize), rocksdb::Error> {
+    let mut evicted = Vec::new();
+    let mut remaining = 0;
+    for (key, _) in map_db.iterator(IteratorMode::Start) {
+        if u64::from_le_bytes(key[..].try_into().unwrap()) >= piece_count {
+            evicted.push(key);
+        } else {
+            remaining += 1;
+        }
+    }
+    if evicted.is_empty() {
+        return Ok((0, remaining));
+    }
+
+    let mut write_options = WriteOptions::default();
+    write_options.set_sync(true);
+
+    let mut batch = WriteBatch::default();
+    for (tag, index) in tags_db.iterator(IteratorMode::Start) {
+        if u64::from_le_bytes(index[..].try_into().unwrap()) >= piece_count {
+            batch.delete(tag)?;
+        }
+    }
+    tags_db.write_opt(batch, &write_options)?;
+
+    let mut batch = WriteBatch::default();
+    for key in evicted.iter() {
+        batch.delete(key)?;
+    }
+    map_db.write_opt(batch, &write_options)?;
+
+    Ok((evicted.len() as u64, remaining))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use crate::test_utils::TargetDirectory;
+    use crate::{state, GENESIS_PIECE_COUNT, GENESIS_STATE_BLOCKS, GENESIS_STATE_SEED};
+    use async_std::sync::channel;
+    use std::fs;
+    use std::time::Duration;
+
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
     }
@@ -608,13 +1481,14 @@ mod tests {
         let path = TargetDirectory::new("read_write");
 
         let piece = crypto::generate_random_piece();
-        let nonce = rand::thread_rng().gen::<u64>();
         let index = 0;
         let merkle_proof = vec![0u8; 256];
 
-        let plot = Plot::open_or_create(&path).await.unwrap();
+        assert!(Plot::read_header(&path).await.unwrap().is_none());
+
+        let plot = Plot::open_or_create(&path, [1u8; 32], 2).await.unwrap();
         assert_eq!(true, plot.is_empty().await);
-        plot.write(piece, nonce, index, merkle_proof.clone())
+        plot.write(piece, index, merkle_proof.clone())
             .await
             .unwrap();
         assert_eq!(false, plot.is_empty().await);
@@ -623,15 +1497,33 @@ mod tests {
         assert_eq!(piece[..], extracted_piece[..]);
         assert_eq!(merkle_proof, extracted_merkle_proof);
 
+        let stats = plot.stats();
+        assert_eq!(1, stats.piece_count);
+        assert_eq!((HEADER_SIZE + PIECE_SIZE) as u64, stats.plot_file_size);
+        assert_eq!(1, stats.reads.count);
+        assert_eq!(1, stats.writes.count);
+        assert_eq!(0, stats.pending_requests);
+
         drop(plot);
 
         async_std::task::sleep(Duration::from_millis(100)).await;
 
+        // Key index is read back before opening, whatever the plot position in the config
+        let header = Plot::read_header(&path).await.unwrap().unwrap();
+        assert_eq!(2, header.key_index);
+
         // Make sure it is still not empty on reopen
-        let plot = Plot::open_or_create(&path).await.unwrap();
+        let plot = Plot::open_or_create(&path, [1u8; 32], 2).await.unwrap();
         assert_eq!(false, plot.is_empty().await);
+        assert_eq!(PLOT_FORMAT_VERSION, plot.header().version);
         drop(plot);
 
+        // Plot encoded for another node id must not be used
+        assert!(matches!(
+            Plot::open_or_create(&path, [2u8; 32], 0).await,
+            Err(PlotCreationError::Header(PlotHeaderError::NodeIdMismatch))
+        ));
+
         // Let plot to destroy gracefully, otherwise may get "pure virtual method called
         // terminate called without an active exception" message
         async_std::task::sleep(Duration::from_millis(100)).await;
@@ -642,12 +1534,11 @@ mod tests {
         init();
         let path = TargetDirectory::new("find_by_tag");
 
-        let plot = Plot::open_or_create(&path).await.unwrap();
+        let plot = Plot::open_or_create(&path, [1u8; 32], 0).await.unwrap();
         for index in 0..1024 {
             let piece = crypto::generate_random_piece();
             let merkle_proof = vec![0u8; 256];
-            let nonce = rand::thread_rng().gen::<u64>();
-            plot.write(piece, nonce, index, merkle_proof).await.unwrap();
+            plot.write(piece, index, merkle_proof).await.unwrap();
         }
 
         {
@@ -721,4 +1612,361 @@ mod tests {
         // terminate called without an active exception" message
         async_std::task::sleep(Duration::from_millis(100)).await;
     }
+
+    #[async_std::test]
+    async fn test_resize() {
+        init();
+        let path = TargetDirectory::new("resize");
+
+        let plot = Plot::open_or_create(&path, [1u8; 32], 0).await.unwrap();
+        let mut pieces = Vec::new();
+        for index in 0..16 {
+            let piece = crypto::generate_random_piece();
+            plot.write(piece, index, vec![index as u8; 256])
+                .await
+                .unwrap();
+            pieces.push(piece);
+        }
+        // Overwritten piece frees its old slot
+        plot.write(pieces[3], 3, vec![3u8; 256]).await.unwrap();
+
+        assert_eq!(8, plot.resize(8).await.unwrap());
+
+        for index in 0..8 {
+            assert!(plot.contains(index).await);
+            let (piece, merkle_proof) = plot.read(index).await.unwrap();
+            assert_eq!(pieces[index as usize][..], piece[..]);
+            assert_eq!(vec![index as u8; 256], merkle_proof);
+        }
+        for index in 8..16 {
+            assert!(!plot.contains(index).await);
+            assert_eq!(
+                io::ErrorKind::NotFound,
+                plot.read(index).await.unwrap_err().kind()
+            );
+        }
+        let solutions = plot.find_by_range([0u8; 8], u64::MAX).await.unwrap();
+        assert!(solutions.iter().all(|(_, index)| *index < 8));
+        assert_eq!(
+            (HEADER_SIZE + 8 * PIECE_SIZE) as u64,
+            fs::metadata(path.join("plot.bin")).unwrap().len()
+        );
+
+        // Growing is just writing pieces with new indexes
+        assert_eq!(0, plot.resize(16).await.unwrap());
+        plot.write(pieces[8], 8, vec![8u8; 256]).await.unwrap();
+        assert_eq!(pieces[8][..], plot.read(8).await.unwrap().0[..]);
+
+        drop(plot);
+
+        // Let plot to destroy gracefully, otherwise may get "pure virtual method called
+        // terminate called without an active exception" message
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+
+    #[async_std::test]
+    async fn test_verify_and_repair() {
+        init();
+        let path = TargetDirectory::new("verify");
+        let node_id = [1u8; 32];
+
+        let (state_sender, _state_receiver) = channel(GENESIS_STATE_BLOCKS);
+        let piece_bundles = state::State::new(state_sender)
+            .create_genesis_state(GENESIS_STATE_SEED, GENESIS_PIECE_COUNT)
+            .await;
+        let options = VerifyOptions {
+            mode: VerifyMode::Full,
+            repair: false,
+            merkle_roots: state::genesis_merkle_roots().await,
+        };
+
+        let plot = Plot::open_or_create(&path, node_id, 0).await.unwrap();
+        plot.plot_pieces(node_id, piece_bundles[..8].to_vec()).await;
+
+        let report = plot.verify(options.clone()).await.unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(8, report.checked);
+
+        // Replace piece with garbage that doesn't match its merkle proof
+        plot.write(
+            crypto::generate_random_piece(),
+            3,
+            piece_bundles[3].piece_proof.clone(),
+        )
+        .await
+        .unwrap();
+
+        let report = plot.verify(options.clone()).await.unwrap();
+        assert_eq!(vec![3], report.invalid);
+        assert!(report.stale_tags > 0);
+        assert!(!report.repaired);
+        assert!(plot.contains(3).await);
+
+        let report = plot
+            .verify(VerifyOptions {
+                repair: true,
+                ..options.clone()
+            })
+            .await
+            .unwrap();
+        assert!(report.repaired);
+        assert!(!plot.contains(3).await);
+
+        let report = plot.verify(options).await.unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(7, report.checked);
+
+        drop(plot);
+
+        // Let plot to destroy gracefully, otherwise may get "pure virtual method called
+        // terminate called without an active exception" message
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+
+    #[async_std::test]
+    async fn test_free_slots_and_compaction() {
+        init();
+        let path = TargetDirectory::new("free_slots_and_compaction");
+        let plot_file_size = || fs::metadata(path.join("plot.bin")).unwrap().len();
+
+        let plot = Plot::open_or_create(&path, [1u8; 32], 0).await.unwrap();
+        let mut pieces = Vec::new();
+        for index in 0..4 {
+            let piece = crypto::generate_random_piece();
+            plot.write(piece, index, vec![0u8; 256]).await.unwrap();
+            pieces.push(piece);
+        }
+        assert_eq!((HEADER_SIZE + 4 * PIECE_SIZE) as u64, plot_file_size());
+
+        // Removed piece can't be found by its tag and frees a slot that the next write reuses
+        plot.remove(1).await.unwrap();
+        let tag = crypto::create_tag(&pieces[1], plot.salt());
+        assert!(plot.find_by_range(tag, 0).await.unwrap().is_empty());
+        let piece = crypto::generate_random_piece();
+        plot.write(piece, 4, vec![0u8; 256]).await.unwrap();
+        assert_eq!((HEADER_SIZE + 4 * PIECE_SIZE) as u64, plot_file_size());
+        assert_eq!(piece[..], plot.read(4).await.unwrap().0[..]);
+
+        plot.remove(0).await.unwrap();
+        plot.remove(2).await.unwrap();
+        assert_eq!(2 * PIECE_SIZE as u64, plot.compact().await.unwrap());
+        assert_eq!((HEADER_SIZE + 2 * PIECE_SIZE) as u64, plot_file_size());
+        assert_eq!(piece[..], plot.read(4).await.unwrap().0[..]);
+        assert!(plot.read(3).await.is_ok());
+
+        drop(plot);
+
+        // Let plot to destroy gracefully, otherwise may get "pure virtual method called
+        // terminate called without an active exception" message
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+
+    #[async_std::test]
+    async fn test_recovery_after_crash() {
+        init();
+        let path = TargetDirectory::new("recovery_after_crash");
+
+        let plot = Plot::open_or_create(&path, [1u8; 32], 0).await.unwrap();
+        let writes = (0..4)
+            .map(|index| PieceWrite {
+                encoding: crypto::generate_random_piece(),
+                index,
+                merkle_proof: vec![0u8; 256],
+            })
+            .collect();
+        plot.write_many(writes).await.unwrap();
+        let (encoding, _) = plot.read(0).await.unwrap();
+        let (replaced_encoding, _) = plot.read(1).await.unwrap();
+        drop(plot);
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert!(!path.join(recovery::DIRTY_MARKER_FILE).exists().await);
+
+        // Leave behind what a crash in the middle of writes could
+        let new_encoding = crypto::generate_random_piece();
+        {
+            let map_db = DB::open_default(path.join("plot-map")).unwrap();
+            let tags_db = DB::open_default(path.join("plot-tags")).unwrap();
+            map_db
+                .put(
+                    7u64.to_le_bytes(),
+                    [&u64::MAX.to_le_bytes()[..], &[0u8; 256]].concat(),
+                )
+                .unwrap();
+            tags_db.put([0u8; 8], 99u64.to_le_bytes()).unwrap();
+            tags_db
+                .delete(crypto::create_tag(&encoding, crypto::genesis_salt()))
+                .unwrap();
+
+            // Overwrite of a piece that crashed between the map and tags batches
+            let mut plot_file = fs::OpenOptions::new()
+                .append(true)
+                .open(path.join("plot.bin"))
+                .unwrap();
+            std::io::Write::write_all(&mut plot_file, &new_encoding).unwrap();
+            plot_file.sync_all().unwrap();
+            map_db
+                .put(
+                    1u64.to_le_bytes(),
+                    [
+                        &((HEADER_SIZE + 4 * PIECE_SIZE) as u64).to_le_bytes()[..],
+                        &[0u8; 256],
+                    ]
+                    .concat(),
+                )
+                .unwrap();
+        }
+        fs::File::create(path.join(recovery::DIRTY_MARKER_FILE)).unwrap();
+
+        let plot = Plot::open_or_create(&path, [1u8; 32], 0).await.unwrap();
+        assert!(!plot.contains(7).await);
+        let report = plot
+            .verify(VerifyOptions {
+                mode: VerifyMode::Full,
+                repair: false,
+                merkle_roots: Default::default(),
+            })
+            .await
+            .unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(4, report.checked);
+
+        assert_eq!(new_encoding[..], plot.read(1).await.unwrap().0[..]);
+        let tag = crypto::create_tag(&new_encoding, crypto::genesis_salt());
+        assert_eq!(vec![(tag, 1)], plot.find_by_range(tag, 0).await.unwrap());
+        let stale_tag = crypto::create_tag(&replaced_encoding, crypto::genesis_salt());
+        assert!(plot.find_by_range(stale_tag, 0).await.unwrap().is_empty());
+
+        drop(plot);
+
+        // Let plot to destroy gracefully, otherwise may get "pure virtual method called
+        // terminate called without an active exception" message
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+
+    #[async_std::test]
+    async fn test_recommit() {
+        init();
+        let path = TargetDirectory::new("recommit");
+
+        let plot = Plot::open_or_create(&path, [1u8; 32], 0).await.unwrap();
+        assert_eq!(crypto::genesis_salt(), plot.salt());
+        let piece = crypto::generate_random_piece();
+        plot.write(piece, 0, vec![0u8; 256]).await.unwrap();
+
+        let salt = crypto::derive_salt(1, &[1u8; 32]);
+        assert_ne!(plot.salt(), salt);
+        plot.recommit(salt).await.unwrap();
+        assert_eq!(salt, plot.salt());
+
+        let tag = crypto::create_tag(&piece, salt);
+        assert_eq!(vec![(tag, 0)], plot.find_by_range(tag, 0).await.unwrap());
+        let old_tag = crypto::create_tag(&piece, crypto::genesis_salt());
+        assert!(plot.find_by_range(old_tag, 0).await.unwrap().is_empty());
+
+        drop(plot);
+        async_std::task::sleep(Duration::from_millis(100)).await;
+
+        // Salt survives reopening
+        let plot = Plot::open_or_create(&path, [1u8; 32], 0).await.unwrap();
+        assert_eq!(salt, plot.salt());
+        assert_eq!(vec![(tag, 0)], plot.find_by_range(tag, 0).await.unwrap());
+
+        drop(plot);
+
+        // Let plot to destroy gracefully, otherwise may get "pure virtual method called
+        // terminate called without an active exception" message
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+
+    #[test]
+    fn test_evict_pieces_deletes_tags_first() {
+        init();
+        let path = TargetDirectory::new("evict_pieces_deletes_tags_first");
+
+        let salt = crypto::genesis_salt();
+        let mut tags = Vec::new();
+        {
+            let map_db = DB::open_default(path.join("plot-map")).unwrap();
+            let tags_db = DB::open_default(path.join("plot-tags")).unwrap();
+            for index in 0..4u64 {
+                let tag = crypto::create_tag(&crypto::generate_random_piece(), salt);
+                let position = HEADER_SIZE as u64 + index * PIECE_SIZE as u64;
+                map_db
+                    .put(
+                        index.to_le_bytes(),
+                        [&position.to_le_bytes()[..], &[0u8; 256]].concat(),
+                    )
+                    .unwrap();
+                tags_db.put(tag, index.to_le_bytes()).unwrap();
+                tags.push(tag);
+            }
+        }
+
+        // Failing to delete tags must leave the map untouched
+        {
+            let map_db = DB::open_default(path.join("plot-map")).unwrap();
+            let tags_db =
+                DB::open_for_read_only(&rocksdb::Options::default(), path.join("plot-tags"), false)
+                    .unwrap();
+            assert!(evict_pieces(&map_db, &tags_db, 2).is_err());
+            assert_eq!(vec![0, 1, 2, 3], list_indexes(&map_db));
+        }
+
+        let map_db = DB::open_default(path.join("plot-map")).unwrap();
+        let tags_db = DB::open_default(path.join("plot-tags")).unwrap();
+        assert_eq!((2, 2), evict_pieces(&map_db, &tags_db, 2).unwrap());
+        assert_eq!(vec![0, 1], list_indexes(&map_db));
+        for (index, tag) in tags.iter().enumerate() {
+            assert_eq!(index < 2, tags_db.get(tag).unwrap().is_some());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_reads() {
+        init();
+        let path = TargetDirectory::new("concurrent_reads");
+
+        let plot = Plot::open_or_create(&path, [1u8; 32], 0).await.unwrap();
+        let old_pieces: Vec<Piece> = (0..32).map(|_| crypto::generate_random_piece()).collect();
+        for (index, piece) in old_pieces.iter().enumerate() {
+            plot.write(*piece, index as u64, vec![0u8; 256])
+                .await
+                .unwrap();
+        }
+        // Free some slots, so that overwrites below reuse them
+        plot.remove(0).await.unwrap();
+        plot.remove(1).await.unwrap();
+
+        // Reads racing with overwrites see either piece, but never one from another index
+        let new_pieces: Vec<Piece> = (0..32).map(|_| crypto::generate_random_piece()).collect();
+        let writes = futures::future::join_all(
+            new_pieces
+                .iter()
+                .enumerate()
+                .skip(2)
+                .map(|(index, piece)| plot.write(*piece, index as u64, vec![1u8; 256])),
+        );
+        let reads = futures::future::join_all((2..32).map(|index| plot.read(index)));
+        let (write_results, read_results) = futures::join!(writes, reads);
+
+        for result in write_results {
+            result.unwrap();
+        }
+        for (index, result) in (2..32).zip(read_results) {
+            let (piece, _) = result.unwrap();
+            assert!(piece[..] == old_pieces[index][..] || piece[..] == new_pieces[index][..]);
+        }
+        for index in 2..32 {
+            assert_eq!(
+                new_pieces[index][..],
+                plot.read(index as u64).await.unwrap().0[..]
+            );
+        }
+
+        drop(plot);
+
+        // Let plot to destroy gracefully, otherwise may get "pure virtual method called
+        // terminate called without an active exception" message
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
 }
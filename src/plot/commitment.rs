@@ -0,0 +1,161 @@
+use super::position_of;
+use super::reader::PlotReader;
+use crate::{crypto, Piece, Salt, Tag, PIECE_SIZE};
+use rocksdb::{IteratorMode, WriteBatch, WriteOptions, DB};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Salt the tags of the plot are committed to
+const SALT_FILE: &str = "plot.salt";
+/// Salt tags are being recommitted to, replaces `plot.salt` once all tags are rewritten
+const NEXT_SALT_FILE: &str = "plot.salt.next";
+
+/// Tags of pieces committed to a new salt, computed before the request loop replaces tags with them
+#[derive(Debug)]
+pub(super) struct Recommitment {
+    pub(super) salt: Salt,
+    /// tags by piece index along with the map entry each was computed from, pieces whose entries
+    /// changed since are read again
+    pub(super) tags: HashMap<u64, (Vec<u8>, Tag)>,
+}
+
+/// Salt committed to by tags of the plot at `path`, `None` for plots from before tags were salted
+pub(super) fn read_salt(path: &Path) -> io::Result<Option<Salt>> {
+    if !path.join(SALT_FILE).exists() {
+        return Ok(None);
+    }
+
+    let mut salt = Salt::default();
+    File::open(path.join(SALT_FILE))?.read_exact(&mut salt)?;
+
+    Ok(Some(salt))
+}
+
+/// Returns the salt tags are committed to, finishing recommitment that was interrupted and
+/// committing tags of plots from before salts to the salt of the first eon
+pub(super) fn resume(
+    path: &Path,
+    plot_file_path: &Path,
+    map_db: &DB,
+    tags_db: &DB,
+) -> io::Result<Salt> {
+    if path.join(NEXT_SALT_FILE).exists() {
+        let mut salt = Salt::default();
+        File::open(path.join(NEXT_SALT_FILE))?.read_exact(&mut salt)?;
+        rebuild_tags(plot_file_path, map_db, tags_db, salt, &HashMap::new())?;
+        fs::rename(path.join(NEXT_SALT_FILE), path.join(SALT_FILE))?;
+
+        return Ok(salt);
+    }
+
+    if let Some(salt) = read_salt(path)? {
+        return Ok(salt);
+    }
+
+    let salt = crypto::genesis_salt();
+    if map_db.iterator(IteratorMode::Start).next().is_some() {
+        rebuild_tags(plot_file_path, map_db, tags_db, salt, &HashMap::new())?;
+    }
+    write_salt(&path.join(SALT_FILE), salt)?;
+
+    Ok(salt)
+}
+
+/// Tags of pieces at `indexes` committed to `salt` along with the map entries they were computed
+/// from, pieces that were removed or can't be read are left out
+pub(super) fn compute_tags(
+    map_db: &DB,
+    reader: &PlotReader,
+    indexes: &[u64],
+    salt: Salt,
+) -> Result<Vec<(u64, Vec<u8>, Tag)>, rocksdb::Error> {
+    let mut tags = Vec::with_capacity(indexes.len());
+    for &index in indexes {
+        if let Some(value) = map_db.get(index.to_le_bytes())? {
+            if let Ok(encoding) = reader.read(position_of(&value)) {
+                tags.push((index, value.to_vec(), crypto::create_tag(&encoding, salt)));
+            }
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Replaces all tags with tags of `recommitment`, returns the number of tags
+///
+/// The new salt is recorded before tags are rewritten, such that [`resume`] can finish
+/// recommitment that was interrupted.
+pub(super) fn recommit(
+    path: &Path,
+    plot_file_path: &Path,
+    map_db: &DB,
+    tags_db: &DB,
+    recommitment: Recommitment,
+) -> io::Result<usize> {
+    write_salt(&path.join(NEXT_SALT_FILE), recommitment.salt)?;
+    let tags = rebuild_tags(
+        plot_file_path,
+        map_db,
+        tags_db,
+        recommitment.salt,
+        &recommitment.tags,
+    )?;
+    fs::rename(path.join(NEXT_SALT_FILE), path.join(SALT_FILE))?;
+
+    Ok(tags)
+}
+
+fn write_salt(salt_file_path: &Path, salt: Salt) -> io::Result<()> {
+    let mut salt_file = File::create(salt_file_path)?;
+    salt_file.write_all(&salt)?;
+    salt_file.sync_all()
+}
+
+/// Replaces the contents of the tags database with tags of every piece in the map in a single
+/// batch, pieces without a `known_tag` for their current map entry are read
+fn rebuild_tags(
+    plot_file_path: &Path,
+    map_db: &DB,
+    tags_db: &DB,
+    salt: Salt,
+    known_tags: &HashMap<u64, (Vec<u8>, Tag)>,
+) -> io::Result<usize> {
+    let mut plot_file = File::open(plot_file_path)?;
+
+    let mut batch = WriteBatch::default();
+    for (tag, _) in tags_db.iterator(IteratorMode::Start) {
+        batch.delete(tag).map_err(to_io_error)?;
+    }
+
+    let mut tags = 0;
+    let mut encoding: Piece = [0u8; PIECE_SIZE];
+    for (key, value) in map_db.iterator(IteratorMode::Start) {
+        let index = u64::from_le_bytes(key[..].try_into().unwrap());
+        let tag = match known_tags.get(&index) {
+            Some((known_value, tag)) if known_value[..] == value[..] => *tag,
+            _ => {
+                plot_file.seek(SeekFrom::Start(position_of(&value)))?;
+                plot_file.read_exact(&mut encoding)?;
+                crypto::create_tag(&encoding, salt)
+            }
+        };
+        batch.put(tag, index.to_le_bytes()).map_err(to_io_error)?;
+        tags += 1;
+    }
+
+    let mut write_options = WriteOptions::default();
+    write_options.set_sync(true);
+    tags_db
+        .write_opt(batch, &write_options)
+        .map_err(to_io_error)?;
+
+    Ok(tags)
+}
+
+fn to_io_error(error: rocksdb::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
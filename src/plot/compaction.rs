@@ -0,0 +1,131 @@
+use super::{open_plot_file, position_of, HEADER_SIZE};
+use crate::PIECE_SIZE;
+use async_std::fs::{self, File, OpenOptions};
+use async_std::path::PathBuf;
+use async_std::task;
+use futures::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use rocksdb::{IteratorMode, WriteBatch, WriteOptions, DB};
+use std::convert::TryInto;
+use std::io;
+use std::io::SeekFrom;
+use std::sync::Arc;
+
+/// Copy of the plot file without dead space, replaces `plot.bin` once complete
+const COMPACTED_FILE: &str = "plot.bin.compact";
+/// Map entries pointing into the compacted file, compaction is committed once this file exists
+const JOURNAL_FILE: &str = "plot-compaction.journal";
+const JOURNAL_TMP_FILE: &str = "plot-compaction.journal.tmp";
+
+/// Rewrites the plot file with pieces back to back after the header and points map entries to
+/// their new positions, returns the reopened plot file
+///
+/// New map entries are journaled before the plot file is replaced, such that [`recover`] can
+/// finish compaction that was interrupted at any point.
+pub(super) async fn compact(
+    path: &PathBuf,
+    plot_file: &mut File,
+    map_db: &Arc<DB>,
+) -> io::Result<File> {
+    let mut map_entries = task::spawn_blocking({
+        let map_db = Arc::clone(map_db);
+        move || {
+            map_db
+                .iterator(IteratorMode::Start)
+                .map(|(key, value)| (key.to_vec(), value.to_vec()))
+                .collect::<Vec<_>>()
+        }
+    })
+    .await;
+    // Keep the order pieces had on disk
+    map_entries.sort_by_key(|(_, value)| position_of(value));
+
+    let mut compacted_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path.join(COMPACTED_FILE))
+        .await?;
+
+    let mut header = [0u8; HEADER_SIZE];
+    plot_file.seek(SeekFrom::Start(0)).await?;
+    plot_file.read_exact(&mut header).await?;
+    compacted_file.write_all(&header).await?;
+
+    let mut buffer = [0u8; PIECE_SIZE];
+    for (new_position, (_, value)) in (HEADER_SIZE as u64..)
+        .step_by(PIECE_SIZE)
+        .zip(map_entries.iter_mut())
+    {
+        plot_file.seek(SeekFrom::Start(position_of(value))).await?;
+        plot_file.read_exact(&mut buffer).await?;
+        compacted_file.write_all(&buffer).await?;
+        value[0..8].copy_from_slice(&new_position.to_le_bytes());
+    }
+    compacted_file.sync_all().await?;
+
+    let mut journal = File::create(path.join(JOURNAL_TMP_FILE)).await?;
+    for (key, value) in map_entries.iter() {
+        journal.write_all(key).await?;
+        journal
+            .write_all(&(value.len() as u32).to_le_bytes())
+            .await?;
+        journal.write_all(value).await?;
+    }
+    journal.sync_all().await?;
+    // Commit point
+    fs::rename(path.join(JOURNAL_TMP_FILE), path.join(JOURNAL_FILE)).await?;
+
+    finish(path, map_db).await?;
+
+    open_plot_file(&path.join("plot.bin")).await
+}
+
+/// Finishes compaction that was committed before the node stopped, or discards the one that
+/// wasn't
+pub(super) async fn recover(path: &PathBuf, map_db: &Arc<DB>) -> io::Result<()> {
+    if path.join(JOURNAL_FILE).exists().await {
+        return finish(path, map_db).await;
+    }
+
+    for file in &[COMPACTED_FILE, JOURNAL_TMP_FILE] {
+        if path.join(file).exists().await {
+            fs::remove_file(path.join(file)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the plot file with the compacted one and applies the journal to the map, safe to
+/// repeat if interrupted
+async fn finish(path: &PathBuf, map_db: &Arc<DB>) -> io::Result<()> {
+    if path.join(COMPACTED_FILE).exists().await {
+        fs::rename(path.join(COMPACTED_FILE), path.join("plot.bin")).await?;
+    }
+
+    let journal = fs::read(path.join(JOURNAL_FILE)).await?;
+    task::spawn_blocking({
+        let map_db = Arc::clone(map_db);
+        move || {
+            let mut batch = WriteBatch::default();
+            let mut offset = 0;
+            while offset < journal.len() {
+                let key = &journal[offset..offset + 8];
+                let value_length =
+                    u32::from_le_bytes(journal[offset + 8..offset + 12].try_into().unwrap())
+                        as usize;
+                let value = &journal[offset + 12..offset + 12 + value_length];
+                batch.put(key, value)?;
+                offset += 12 + value_length;
+            }
+
+            let mut write_options = WriteOptions::default();
+            write_options.set_sync(true);
+            map_db.write_opt(batch, &write_options)
+        }
+    })
+    .await
+    .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+    fs::remove_file(path.join(JOURNAL_FILE)).await
+}
@@ -0,0 +1,202 @@
+use crate::{crypto, NodeID, ENCODING_LAYERS, ENCODING_SCHEME, PIECE_SIZE, PRIME_SIZE_BITS};
+use std::convert::TryInto;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Space reserved for the header at the beginning of `plot.bin`, encodings follow it
+pub const HEADER_SIZE: usize = 128;
+/// Current version of the plot file format
+pub const PLOT_FORMAT_VERSION: u16 = 1;
+
+const MAGIC: &[u8; 8] = b"SUBSPLOT";
+const SOFTWARE_VERSION_SIZE: usize = 16;
+/// Bytes covered by the checksum, the checksum itself occupies the rest of the header
+const CHECKSUM_OFFSET: usize = HEADER_SIZE - 32;
+
+#[derive(Debug)]
+pub enum PlotHeaderError {
+    Io(io::Error),
+    /// Plot file has data, but doesn't start with a header (created before headers were added)
+    MissingHeader,
+    /// Header checksum doesn't match its contents
+    Corrupted,
+    UnsupportedVersion(u16),
+    NodeIdMismatch,
+    PrimeSizeMismatch {
+        plot: u32,
+        expected: u32,
+    },
+    EncodingLayersMismatch {
+        plot: u32,
+        expected: u32,
+    },
+    PieceSizeMismatch {
+        plot: u32,
+        expected: u32,
+    },
+    /// Pieces were encoded with another `sloth::EncodingScheme` version, the plot has to be re-plotted
+    EncodingSchemeMismatch {
+        plot: u16,
+        expected: u16,
+    },
+}
+
+/// Parameters a plot was created with, stored at the beginning of the plot file
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlotHeader {
+    pub version: u16,
+    /// node id the pieces are encoded for, hash of the plot public key
+    pub node_id: NodeID,
+    pub prime_size_bits: u32,
+    pub encoding_layers: u32,
+    pub piece_size: u32,
+    /// version of the `sloth::EncodingScheme`, headers written before it was recorded read as 0
+    pub encoding_scheme: u16,
+    /// index of the wallet derived key pair the plot is encoded for, see `crypto::derive_plot_keys`,
+    /// headers written before it was recorded read as 0
+    pub key_index: u32,
+    /// creation time in milliseconds since unix epoch
+    pub created_at: u64,
+    /// version of the software that created the plot
+    pub software_version: [u8; SOFTWARE_VERSION_SIZE],
+}
+
+impl PlotHeader {
+    /// Header of a plot created now with the running configuration
+    pub fn new(node_id: NodeID, key_index: u32) -> Self {
+        let mut software_version = [0u8; SOFTWARE_VERSION_SIZE];
+        let package_version = env!("CARGO_PKG_VERSION").as_bytes();
+        let length = package_version.len().min(SOFTWARE_VERSION_SIZE);
+        software_version[..length].copy_from_slice(&package_version[..length]);
+
+        PlotHeader {
+            version: PLOT_FORMAT_VERSION,
+            node_id,
+            prime_size_bits: PRIME_SIZE_BITS as u32,
+            encoding_layers: ENCODING_LAYERS as u32,
+            piece_size: PIECE_SIZE as u32,
+            encoding_scheme: ENCODING_SCHEME.version(),
+            key_index,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64,
+            software_version,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(MAGIC);
+        bytes[8..10].copy_from_slice(&self.version.to_le_bytes());
+        bytes[10..42].copy_from_slice(&self.node_id);
+        bytes[42..46].copy_from_slice(&self.prime_size_bits.to_le_bytes());
+        bytes[46..50].copy_from_slice(&self.encoding_layers.to_le_bytes());
+        bytes[50..54].copy_from_slice(&self.piece_size.to_le_bytes());
+        bytes[54..62].copy_from_slice(&self.created_at.to_le_bytes());
+        bytes[62..62 + SOFTWARE_VERSION_SIZE].copy_from_slice(&self.software_version);
+        bytes[78..80].copy_from_slice(&self.encoding_scheme.to_le_bytes());
+        bytes[80..84].copy_from_slice(&self.key_index.to_le_bytes());
+
+        let checksum = crypto::digest_sha_256(&bytes[..CHECKSUM_OFFSET]);
+        bytes[CHECKSUM_OFFSET..].copy_from_slice(&checksum);
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Result<Self, PlotHeaderError> {
+        if &bytes[0..8] != MAGIC {
+            return Err(PlotHeaderError::MissingHeader);
+        }
+
+        if crypto::digest_sha_256(&bytes[..CHECKSUM_OFFSET])[..] != bytes[CHECKSUM_OFFSET..] {
+            return Err(PlotHeaderError::Corrupted);
+        }
+
+        let version = u16::from_le_bytes(bytes[8..10].try_into().unwrap());
+        if version != PLOT_FORMAT_VERSION {
+            return Err(PlotHeaderError::UnsupportedVersion(version));
+        }
+
+        Ok(PlotHeader {
+            version,
+            node_id: bytes[10..42].try_into().unwrap(),
+            prime_size_bits: u32::from_le_bytes(bytes[42..46].try_into().unwrap()),
+            encoding_layers: u32::from_le_bytes(bytes[46..50].try_into().unwrap()),
+            piece_size: u32::from_le_bytes(bytes[50..54].try_into().unwrap()),
+            encoding_scheme: u16::from_le_bytes(bytes[78..80].try_into().unwrap()),
+            key_index: u32::from_le_bytes(bytes[80..84].try_into().unwrap()),
+            created_at: u64::from_le_bytes(bytes[54..62].try_into().unwrap()),
+            software_version: bytes[62..62 + SOFTWARE_VERSION_SIZE].try_into().unwrap(),
+        })
+    }
+
+    /// Checks that pieces in a plot with this header can be used with the `expected` parameters
+    pub fn check_compatible(&self, expected: &PlotHeader) -> Result<(), PlotHeaderError> {
+        if self.node_id != expected.node_id {
+            return Err(PlotHeaderError::NodeIdMismatch);
+        }
+        if self.prime_size_bits != expected.prime_size_bits {
+            return Err(PlotHeaderError::PrimeSizeMismatch {
+                plot: self.prime_size_bits,
+                expected: expected.prime_size_bits,
+            });
+        }
+        if self.encoding_layers != expected.encoding_layers {
+            return Err(PlotHeaderError::EncodingLayersMismatch {
+                plot: self.encoding_layers,
+                expected: expected.encoding_layers,
+            });
+        }
+        if self.piece_size != expected.piece_size {
+            return Err(PlotHeaderError::PieceSizeMismatch {
+                plot: self.piece_size,
+                expected: expected.piece_size,
+            });
+        }
+        if self.encoding_scheme != expected.encoding_scheme {
+            return Err(PlotHeaderError::EncodingSchemeMismatch {
+                plot: self.encoding_scheme,
+                expected: expected.encoding_scheme,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip_and_checks() {
+        let header = PlotHeader::new([1u8; 32], 3);
+        let mut bytes = header.to_bytes();
+        assert_eq!(header, PlotHeader::from_bytes(&bytes).unwrap());
+        assert!(header.check_compatible(&header).is_ok());
+
+        assert!(matches!(
+            header.check_compatible(&PlotHeader::new([2u8; 32], 3)),
+            Err(PlotHeaderError::NodeIdMismatch)
+        ));
+
+        let mut old_scheme = header.clone();
+        old_scheme.encoding_scheme = ENCODING_SCHEME.version() + 1;
+        assert!(matches!(
+            old_scheme.check_compatible(&header),
+            Err(PlotHeaderError::EncodingSchemeMismatch { .. })
+        ));
+
+        bytes[HEADER_SIZE / 2] ^= 1;
+        assert!(matches!(
+            PlotHeader::from_bytes(&bytes),
+            Err(PlotHeaderError::Corrupted)
+        ));
+
+        assert!(matches!(
+            PlotHeader::from_bytes(&[0u8; HEADER_SIZE]),
+            Err(PlotHeaderError::MissingHeader)
+        ));
+    }
+}
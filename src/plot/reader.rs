@@ -0,0 +1,71 @@
+use crate::{Piece, PIECE_SIZE};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+/// Alignment of offsets and buffers for direct I/O, covers logical block sizes of common disks
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Buffer for direct reads, pieces don't start at aligned offsets, so one extra block is read
+#[repr(C, align(4096))]
+struct AlignedBuffer([u8; PIECE_SIZE + DIRECT_IO_ALIGNMENT]);
+
+/// Reads pieces from the plot file with positioned reads, so that any number of reads can run at
+/// once instead of seeking a single shared file handle
+#[derive(Debug)]
+pub(super) struct PlotReader {
+    file: File,
+    direct_io: bool,
+}
+
+impl PlotReader {
+    /// Opens the plot file for reading, with `direct_io` reads bypass the page cache (Linux only)
+    pub(super) fn open(plot_file_path: &Path, direct_io: bool) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        if direct_io {
+            #[cfg(target_os = "linux")]
+            options.custom_flags(libc::O_DIRECT);
+            #[cfg(not(target_os = "linux"))]
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Direct I/O is only supported on Linux",
+            ));
+        }
+
+        Ok(PlotReader {
+            file: options.open(plot_file_path)?,
+            direct_io,
+        })
+    }
+
+    pub(super) fn direct_io(&self) -> bool {
+        self.direct_io
+    }
+
+    /// Reads the piece at `position` in the plot file
+    pub(super) fn read(&self, position: u64) -> io::Result<Piece> {
+        let mut piece = [0u8; PIECE_SIZE];
+        if !self.direct_io {
+            self.file.read_exact_at(&mut piece, position)?;
+            return Ok(piece);
+        }
+
+        // Direct reads must start at an aligned offset and only come back short at the end of file
+        let offset = (position % DIRECT_IO_ALIGNMENT as u64) as usize;
+        let mut buffer = Box::new(AlignedBuffer([0u8; PIECE_SIZE + DIRECT_IO_ALIGNMENT]));
+        let read = loop {
+            match self.file.read_at(&mut buffer.0, position - offset as u64) {
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                result => break result?,
+            }
+        };
+        if read < offset + PIECE_SIZE {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        piece.copy_from_slice(&buffer.0[offset..offset + PIECE_SIZE]);
+        Ok(piece)
+    }
+}
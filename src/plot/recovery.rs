@@ -0,0 +1,88 @@
+use super::{position_of, HEADER_SIZE};
+use crate::{crypto, Piece, Salt, Tag, PIECE_SIZE};
+use rocksdb::{IteratorMode, WriteBatch, DB};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Exists while the plot is open, a plot that still has it on open wasn't closed cleanly
+pub(super) const DIRTY_MARKER_FILE: &str = "plot.dirty";
+
+#[derive(Debug, Default)]
+pub(super) struct RecoveryReport {
+    /// map entries pointing past the end of the plot file
+    pub(super) dropped_entries: usize,
+    /// tags that don't match the encoding of the piece they point to, left by batches that never
+    /// made it into the map or by overwrites that didn't make it into tags
+    pub(super) dropped_tags: usize,
+    /// tags of pieces that were committed to the map, but not to tags
+    pub(super) restored_tags: usize,
+}
+
+/// Brings map and tags back in line with the plot file after a crash
+///
+/// Piece data is synced before the map is committed and the map is committed before tags, so a
+/// crash can only leave map entries for data that didn't reach the disk (when the OS crashed as
+/// well), tags of an unfinished batch or committed pieces without tags (or with tags of the pieces
+/// they replaced). Every mapped piece is read to tag it again, which is only done after a crash.
+pub(super) fn recover(
+    plot_file_path: &Path,
+    map_db: &DB,
+    tags_db: &DB,
+    salt: Salt,
+) -> Result<RecoveryReport, io::Error> {
+    let mut report = RecoveryReport::default();
+    let mut plot_file = File::open(plot_file_path)?;
+    let plot_file_size = plot_file.metadata()?.len();
+
+    let mut positions = Vec::new();
+    let mut batch = WriteBatch::default();
+    for (key, value) in map_db.iterator(IteratorMode::Start) {
+        let index = u64::from_le_bytes(key[..].try_into().unwrap());
+        let position = position_of(&value);
+        if position < HEADER_SIZE as u64 || position + PIECE_SIZE as u64 > plot_file_size {
+            batch.delete(key).map_err(to_io_error)?;
+            report.dropped_entries += 1;
+        } else {
+            positions.push((index, position));
+        }
+    }
+    map_db.write(batch).map_err(to_io_error)?;
+
+    // Tags are derived from encodings on disk rather than trusted, an overwrite that crashed
+    // between the map and tags batches leaves the tag of the old piece pointing to the index
+    let mut expected_tags = HashMap::with_capacity(positions.len());
+    let mut encoding: Piece = [0u8; PIECE_SIZE];
+    for (index, position) in positions {
+        plot_file.seek(SeekFrom::Start(position))?;
+        plot_file.read_exact(&mut encoding)?;
+        expected_tags.insert(crypto::create_tag(&encoding, salt), index);
+    }
+
+    let mut batch = WriteBatch::default();
+    for (tag, index) in tags_db.iterator(IteratorMode::Start) {
+        let index = u64::from_le_bytes(index[..].try_into().unwrap());
+        let tag: Tag = tag[..].try_into().unwrap();
+        if expected_tags.get(&tag) == Some(&index) {
+            expected_tags.remove(&tag);
+        } else {
+            batch.delete(tag).map_err(to_io_error)?;
+            report.dropped_tags += 1;
+        }
+    }
+
+    for (tag, index) in expected_tags {
+        batch.put(tag, index.to_le_bytes()).map_err(to_io_error)?;
+        report.restored_tags += 1;
+    }
+    tags_db.write(batch).map_err(to_io_error)?;
+
+    Ok(report)
+}
+
+fn to_io_error(error: rocksdb::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
@@ -0,0 +1,101 @@
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters of a plot at one point in time
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PlotStats {
+    pub piece_count: u64,
+    /// size of the plot file, including the header and free slots
+    pub plot_file_size: u64,
+    /// slots left by removed pieces, new pieces are written there first
+    pub free_slots: u64,
+    /// estimated size of the tags database on disk
+    pub tags_db_size: u64,
+    pub reads: LatencyStats,
+    pub writes: LatencyStats,
+    /// requests sent to the plot that weren't processed yet
+    pub pending_requests: usize,
+}
+
+impl fmt::Display for PlotStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pieces, {} MiB, read {} µs, write {} µs, {} pending",
+            self.piece_count,
+            self.plot_file_size / 1024 / 1024,
+            self.reads.average_micros,
+            self.writes.average_micros,
+            self.pending_requests
+        )
+    }
+}
+
+/// Latencies since the plot was opened, writes are counted per batch
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub average_micros: u64,
+    pub max_micros: u64,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct LatencyRecorder {
+    count: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl LatencyRecorder {
+    pub(super) fn record(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub(super) fn stats(&self) -> LatencyStats {
+        let count = self.count.load(Ordering::Relaxed);
+
+        LatencyStats {
+            count,
+            average_micros: self.total_micros.load(Ordering::Relaxed) / count.max(1),
+            max_micros: self.max_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sizes of a plot, stored by its request loop after every write so that stats can be read
+/// without waiting for the loop
+#[derive(Debug, Default)]
+pub(super) struct SizeCounters {
+    plot_file_size: AtomicU64,
+    free_slots: AtomicU64,
+    tags_db_size: AtomicU64,
+}
+
+impl SizeCounters {
+    pub(super) fn store(&self, plot_file_size: u64, free_slots: u64, tags_db_size: u64) {
+        self.plot_file_size.store(plot_file_size, Ordering::Relaxed);
+        self.free_slots.store(free_slots, Ordering::Relaxed);
+        self.tags_db_size.store(tags_db_size, Ordering::Relaxed);
+    }
+
+    /// Stats without latencies and pending requests, which are counted elsewhere
+    pub(super) fn stats(&self, header_size: u64, piece_size: u64) -> PlotStats {
+        let plot_file_size = self.plot_file_size.load(Ordering::Relaxed);
+        let free_slots = self.free_slots.load(Ordering::Relaxed);
+        // every slot either holds a piece or is free
+        let slots = plot_file_size.saturating_sub(header_size) / piece_size;
+
+        PlotStats {
+            piece_count: slots.saturating_sub(free_slots),
+            plot_file_size,
+            free_slots,
+            tags_db_size: self.tags_db_size.load(Ordering::Relaxed),
+            ..PlotStats::default()
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use super::reader::PlotReader;
+use super::{position_of, HEADER_SIZE};
+use crate::state::{BlockHeight, MerkleRoot};
+use crate::{
+    crypto, sloth, NodeID, Piece, Salt, Tag, ENCODING_LAYERS, ENCODING_SCHEME,
+    PIECES_PER_STATE_BLOCK, PIECE_SIZE,
+};
+use rocksdb::{IteratorMode, WriteBatch, DB};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+
+/// How many pieces of the plot are checked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyMode {
+    /// Every piece is decoded and stale tags are looked for
+    Full,
+    /// Only this many randomly chosen pieces are decoded
+    Sample(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    pub mode: VerifyMode,
+    /// Remove corrupted pieces and fix tags instead of just reporting them
+    pub repair: bool,
+    /// Piece merkle roots of known state blocks, e.g. the ones synced from the state chain, proofs
+    /// of pieces from other state blocks can't be checked
+    pub merkle_roots: HashMap<BlockHeight, MerkleRoot>,
+}
+
+/// Outcome of verifying a plot, lists piece indexes that have problems
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    /// pieces that couldn't be read from the plot file
+    pub unreadable: Vec<u64>,
+    /// pieces that don't decode into data matching their merkle proof
+    pub invalid: Vec<u64>,
+    /// pieces without a known merkle root, decoded but not checked
+    pub unverified: usize,
+    /// pieces that can't be found by their tag
+    pub missing_tags: Vec<u64>,
+    /// tags pointing to pieces that aren't in the plot
+    pub stale_tags: usize,
+    /// whether the problems above were fixed, corrupted pieces are removed from the plot
+    pub repaired: bool,
+}
+
+impl VerifyReport {
+    /// Pieces that have to be fetched from the network again
+    pub fn corrupted(&self) -> Vec<u64> {
+        self.unreadable
+            .iter()
+            .chain(self.invalid.iter())
+            .copied()
+            .collect()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.unreadable.is_empty()
+            && self.invalid.is_empty()
+            && self.missing_tags.is_empty()
+            && self.stale_tags == 0
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Checked pieces: {}", self.checked)?;
+        writeln!(f, "Unreadable pieces: {:?}", self.unreadable)?;
+        writeln!(f, "Invalid pieces: {:?}", self.invalid)?;
+        writeln!(f, "Pieces without known merkle root: {}", self.unverified)?;
+        writeln!(f, "Pieces with missing tags: {:?}", self.missing_tags)?;
+        writeln!(f, "Stale tags: {}", self.stale_tags)?;
+        write!(f, "Repaired: {}", self.repaired)
+    }
+}
+
+pub(super) enum PieceStatus {
+    Valid,
+    Unverified,
+    Invalid,
+}
+
+/// Map entry of a piece and its encoding, as far as it could be read
+pub(super) enum Entry {
+    /// piece was removed from the plot in the meantime
+    Removed,
+    Unreadable(Vec<u8>),
+    Readable(Vec<u8>, Piece),
+}
+
+/// Problems found by verify, applied by the request loop such that no write runs at the same time
+#[derive(Debug)]
+pub(super) struct Repair {
+    /// salt tags were checked against
+    pub(super) salt: Salt,
+    /// map entries of checked pieces as they were read, pieces whose entries changed since then
+    /// were written again and are left alone
+    pub(super) checked: HashMap<u64, Vec<u8>>,
+    pub(super) corrupted: Vec<u64>,
+    pub(super) stale_tags: Vec<(Box<[u8]>, u64)>,
+    pub(super) missing_tags: Vec<(Tag, u64)>,
+}
+
+/// Reads the map entry of the piece at `index` and the encoding it points to
+pub(super) fn read_entry(map_db: &DB, reader: &PlotReader, index: u64) -> io::Result<Entry> {
+    let value = match map_db
+        .get(index.to_le_bytes())
+        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?
+    {
+        Some(value) => value.to_vec(),
+        None => return Ok(Entry::Removed),
+    };
+
+    let position = position_of(&value);
+    // Pieces are only ever written after the header, one after another
+    if position < HEADER_SIZE as u64 || (position - HEADER_SIZE as u64) % PIECE_SIZE as u64 != 0 {
+        return Ok(Entry::Unreadable(value));
+    }
+
+    Ok(match reader.read(position) {
+        Ok(encoding) => Entry::Readable(value, encoding),
+        Err(_) => Entry::Unreadable(value),
+    })
+}
+
+/// Decodes the piece and checks it against the merkle proof stored next to it
+pub(super) fn check_piece(
+    decoder: &dyn sloth::Decoder,
+    node_id: NodeID,
+    index: u64,
+    mut encoding: Piece,
+    merkle_proof: &[u8],
+    merkle_roots: &HashMap<BlockHeight, MerkleRoot>,
+) -> PieceStatus {
+    let merkle_root = match merkle_roots.get(&(index / PIECES_PER_STATE_BLOCK as u64)) {
+        Some(merkle_root) => merkle_root,
+        None => return PieceStatus::Unverified,
+    };
+
+    decoder.decode(
+        &mut encoding,
+        ENCODING_SCHEME.expanded_iv(node_id, index),
+        ENCODING_LAYERS,
+    );
+
+    if crypto::validate_merkle_proof(crypto::digest_sha_256(&encoding), merkle_proof, merkle_root) {
+        PieceStatus::Valid
+    } else {
+        PieceStatus::Invalid
+    }
+}
+
+/// Whether the tags database points `tag` to the piece at `index`
+pub(super) fn has_tag(tags_db: &DB, tag: &[u8; 8], index: u64) -> bool {
+    matches!(
+        tags_db.get(tag),
+        Ok(Some(tag_index)) if tag_index[..] == index.to_le_bytes()
+    )
+}
+
+/// Finds tags that don't belong to any piece in the plot, i.e. tags of removed or overwritten
+/// pieces
+///
+/// `checked_tags` are the tags of pieces that were decoded, other pieces only need to be in the
+/// map.
+pub(super) fn find_stale_tags(
+    tags_db: &DB,
+    map_db: &DB,
+    checked_tags: &HashMap<u64, Tag>,
+) -> Vec<(Box<[u8]>, u64)> {
+    tags_db
+        .iterator(IteratorMode::Start)
+        .map(|(tag, index)| (tag, u64::from_le_bytes(index[..].try_into().unwrap())))
+        .filter(|(tag, index)| match checked_tags.get(index) {
+            Some(checked_tag) => checked_tag[..] != tag[..],
+            None => matches!(map_db.get(index.to_le_bytes()), Ok(None)),
+        })
+        .collect()
+}
+
+/// Removes corrupted pieces from the map, all their tags along with other stale tags, and adds
+/// missing tags of valid pieces
+///
+/// Pieces that were written again since they were checked are skipped.
+pub(super) fn repair(map_db: &DB, tags_db: &DB, repair: Repair) -> Result<(), rocksdb::Error> {
+    let Repair {
+        checked,
+        corrupted,
+        stale_tags,
+        missing_tags,
+        ..
+    } = repair;
+    let unchanged = |index: u64| -> Result<bool, rocksdb::Error> {
+        Ok(map_db.get(index.to_le_bytes())?.as_deref() == checked.get(&index).map(Vec::as_slice))
+    };
+
+    let mut corrupted_indexes = HashSet::new();
+    let mut batch = WriteBatch::default();
+    for index in corrupted {
+        if unchanged(index)? {
+            batch.delete(index.to_le_bytes())?;
+            corrupted_indexes.insert(index);
+        }
+    }
+    map_db.write(batch)?;
+
+    let mut batch = WriteBatch::default();
+    for (tag, index) in tags_db.iterator(IteratorMode::Start) {
+        if corrupted_indexes.contains(&u64::from_le_bytes(index[..].try_into().unwrap())) {
+            batch.delete(tag)?;
+        }
+    }
+    for (tag, index) in stale_tags {
+        let still_points_to_index =
+            matches!(tags_db.get(&tag)?, Some(tag_index) if tag_index[..] == index.to_le_bytes());
+        if still_points_to_index && unchanged(index)? {
+            batch.delete(tag)?;
+        }
+    }
+    for (tag, index) in missing_tags {
+        if unchanged(index)? {
+            batch.put(tag, index.to_le_bytes())?;
+        }
+    }
+    tags_db.write(batch)
+}
@@ -1,16 +1,13 @@
-use crate::plot::Plot;
-use crate::state::PieceBundle;
-use crate::{
-    crypto, sloth, NodeID, CONSOLE, ENCODING_LAYERS_TEST, PIECE_SIZE, PLOT_SIZE, PRIME_SIZE_BITS,
-};
+use crate::plot::{PieceWrite, Plot, PlotCreationError, VerifyMode, VerifyOptions, VerifyReport};
+use crate::state::{BlockHeight, MerkleRoot, PieceBundle};
+use crate::{NodeID, CONSOLE, ENCODING_SCHEME, PIECE_SIZE, PLOT_SIZE, PLOT_WRITE_BATCH_SIZE};
 use async_std::path::PathBuf;
 use async_std::task;
 use indicatif::ProgressBar;
 use log::*;
-use rayon::prelude::*;
-use rug::integer::Order;
-use rug::Integer;
-use std::convert::TryInto;
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
 use std::time::Instant;
 
 /* ToDo
@@ -25,64 +22,177 @@ use std::time::Instant;
  *
 */
 
-pub async fn plot(path: PathBuf, node_id: NodeID, piece_bundles: Vec<PieceBundle>) -> Plot {
+/// How many pieces of an existing plot are checked when it is repaired on startup
+const STARTUP_VERIFY_SAMPLES: usize = 16;
+
+/// Location and size of a single plot, parsed from `PATH[:SIZE]`
+///
+/// Size is in bytes and may have a `K`, `M`, `G` or `T` (binary) suffix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlotConfig {
+    pub path: PathBuf,
+    pub size_in_bytes: u64,
+}
+
+impl PlotConfig {
+    /// Number of pieces that fit into the plot
+    pub fn piece_count(&self) -> usize {
+        (self.size_in_bytes / PIECE_SIZE as u64) as usize
+    }
+}
+
+impl FromStr for PlotConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(separator) = s.rfind(':') {
+            if let Some(size_in_bytes) = parse_size(&s[separator + 1..]) {
+                if size_in_bytes < PIECE_SIZE as u64 {
+                    return Err(format!(
+                        "Plot {} must fit at least one piece of {} bytes",
+                        s, PIECE_SIZE
+                    ));
+                }
+
+                return Ok(PlotConfig {
+                    path: PathBuf::from(&s[..separator]),
+                    size_in_bytes,
+                });
+            }
+        }
+
+        Ok(PlotConfig {
+            path: PathBuf::from(s),
+            size_in_bytes: (PLOT_SIZE * PIECE_SIZE) as u64,
+        })
+    }
+}
+
+fn parse_size(size: &str) -> Option<u64> {
+    let (number, multiplier) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        'T' => (&size[..size.len() - 1], 1 << 40),
+        _ => (size, 1),
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Verifies a sample of pieces of an existing plot against `merkle_roots` and removes corrupted
+/// ones, such that they are plotted or fetched from the network again
+pub async fn repair_plot(
+    plot: &Plot,
+    merkle_roots: HashMap<BlockHeight, MerkleRoot>,
+) -> io::Result<VerifyReport> {
+    plot.verify(VerifyOptions {
+        mode: VerifyMode::Sample(STARTUP_VERIFY_SAMPLES),
+        repair: true,
+        merkle_roots,
+    })
+    .await
+}
+
+/// Opens or creates the plot and fits it to `piece_count` pieces: an existing plot that is larger
+/// is shrunk, one that is smaller gets missing pieces from `piece_bundles` plotted
+///
+/// With `repair_with` merkle roots, a sample of pieces in an existing plot is verified first, see
+/// `repair_plot()`.
+pub async fn plot(
+    path: PathBuf,
+    node_id: NodeID,
+    key_index: u32,
+    mut piece_bundles: Vec<PieceBundle>,
+    piece_count: usize,
+    repair_with: Option<HashMap<BlockHeight, MerkleRoot>>,
+) -> Plot {
     // init plot
-    let plot = Plot::open_or_create(&path).await.unwrap();
+    let plot = match Plot::open_or_create(&path, node_id, key_index).await {
+        Ok(plot) => plot,
+        Err(PlotCreationError::Header(error)) => {
+            panic!(
+                "Plot {:?} was created with different parameters ({:?}), remove it to replot",
+                path, error
+            );
+        }
+        Err(error) => panic!("Failed to open plot {:?}: {:?}", path, error),
+    };
+
+    piece_bundles.retain(|piece_bundle| piece_bundle.piece_index < piece_count as u64);
+
+    if !plot.is_empty().await {
+        if let Some(merkle_roots) = repair_with {
+            let report = repair_plot(&plot, merkle_roots)
+                .await
+                .expect("Failed to verify plot");
+            if !report.is_ok() {
+                warn!(
+                    "Plot {:?} had problems and was repaired, run `plot verify` for a full check:\n{}",
+                    path, report
+                );
+            }
+        }
+
+        let evicted = plot
+            .resize(piece_count as u64)
+            .await
+            .expect("Failed to resize plot");
+        if evicted > 0 {
+            info!("Evicted {} pieces to shrink plot {:?}", evicted, path);
+        }
+
+        let mut missing_piece_bundles = Vec::new();
+        for piece_bundle in piece_bundles {
+            if !plot.contains(piece_bundle.piece_index).await {
+                missing_piece_bundles.push(piece_bundle);
+            }
+        }
+        piece_bundles = missing_piece_bundles;
+    }
+
+    if !piece_bundles.is_empty() {
+        let piece_count = piece_bundles.len();
 
-    if plot.is_empty().await {
         let plotting_fut = task::spawn_blocking({
             let plot = plot.clone();
 
             move || {
-                let expanded_iv = crypto::expand_iv(node_id);
-                let integer_expanded_iv = Integer::from_digits(&expanded_iv, Order::Lsf);
-
                 // init sloth
-                let sloth = sloth::Sloth::init(PRIME_SIZE_BITS);
+                let sloth = ENCODING_SCHEME.sloth();
 
                 let mut bar: Option<ProgressBar> = None;
                 if !CONSOLE {
-                    bar = Some(ProgressBar::new(PLOT_SIZE as u64))
+                    bar = Some(ProgressBar::new(piece_count as u64))
                 };
 
                 // plot pieces in parallel on all cores, using IV as a source of randomness
                 // this is just for efficient testing atm
-                piece_bundles.into_par_iter().for_each(|piece_bundle| {
-                    let mut piece = piece_bundle.piece;
-
-                    sloth
-                        .encode(&mut piece, &integer_expanded_iv, ENCODING_LAYERS_TEST)
-                        .unwrap();
-
-                    // TODO: Replace challenge here and in other places
-                    let nonce = u64::from_le_bytes(
-                        crypto::create_hmac(&piece, b"subspace")[0..8]
-                            .try_into()
-                            .unwrap(),
-                    );
+                // next batch is encoded while the previous one is written
+                let mut pending_write: Option<task::JoinHandle<()>> = None;
+                for piece_bundles in piece_bundles.chunks(PLOT_WRITE_BATCH_SIZE) {
+                    let writes = PieceWrite::encode_batch(&sloth, node_id, piece_bundles.to_vec());
 
-                    task::spawn({
+                    if let Some(pending_write) = pending_write.take() {
+                        task::block_on(pending_write);
+                    }
+                    pending_write = Some(task::spawn({
                         let plot = plot.clone();
 
                         async move {
-                            let result = plot
-                                .write(
-                                    piece,
-                                    nonce,
-                                    piece_bundle.piece_index,
-                                    piece_bundle.piece_proof,
-                                )
-                                .await;
-
-                            if let Err(error) = result {
+                            if let Err(error) = plot.write_many(writes).await {
                                 warn!("{}", error);
                             }
                         }
-                    });
+                    }));
+
                     if let Some(b) = &bar {
-                        b.inc(1);
+                        b.inc(piece_bundles.len() as u64);
                     }
-                });
+                }
+                if let Some(pending_write) = pending_write {
+                    task::block_on(pending_write);
+                }
 
                 if let Some(b) = &bar {
                     b.finish();
@@ -92,7 +202,10 @@ pub async fn plot(path: PathBuf, node_id: NodeID, piece_bundles: Vec<PieceBundle
 
         let plot_time = Instant::now();
 
-        info!("Sloth is slowly plotting {} pieces...", PLOT_SIZE);
+        info!(
+            "Sloth is slowly plotting {} pieces into {:?}...",
+            piece_count, path
+        );
 
         if !CONSOLE {
             eprintln!(
@@ -116,7 +229,7 @@ pub async fn plot(path: PathBuf, node_id: NodeID, piece_bundles: Vec<PieceBundle
 
         let total_plot_time = plot_time.elapsed();
         let average_plot_time =
-            (total_plot_time.as_nanos() / PLOT_SIZE as u128) as f32 / (1000f32 * 1000f32);
+            (total_plot_time.as_nanos() / piece_count.max(1) as u128) as f32 / (1000f32 * 1000f32);
 
         info!("Average plot time is {:.3} ms per piece", average_plot_time);
 
@@ -127,7 +240,7 @@ pub async fn plot(path: PathBuf, node_id: NodeID, piece_bundles: Vec<PieceBundle
 
         info!(
             "Plotting throughput is {} mb/sec\n",
-            ((PLOT_SIZE as u64 * PIECE_SIZE as u64) / (1000 * 1000)) as f32
+            ((piece_count as u64 * PIECE_SIZE as u64) / (1000 * 1000)) as f32
                 / (total_plot_time.as_secs_f32())
         );
     } else {
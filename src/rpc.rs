@@ -1,6 +1,14 @@
+use crate::farmer::{AuditStats, AuditSummary, FarmerPlot};
+use crate::manager::SharedLedger;
 use crate::network::messages::GossipMessage;
 use crate::network::Network;
-use crate::{NodeID, DEV_WS_ADDR};
+use crate::plot::PlotStats;
+use crate::transaction::TxId;
+use crate::{NodeID, DEV_METRICS_ADDR, DEV_WS_ADDR};
+use async_std::io;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
 use futures::future;
 use jsonrpc_core::{MetaIoHandler, Middleware, Params, Value};
 use jsonrpc_pubsub::{PubSubHandler, PubSubMetadata, Session, Sink, Subscriber, SubscriptionId};
@@ -8,8 +16,16 @@ use jsonrpc_ws_server::{RequestContext, Server, ServerBuilder};
 use log::*;
 use static_assertions::_core::sync::atomic::AtomicUsize;
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Write;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Longest request head the metrics endpoint reads before giving up on the request
+const MAX_METRICS_REQUEST_SIZE: usize = 8 * 1024;
+/// Time a client of the metrics endpoint has to send its request and read the response
+const METRICS_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn add_subscriptions<T, S>(io: &mut PubSubHandler<T, S>, network: Network)
 where
@@ -54,11 +70,12 @@ where
             let sinks = Arc::clone(&sinks);
 
             match message {
-                GossipMessage::BlockProposal { block } => {
+                GossipMessage::BlockProposal { header } => {
                     // TODO: Serialization for numbers may lose u64 precision, also bytes are ugly, we
                     //  probably want to have them as hex.
                     //  https://openethereum.github.io/wiki/JSONRPC
-                    let params = Params::Array(vec![serde_json::to_value(block.clone()).unwrap()]);
+                    // only headers are gossiped, bodies are fetched on demand
+                    let params = Params::Array(vec![serde_json::to_value(header.clone()).unwrap()]);
                     for sink in sinks.lock().unwrap().values() {
                         drop(sink.notify(params.clone()));
                     }
@@ -71,13 +88,164 @@ where
         .detach();
 }
 
-pub fn run(node_id: NodeID, network: Network) -> Server {
+/// Stats of every plot, by plot index
+fn plot_stats(plots: &[FarmerPlot]) -> Vec<(usize, PlotStats)> {
+    plots
+        .iter()
+        .map(|farmer_plot| (farmer_plot.index, farmer_plot.plot.stats()))
+        .collect()
+}
+
+/// Plot and audit stats in Prometheus text format
+fn render_metrics(plot_stats: &[(usize, PlotStats)], audits: &AuditSummary) -> String {
+    let mut metrics = String::new();
+    for (index, stats) in plot_stats {
+        for (name, value) in &[
+            ("pieces", stats.piece_count),
+            ("file_bytes", stats.plot_file_size),
+            ("free_slots", stats.free_slots),
+            ("tags_db_bytes", stats.tags_db_size),
+            ("reads", stats.reads.count),
+            ("read_latency_average_micros", stats.reads.average_micros),
+            ("read_latency_max_micros", stats.reads.max_micros),
+            ("writes", stats.writes.count),
+            ("write_latency_average_micros", stats.writes.average_micros),
+            ("write_latency_max_micros", stats.writes.max_micros),
+            ("pending_requests", stats.pending_requests as u64),
+        ] {
+            let _ = writeln!(
+                metrics,
+                "subspace_plot_{}{{plot=\"{}\"}} {}",
+                name, index, value
+            );
+        }
+    }
+    for (name, value) in &[
+        ("timeslots", audits.timeslots),
+        ("candidates", audits.candidates),
+        ("solutions", audits.solutions),
+        ("blocks_won", audits.blocks_won),
+    ] {
+        let _ = writeln!(metrics, "subspace_audit_{} {}", name, value);
+    }
+
+    metrics
+}
+
+/// Serves `render_metrics` on `GET /metrics` for Prometheus to scrape
+async fn serve_metrics(listener: TcpListener, plots: Vec<FarmerPlot>, audit_stats: AuditStats) {
+    let plots = Arc::new(plots);
+    let mut connections = listener.incoming();
+
+    while let Some(stream) = connections.next().await {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                debug!("Failed to accept metrics connection: {}", error);
+                continue;
+            }
+        };
+        let plots = Arc::clone(&plots);
+        let audit_stats = audit_stats.clone();
+
+        task::spawn(async move {
+            let result = io::timeout(METRICS_REQUEST_TIMEOUT, async {
+                let request_line = read_request_line(&mut stream).await?;
+                let mut parts = request_line.split(' ');
+                let (status, body) = match (parts.next(), parts.next()) {
+                    (Some("GET"), Some("/metrics")) => (
+                        "200 OK",
+                        render_metrics(&plot_stats(&plots), &audit_stats.summary()),
+                    ),
+                    _ => ("404 Not Found", String::new()),
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await
+            })
+            .await;
+
+            if let Err(error) = result {
+                debug!("Failed to serve metrics: {}", error);
+            }
+        });
+    }
+}
+
+/// Reads the head of an HTTP request and returns its first line, the rest is ignored
+async fn read_request_line(stream: &mut TcpStream) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() >= MAX_METRICS_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Metrics request is too large",
+            ));
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    Ok(String::from_utf8_lossy(&request)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+pub fn run(
+    node_id: NodeID,
+    network: Network,
+    plots: Vec<FarmerPlot>,
+    audit_stats: AuditStats,
+    ledger: SharedLedger,
+) -> Server {
     let mut io = PubSubHandler::new(MetaIoHandler::default());
     io.add_sync_method("get_node_id", move |_params: Params| {
         Ok(Value::String(hex::encode(&node_id)))
     });
+    io.add_sync_method("get_plot_stats", {
+        let plots = plots.clone();
+        move |_params: Params| {
+            let plot_stats: HashMap<usize, PlotStats> = plot_stats(&plots).into_iter().collect();
+            Ok(serde_json::to_value(plot_stats).unwrap())
+        }
+    });
+    io.add_sync_method("get_audit_stats", {
+        let audit_stats = audit_stats.clone();
+        move |_params: Params| Ok(serde_json::to_value(audit_stats.summary()).unwrap())
+    });
+    io.add_method("get_tx_inclusion_proof", move |params: Params| {
+        let ledger = Arc::clone(&ledger);
+
+        // the ledger is awaited, so that requests don't block the RPC threads while it is busy
+        async move {
+            let (tx_id,) = params.parse::<(String,)>()?;
+            let tx_id: TxId = hex::decode(&tx_id)
+                .ok()
+                .and_then(|tx_id| tx_id.try_into().ok())
+                .ok_or_else(|| {
+                    jsonrpc_core::Error::invalid_params("Expected a hex encoded tx id")
+                })?;
+            let proof = ledger.lock().await.get_tx_inclusion_proof(&tx_id);
+            Ok(serde_json::to_value(proof).unwrap())
+        }
+    });
     add_subscriptions(&mut io, network);
 
+    let metrics_listener = task::block_on(TcpListener::bind(DEV_METRICS_ADDR))
+        .expect("Metrics server must start with no issues");
+    task::spawn(serve_metrics(metrics_listener, plots, audit_stats));
+
     // TODO: CORS origins
     let server = ServerBuilder::new(io)
         .session_meta_extractor(|context: &RequestContext| {
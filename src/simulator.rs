@@ -0,0 +1,674 @@
+//! Deterministic multi-node consensus simulator
+//!
+//! Runs several in-process nodes, each with its own `Ledger`, `EpochTracker` and in-memory plot,
+//! in lockstep on a virtual clock instead of `timer::run`'s wall clock sleeps. This makes forks,
+//! epoch randomness and eon adjustments reproducible from a seed.
+//!
+//! Every node is a farmer that holds the full genesis state. Gossip is modelled as a queue of
+//! deliveries ordered by virtual arrival time, with a (seeded) random delay for every link of a
+//! full mesh; blocks are not re-gossiped. Farmers can behave adversarially by withholding their
+//! blocks for a while or never publishing them at all.
+
+mod memory_plot;
+mod report;
+
+pub use memory_plot::MemoryPlot;
+pub use report::SimulationReport;
+
+use crate::block::Block;
+use crate::clock::{ManualClock, SharedClock};
+use crate::farmer::Solution;
+use crate::ledger::Ledger;
+use crate::manager::{GenesisConfig, ProtocolMessage};
+use crate::sloth::DecodingService;
+use crate::state::State;
+use crate::timer::EpochTracker;
+use crate::{
+    crypto, ContentId, CONFIRMATION_DEPTH, ENCODING_LAYERS, ENCODING_SCHEME, GENESIS_PIECE_COUNT,
+    GENESIS_STATE_BLOCKS, GENESIS_STATE_SEED, INITIAL_SOLUTION_RANGE, TIMESLOTS_PER_EPOCH,
+    TIMESLOT_DURATION,
+};
+use async_std::sync::{channel, Receiver};
+use log::*;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand_core::SeedableRng;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::sync::Arc;
+
+/// Genesis timestamp used by all simulated nodes
+const GENESIS_TIMESTAMP: u64 = 1;
+/// How far back from a head to look when measuring re-orgs and confirmations
+const MAX_CHAIN_WALK_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FarmerBehaviour {
+    /// Gossips every block as soon as it is created
+    Honest,
+    /// Keeps its blocks private and only gossips them a number of timeslots later
+    Withholding { release_after_timeslots: u64 },
+    /// Farms on its own view of the ledger, but never gossips any block
+    Silent,
+}
+
+/// Uniformly distributed gossip delay for a link, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkDelay {
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulationConfig {
+    /// seed for farmer keys and network delays
+    pub seed: u64,
+    /// number of timeslots to simulate
+    pub timeslots: u64,
+    /// one entry per node
+    pub farmers: Vec<FarmerBehaviour>,
+    /// default delay for every link
+    pub delay: NetworkDelay,
+    /// delay overrides for individual (from, to) links
+    pub link_delays: HashMap<(usize, usize), NetworkDelay>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            seed: 0,
+            timeslots: 256,
+            farmers: vec![FarmerBehaviour::Honest; 4],
+            delay: NetworkDelay {
+                min_ms: 50,
+                max_ms: 250,
+            },
+            link_delays: HashMap::new(),
+        }
+    }
+}
+
+/// A block in flight between two nodes
+struct Delivery {
+    /// virtual time of arrival in ms since genesis
+    at: u64,
+    /// tie breaker that keeps ordering stable for deliveries arriving at the same time
+    sequence: u64,
+    to: usize,
+    block: Block,
+}
+
+impl PartialEq for Delivery {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.sequence) == (other.at, other.sequence)
+    }
+}
+
+impl Eq for Delivery {}
+
+impl PartialOrd for Delivery {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delivery {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.sequence).cmp(&(other.at, other.sequence))
+    }
+}
+
+struct SimNode {
+    behaviour: FarmerBehaviour,
+    ledger: Ledger,
+    plot: MemoryPlot,
+    /// receives state bundles emitted by `State`, which are not plotted in the simulation
+    state_receiver: Receiver<ProtocolMessage>,
+    /// proposer blocks received before their parent
+    pending_blocks: Vec<Block>,
+    current_epoch_index: u64,
+    last_head: ContentId,
+}
+
+#[derive(Default)]
+struct Metrics {
+    proposer_blocks: Vec<ContentId>,
+    tx_blocks: u64,
+    max_fork_depth: u64,
+    max_concurrent_heads: usize,
+    solution_range_updates: Vec<(u64, u64)>,
+    solution_range_disagreements: u64,
+    confirmed: HashSet<ContentId>,
+    confirmation_latencies: Vec<u64>,
+}
+
+pub struct Simulation {
+    config: SimulationConfig,
+    nodes: Vec<SimNode>,
+    /// index of the node whose view of the ledger is used for chain level metrics
+    observer: usize,
+    deliveries: BinaryHeap<Reverse<Delivery>>,
+    /// (release timeslot, sender, block) for blocks held back by withholding farmers
+    withheld_blocks: Vec<(u64, usize, Block)>,
+    next_sequence: u64,
+    rng: StdRng,
+    metrics: Metrics,
+    /// shared by all nodes, moved forward as simulated time passes
+    clock: Arc<ManualClock>,
+}
+
+impl Simulation {
+    /// Creates all nodes from the same genesis state and plots it for each of them
+    pub async fn new(config: SimulationConfig) -> Self {
+        let sloth = ENCODING_SCHEME.sloth();
+        // one decoding pool for all nodes, instead of one per ledger
+        let decoding_service =
+            DecodingService::new(Arc::new(ENCODING_SCHEME.sloth()), ENCODING_LAYERS);
+        let clock = Arc::new(ManualClock::new(GENESIS_TIMESTAMP));
+        let mut nodes = Vec::with_capacity(config.farmers.len());
+
+        for (index, behaviour) in config.farmers.iter().enumerate() {
+            let keys = crypto::gen_keys_from_seed(config.seed.wrapping_add(index as u64));
+            let node_id = crypto::digest_sha_256(&keys.public.to_bytes());
+
+            // genesis emits one state bundle per state block before we get a chance to drain it
+            let (state_sender, state_receiver) =
+                channel::<ProtocolMessage>(GENESIS_STATE_BLOCKS * 2);
+            let mut state = State::new(state_sender);
+            let piece_bundles = state
+                .create_genesis_state(GENESIS_STATE_SEED, GENESIS_PIECE_COUNT)
+                .await;
+            while state_receiver.try_recv().is_ok() {}
+
+            let plot = MemoryPlot::create(node_id, &piece_bundles, &sloth);
+
+            let epoch_tracker = EpochTracker::new().await;
+            let mut ledger = Ledger::new(
+                keys,
+                epoch_tracker,
+                state,
+                Arc::clone(&clock) as SharedClock,
+                decoding_service.clone(),
+            );
+            ledger.set_genesis_config(GenesisConfig {
+                genesis_timestamp: GENESIS_TIMESTAMP,
+                genesis_challenge: [0u8; 32],
+                encoding_layers: ENCODING_LAYERS as u32,
+            });
+            ledger.timer_is_running = true;
+            ledger.current_timeslot = 0;
+            let last_head = ledger.get_head();
+
+            debug!(
+                "Created simulated node {} with id {}",
+                index,
+                hex::encode(&node_id[0..8])
+            );
+
+            nodes.push(SimNode {
+                behaviour: *behaviour,
+                ledger,
+                plot,
+                state_receiver,
+                pending_blocks: Vec::new(),
+                current_epoch_index: 0,
+                last_head,
+            });
+        }
+
+        let observer = config
+            .farmers
+            .iter()
+            .position(|behaviour| *behaviour == FarmerBehaviour::Honest)
+            .unwrap_or_default();
+        let rng = StdRng::seed_from_u64(config.seed);
+
+        Simulation {
+            config,
+            nodes,
+            observer,
+            deliveries: BinaryHeap::new(),
+            withheld_blocks: Vec::new(),
+            next_sequence: 0,
+            rng,
+            metrics: Metrics::default(),
+            clock,
+        }
+    }
+
+    /// Advances all nodes through every timeslot and returns the collected metrics
+    pub async fn run(mut self) -> SimulationReport {
+        for timeslot in 1..=self.config.timeslots {
+            let now = timeslot * TIMESLOT_DURATION;
+
+            // everything that arrived before this timeslot is seen before farming on it
+            self.deliver_until(now).await;
+            self.clock.set(GENESIS_TIMESTAMP + now);
+
+            for index in 0..self.nodes.len() {
+                self.farm(index, timeslot).await;
+            }
+
+            self.release_withheld_blocks(timeslot, now);
+            self.observe(timeslot);
+        }
+
+        self.into_report()
+    }
+
+    /// Does what `timer::run` and `farmer::run` do for a single timeslot on one node
+    async fn farm(&mut self, index: usize, timeslot: u64) {
+        let node = &mut self.nodes[index];
+
+        node.ledger.next_timeslot().await;
+
+        if timeslot % TIMESLOTS_PER_EPOCH == 0
+            && node.current_epoch_index < timeslot / TIMESLOTS_PER_EPOCH
+        {
+            node.current_epoch_index = node.ledger.advance_epoch().await;
+        }
+
+        let epoch_index = node.current_epoch_index;
+        let (randomness, slot_challenge) = match node
+            .ledger
+            .epoch_tracker
+            .get_slot_challenge(epoch_index, timeslot)
+            .await
+        {
+            Ok(challenge) => challenge,
+            Err(error) => {
+                warn!(
+                    "Node {} cannot farm on timeslot {}: {:?}",
+                    index, timeslot, error
+                );
+                return;
+            }
+        };
+        let solution_range = node.ledger.current_solution_range;
+        let target = slot_challenge[0..8].try_into().unwrap();
+        let salt = node.ledger.salt_at(timeslot);
+        if node.plot.salt() != salt {
+            node.plot.recommit(salt);
+        }
+
+        let solutions: Vec<Solution> = node
+            .plot
+            .find_by_range(target, solution_range)
+            .into_iter()
+            .map(|(tag, piece_index)| {
+                let (encoding, merkle_proof) = node
+                    .plot
+                    .read(piece_index)
+                    .expect("Tags only point to plotted pieces");
+                Solution {
+                    epoch_index,
+                    timeslot,
+                    randomness,
+                    piece_index,
+                    tag,
+                    salt,
+                    encoding,
+                    merkle_proof,
+                    solution_range,
+                    target,
+                    plot_index: 0,
+                }
+            })
+            .collect();
+
+        // same as handling `ProtocolMessage::BlockSolutions` in the manager
+        let piece_hashes = node
+            .ledger
+            .decoding_service
+            .piece_hashes(node.ledger.solution_encodings(&solutions))
+            .await;
+        let mut content_ids: Vec<ContentId> = Vec::new();
+        let mut blocks: Vec<Block> = Vec::with_capacity(solutions.len());
+        for (solution, piece_hash) in solutions.into_iter().zip(piece_hashes) {
+            let block = match node
+                .ledger
+                .create_and_apply_local_block(solution, content_ids.clone(), piece_hash)
+                .await
+            {
+                Some(block) => block,
+                None => continue,
+            };
+
+            if block.content.parent_id.is_some() {
+                node.ledger.stage_proposer_block(&block).await;
+                content_ids.push(block.content.get_id());
+            } else {
+                node.ledger.stage_tx_block(&block).await;
+            }
+
+            blocks.push(block);
+        }
+
+        while node.state_receiver.try_recv().is_ok() {}
+
+        let behaviour = node.behaviour;
+        let now = timeslot * TIMESLOT_DURATION;
+        for block in blocks.into_iter() {
+            if block.content.parent_id.is_some() {
+                self.metrics.proposer_blocks.push(block.content.get_id());
+            } else {
+                self.metrics.tx_blocks += 1;
+            }
+
+            match behaviour {
+                FarmerBehaviour::Honest => self.gossip(index, block, now),
+                FarmerBehaviour::Withholding {
+                    release_after_timeslots,
+                } => {
+                    self.withheld_blocks
+                        .push((timeslot + release_after_timeslots, index, block));
+                }
+                FarmerBehaviour::Silent => {}
+            }
+        }
+    }
+
+    /// Gossips all withheld blocks whose release timeslot has arrived
+    fn release_withheld_blocks(&mut self, timeslot: u64, now: u64) {
+        let (released, withheld): (Vec<_>, Vec<_>) = std::mem::take(&mut self.withheld_blocks)
+            .into_iter()
+            .partition(|(release_timeslot, _, _)| *release_timeslot <= timeslot);
+        self.withheld_blocks = withheld;
+
+        for (_, from, block) in released.into_iter() {
+            debug!(
+                "Node {} is releasing a withheld block from timeslot {}",
+                from, block.proof.timeslot
+            );
+            self.gossip(from, block, now);
+        }
+    }
+
+    /// Schedules delivery of a block to every other node
+    fn gossip(&mut self, from: usize, block: Block, now: u64) {
+        for to in 0..self.nodes.len() {
+            if to == from {
+                continue;
+            }
+
+            let delay = self
+                .config
+                .link_delays
+                .get(&(from, to))
+                .copied()
+                .unwrap_or(self.config.delay);
+            let delay_ms = if delay.max_ms > delay.min_ms {
+                self.rng.gen_range(delay.min_ms, delay.max_ms + 1)
+            } else {
+                delay.min_ms
+            };
+
+            self.next_sequence += 1;
+            self.deliveries.push(Reverse(Delivery {
+                at: now + delay_ms,
+                sequence: self.next_sequence,
+                to,
+                block: block.clone(),
+            }));
+        }
+    }
+
+    /// Hands all blocks that arrive strictly before `time` to their recipients
+    async fn deliver_until(&mut self, time: u64) {
+        while let Some(Reverse(delivery)) = self.deliveries.peek() {
+            if delivery.at >= time {
+                break;
+            }
+
+            let Reverse(delivery) = self.deliveries.pop().expect("Peeked above");
+            self.clock.set(GENESIS_TIMESTAMP + delivery.at);
+            self.receive(delivery.to, delivery.block).await;
+        }
+    }
+
+    /// Validates and stages a block received via gossip, the same way the manager does
+    async fn receive(&mut self, index: usize, block: Block) {
+        let node = &mut self.nodes[index];
+        let mut blocks = vec![block];
+
+        while let Some(block) = blocks.pop() {
+            // the ledger expects the parent of a gossiped block to be known
+            if let Some(parent_id) = block.content.parent_id {
+                if parent_id != node.ledger.genesis_challenge
+                    && !node.ledger.metablocks.contains_content_id(&parent_id)
+                {
+                    node.pending_blocks.push(block);
+                    continue;
+                }
+            }
+
+            // blocks arrive one at a time here, the manager decodes the ones that queued up at once
+            if !node.ledger.check_block_from_gossip(&block).await {
+                continue;
+            }
+            let (encoding, expanded_iv) = block
+                .encoding_to_decode()
+                .expect("Valid blocks have auxiliary data");
+            let decoding_hash = node
+                .ledger
+                .decoding_service
+                .piece_hash(encoding, expanded_iv)
+                .await;
+            if !block.has_valid_encoding(&decoding_hash) {
+                continue;
+            }
+
+            if block.content.parent_id.is_some() {
+                node.ledger.stage_proposer_block(&block).await;
+
+                // children that were waiting for this block can now be staged
+                let content_id = block.content.get_id();
+                let (ready, waiting): (Vec<Block>, Vec<Block>) =
+                    std::mem::take(&mut node.pending_blocks)
+                        .into_iter()
+                        .partition(|pending| pending.content.parent_id == Some(content_id));
+                node.pending_blocks = waiting;
+                blocks.extend(ready);
+            } else {
+                node.ledger.stage_tx_block(&block).await;
+            }
+        }
+
+        while node.state_receiver.try_recv().is_ok() {}
+    }
+
+    /// Records fork, solution range and confirmation metrics at the end of a timeslot
+    fn observe(&mut self, timeslot: u64) {
+        let metrics = &mut self.metrics;
+
+        for node in self.nodes.iter_mut() {
+            metrics.max_concurrent_heads =
+                metrics.max_concurrent_heads.max(node.ledger.heads.len());
+
+            let head = node.ledger.get_head();
+            if head != node.last_head {
+                let fork_depth = fork_depth(&node.ledger, node.last_head, head);
+                if fork_depth > 0 {
+                    debug!("Re-org of depth {} at timeslot {}", fork_depth, timeslot);
+                }
+                metrics.max_fork_depth = metrics.max_fork_depth.max(fork_depth);
+                node.last_head = head;
+            }
+        }
+
+        let solution_ranges: HashSet<u64> = self
+            .nodes
+            .iter()
+            .map(|node| node.ledger.current_solution_range)
+            .collect();
+        if solution_ranges.len() > 1 {
+            metrics.solution_range_disagreements += 1;
+        }
+
+        let observer = &self.nodes[self.observer].ledger;
+        let solution_range = observer.current_solution_range;
+        let last_solution_range = metrics
+            .solution_range_updates
+            .last()
+            .map(|(_, solution_range)| *solution_range)
+            .unwrap_or(INITIAL_SOLUTION_RANGE);
+        if solution_range != last_solution_range {
+            metrics
+                .solution_range_updates
+                .push((timeslot, solution_range));
+        }
+
+        // blocks at least `CONFIRMATION_DEPTH` below the head are confirmed
+        for (content_id, block_timeslot) in ancestors(observer, observer.get_head())
+            .into_iter()
+            .skip(CONFIRMATION_DEPTH)
+        {
+            if !metrics.confirmed.insert(content_id) {
+                break;
+            }
+            metrics
+                .confirmation_latencies
+                .push(timeslot - block_timeslot);
+        }
+    }
+
+    fn into_report(self) -> SimulationReport {
+        let observer = &self.nodes[self.observer].ledger;
+        let mut canonical_chain: HashSet<ContentId> = HashSet::new();
+        let mut next = Some(observer.get_head());
+        while let Some(content_id) = next {
+            match observer
+                .metablocks
+                .get_metablock_from_content_id_as_option(&content_id)
+            {
+                Some(metablock) => {
+                    canonical_chain.insert(content_id);
+                    next = metablock.block.content.parent_id;
+                }
+                None => break,
+            }
+        }
+
+        let orphaned_proposer_blocks = self
+            .metrics
+            .proposer_blocks
+            .iter()
+            .filter(|content_id| !canonical_chain.contains(*content_id))
+            .count() as u64;
+
+        SimulationReport {
+            timeslots: self.config.timeslots,
+            nodes: self.nodes.len(),
+            proposer_blocks: self.metrics.proposer_blocks.len() as u64,
+            tx_blocks: self.metrics.tx_blocks,
+            orphaned_proposer_blocks,
+            canonical_height: canonical_chain.len() as u64,
+            max_fork_depth: self.metrics.max_fork_depth,
+            max_concurrent_heads: self.metrics.max_concurrent_heads,
+            solution_range_updates: self.metrics.solution_range_updates,
+            solution_range_disagreements: self.metrics.solution_range_disagreements,
+            final_solution_ranges: self
+                .nodes
+                .iter()
+                .map(|node| node.ledger.current_solution_range)
+                .collect(),
+            confirmation_latencies: self.metrics.confirmation_latencies,
+        }
+    }
+}
+
+/// Returns (content id, timeslot) of a block and its ancestors, starting with the block itself
+fn ancestors(ledger: &Ledger, content_id: ContentId) -> Vec<(ContentId, u64)> {
+    let mut chain = Vec::new();
+    let mut next = Some(content_id);
+    while let Some(content_id) = next {
+        if chain.len() == MAX_CHAIN_WALK_DEPTH {
+            break;
+        }
+        match ledger
+            .metablocks
+            .get_metablock_from_content_id_as_option(&content_id)
+        {
+            Some(metablock) => {
+                chain.push((content_id, metablock.block.proof.timeslot));
+                next = metablock.block.content.parent_id;
+            }
+            None => break,
+        }
+    }
+    chain
+}
+
+/// Number of blocks of the old head's branch that are no longer on the new head's branch
+fn fork_depth(ledger: &Ledger, old_head: ContentId, new_head: ContentId) -> u64 {
+    let new_chain: HashSet<ContentId> = ancestors(ledger, new_head)
+        .into_iter()
+        .map(|(content_id, _)| content_id)
+        .collect();
+    let old_chain = ancestors(ledger, old_head);
+
+    old_chain
+        .iter()
+        .position(|(content_id, _)| new_chain.contains(content_id))
+        .unwrap_or(old_chain.len()) as u64
+}
+
+/// Runs a simulation to completion
+pub async fn run(config: SimulationConfig) -> SimulationReport {
+    Simulation::new(config).await.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[async_std::test]
+    async fn test_single_farmer() {
+        init();
+        let config = SimulationConfig {
+            timeslots: 2 * TIMESLOTS_PER_EPOCH,
+            farmers: vec![FarmerBehaviour::Honest],
+            ..SimulationConfig::default()
+        };
+
+        let report = run(config).await;
+
+        // This is probabilistic, but should be fine most of the time
+        assert!(report.proposer_blocks > 0);
+        assert!(report.canonical_height > 0);
+        assert_eq!(report.solution_range_disagreements, 0);
+    }
+
+    #[async_std::test]
+    async fn test_same_seed_same_outcome() {
+        init();
+        let config = SimulationConfig {
+            seed: 42,
+            timeslots: TIMESLOTS_PER_EPOCH,
+            farmers: vec![
+                FarmerBehaviour::Honest,
+                FarmerBehaviour::Withholding {
+                    release_after_timeslots: 2,
+                },
+            ],
+            ..SimulationConfig::default()
+        };
+
+        let first = run(config.clone()).await;
+        let second = run(config).await;
+
+        assert_eq!(first.proposer_blocks, second.proposer_blocks);
+        assert_eq!(first.tx_blocks, second.tx_blocks);
+        assert_eq!(
+            first.orphaned_proposer_blocks,
+            second.orphaned_proposer_blocks
+        );
+        assert_eq!(first.canonical_height, second.canonical_height);
+        assert_eq!(first.max_fork_depth, second.max_fork_depth);
+    }
+}
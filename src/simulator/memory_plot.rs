@@ -0,0 +1,89 @@
+use crate::plot::PieceWrite;
+use crate::state::PieceBundle;
+use crate::{crypto, sloth, NodeID, Piece, Salt, Tag};
+use std::collections::{BTreeMap, HashMap};
+
+/// In-memory stand-in for `Plot`, so simulated farmers need neither RocksDB nor a plot file
+pub struct MemoryPlot {
+    /// encoding and merkle proof by piece index
+    encodings: HashMap<u64, (Piece, Vec<u8>)>,
+    /// piece indices by tag (as a big endian integer)
+    tags: BTreeMap<u64, Vec<u64>>,
+    /// salt tags are committed to
+    salt: Salt,
+}
+
+impl MemoryPlot {
+    /// Encodes all pieces for a node id and derives their tags, the same way `plotter::plot` does
+    pub fn create(
+        node_id: NodeID,
+        piece_bundles: &[PieceBundle],
+        encoder: &dyn sloth::Encoder,
+    ) -> Self {
+        let writes = PieceWrite::encode_batch(encoder, node_id, piece_bundles.to_vec());
+
+        let mut plot = MemoryPlot {
+            encodings: HashMap::new(),
+            tags: BTreeMap::new(),
+            salt: crypto::genesis_salt(),
+        };
+
+        for write in writes {
+            plot.encodings
+                .insert(write.index, (write.encoding, write.merkle_proof));
+        }
+        plot.commit_tags();
+
+        plot
+    }
+
+    /// Salt tags are committed to
+    pub fn salt(&self) -> Salt {
+        self.salt
+    }
+
+    /// Replaces all tags with tags committed to `salt`, same as `Plot::recommit`
+    pub fn recommit(&mut self, salt: Salt) {
+        self.salt = salt;
+        self.commit_tags();
+    }
+
+    fn commit_tags(&mut self) {
+        self.tags.clear();
+        for (index, (encoding, _)) in self.encodings.iter() {
+            let tag = crypto::create_tag(encoding, self.salt);
+            self.tags
+                .entry(u64::from_be_bytes(tag))
+                .and_modify(|indices| indices.push(*index))
+                .or_insert(vec![*index]);
+        }
+    }
+
+    /// Reads the encoding and merkle proof for a piece index
+    pub fn read(&self, index: u64) -> Option<(Piece, Vec<u8>)> {
+        self.encodings.get(&index).cloned()
+    }
+
+    /// Returns all tags within ±range/2 of target (wrapping around), same as `Plot::find_by_range`
+    pub fn find_by_range(&self, target: Tag, range: u64) -> Vec<(Tag, u64)> {
+        let target = u64::from_be_bytes(target);
+        let (lower, is_lower_overflowed) = target.overflowing_sub(range / 2);
+        let (upper, is_upper_overflowed) = target.overflowing_add(range / 2);
+
+        let mut solutions: Vec<(Tag, u64)> = Vec::new();
+        let mut collect = |(tag, indices): (&u64, &Vec<u64>)| {
+            for index in indices.iter() {
+                solutions.push((tag.to_be_bytes(), *index));
+            }
+        };
+
+        if is_lower_overflowed || is_upper_overflowed {
+            self.tags.range(..=upper).for_each(&mut collect);
+            self.tags.range(lower..).for_each(&mut collect);
+        } else {
+            self.tags.range(lower..=upper).for_each(&mut collect);
+        }
+
+        solutions
+    }
+}
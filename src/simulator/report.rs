@@ -0,0 +1,134 @@
+use crate::{INITIAL_SOLUTION_RANGE, TIMESLOTS_PER_PROPOSER_BLOCK};
+use std::fmt;
+use std::fmt::Display;
+
+/// Consensus metrics collected over a single simulation run
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationReport {
+    /// number of timeslots simulated
+    pub timeslots: u64,
+    /// number of simulated nodes
+    pub nodes: usize,
+    /// proposer blocks created by all farmers (including withheld ones)
+    pub proposer_blocks: u64,
+    /// tx blocks created by all farmers (including withheld ones)
+    pub tx_blocks: u64,
+    /// proposer blocks that did not end up on the observer's longest chain
+    pub orphaned_proposer_blocks: u64,
+    /// height of the longest chain as seen by the observer node at the end of the run
+    pub canonical_height: u64,
+    /// deepest re-org seen by any node, in proposer blocks
+    pub max_fork_depth: u64,
+    /// largest number of heads any node was tracking at once
+    pub max_concurrent_heads: usize,
+    /// (timeslot, solution range) for every change seen by the observer node
+    pub solution_range_updates: Vec<(u64, u64)>,
+    /// number of timeslots in which nodes did not agree on the solution range
+    pub solution_range_disagreements: u64,
+    /// solution range of each node at the end of the run
+    pub final_solution_ranges: Vec<u64>,
+    /// timeslots between creation and confirmation for each block confirmed by the observer
+    pub confirmation_latencies: Vec<u64>,
+}
+
+impl SimulationReport {
+    /// Share of proposer blocks that were created but are not on the longest chain
+    pub fn orphan_rate(&self) -> f64 {
+        if self.proposer_blocks == 0 {
+            0f64
+        } else {
+            self.orphaned_proposer_blocks as f64 / self.proposer_blocks as f64
+        }
+    }
+
+    /// Observed timeslots per proposer block on the longest chain, compare with
+    /// `TIMESLOTS_PER_PROPOSER_BLOCK` to see how far the solution range is from converging
+    pub fn timeslots_per_proposer_block(&self) -> Option<f64> {
+        if self.canonical_height == 0 {
+            None
+        } else {
+            Some(self.timeslots as f64 / self.canonical_height as f64)
+        }
+    }
+
+    pub fn mean_confirmation_latency(&self) -> Option<f64> {
+        if self.confirmation_latencies.is_empty() {
+            None
+        } else {
+            Some(
+                self.confirmation_latencies.iter().sum::<u64>() as f64
+                    / self.confirmation_latencies.len() as f64,
+            )
+        }
+    }
+
+    pub fn max_confirmation_latency(&self) -> Option<u64> {
+        self.confirmation_latencies.iter().max().copied()
+    }
+}
+
+impl Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Simulated {} nodes for {} timeslots",
+            self.nodes, self.timeslots
+        )?;
+        writeln!(
+            f,
+            "Blocks created: {} proposer, {} tx",
+            self.proposer_blocks, self.tx_blocks
+        )?;
+        writeln!(
+            f,
+            "Orphan rate: {:.3} ({} of {} proposer blocks)",
+            self.orphan_rate(),
+            self.orphaned_proposer_blocks,
+            self.proposer_blocks
+        )?;
+        writeln!(f, "Longest chain height: {}", self.canonical_height)?;
+        writeln!(
+            f,
+            "Max fork depth: {}, max concurrent heads: {}",
+            self.max_fork_depth, self.max_concurrent_heads
+        )?;
+        match self.timeslots_per_proposer_block() {
+            Some(observed) => writeln!(
+                f,
+                "Timeslots per proposer block: {:.2} observed vs {} expected",
+                observed, TIMESLOTS_PER_PROPOSER_BLOCK
+            )?,
+            None => writeln!(
+                f,
+                "Timeslots per proposer block: no blocks on longest chain"
+            )?,
+        }
+        writeln!(
+            f,
+            "Solution range: initial {}, {} update(s), {} timeslot(s) with disagreement",
+            INITIAL_SOLUTION_RANGE,
+            self.solution_range_updates.len(),
+            self.solution_range_disagreements
+        )?;
+        for (timeslot, solution_range) in self.solution_range_updates.iter() {
+            writeln!(
+                f,
+                "  timeslot {}: solution range {}",
+                timeslot, solution_range
+            )?;
+        }
+        match (
+            self.mean_confirmation_latency(),
+            self.max_confirmation_latency(),
+        ) {
+            (Some(mean), Some(max)) => write!(
+                f,
+                "Confirmation latency: {:.2} timeslots mean, {} max ({} blocks)",
+                mean,
+                max,
+                self.confirmation_latencies.len()
+            ),
+            _ => write!(f, "Confirmation latency: no blocks confirmed"),
+        }
+    }
+}
@@ -1,319 +1,229 @@
-/// A pure rust implementation of Sloth with extensions for a proof-of-replication
+/// Sloth slow-encoding for a proof-of-replication
 /// https://eprint.iacr.org/2015/366
-/// based on pysloth C implementation by Mathias Michno
-/// https://github.com/randomchain/pysloth/blob/master/sloth.c
-use crate::{ExpandedIV, Piece, PIECE_SIZE};
+///
+/// Encoding and decoding go through the `Encoder` and `Decoder` traits, so that the arithmetic
+/// backend can be swapped without touching plotting or block validation.
+///
+/// The sqrt permutation is only defined for blocks smaller than the prime. When encoding a piece,
+/// a block that is not (after xor with its feedback) is left as it is, and decoding likewise
+/// leaves such blocks alone. Since the permutation maps `[0, prime)` onto itself, this makes
+/// encoding a bijection on all blocks, so any piece can be encoded. Only `2^256 - prime` (less
+/// than `2^12`) of all `2^256` blocks skip the permutation.
+use crate::{crypto, ExpandedIV, NodeID, Piece, PieceIndex, PRIME_SIZE_BITS};
 use rayon::prelude::*;
-use rug::ops::NegAssign;
-use rug::{integer::IsPrime, integer::Order, ops::BitXorFrom, Integer};
-use std::iter;
-use std::ops::{AddAssign, Deref};
+use static_assertions::const_assert_eq;
 
-/*  ToDo
- * Ensure complies for Windows (Nazar)
- * use a different prime for each block for additional ASIC resistance
- * implement for GPU in CUDA with CGBN
- * implement for GPU in OpenCL with ff-cl-gen
- * ensure correct number of levels are applied for security guarantee
- * should this also take an IV?
- *
- * test: data larger than prime should fail
- * test: hardcode in correct prime and ensure those are generated correctly (once prime is chosen)
-*/
+mod decoding_service;
+#[cfg(feature = "gmp")]
+mod gmp;
+mod montgomery;
 
-/// Finds the next smallest prime number
-fn prev_prime(prime: &mut Integer) {
-    if prime.is_even() {
-        *prime -= 1
-    } else {
-        *prime -= 2
-    }
-    while prime.is_probably_prime(25) == IsPrime::No {
-        *prime -= 2
-    }
-}
+pub use decoding_service::DecodingService;
+#[cfg(feature = "gmp")]
+pub use gmp::GmpSloth;
+pub use montgomery::Sloth256;
 
-/// Returns (block, feedback) tuple given block index in a piece
-fn piece_to_block_and_feedback(piece: &mut [Integer], index: usize) -> (&mut Integer, &Integer) {
-    let (ends_with_feedback, starts_with_block) = piece.split_at_mut(index);
-    let feedback = &ends_with_feedback[ends_with_feedback.len() - 1];
-    (&mut starts_with_block[0], &feedback)
-}
+/// Backend used by the node for plotting and validation
+pub type Sloth = Sloth256;
 
-/// Returns (block, feedback) tuple given piece and optional feedback
-fn piece_to_first_block_and_feedback(piece: &mut [Integer]) -> (&mut Integer, &Integer) {
-    let (first_block, remainder) = piece.split_at_mut(1);
-    // At this point last block is already decoded, so we can use it as an IV to previous iteration
-    let iv = &remainder[remainder.len() - 1];
-    (&mut first_block[0], &iv)
-}
-
-/// Converts a 4096 byte piece from an array of GMP big integers back to raw bytes
-fn write_integers_to_array(integer_piece: &[Integer], piece: &mut [u8], block_size_bytes: usize) {
-    integer_piece
-        .iter()
-        .flat_map(|integer| {
-            let integer_bytes = integer.to_digits::<u8>(Order::Lsf);
-            let integer_bytes_len = integer_bytes.len();
-            integer_bytes
-                .into_iter()
-                .chain(iter::repeat(0).take(block_size_bytes - integer_bytes_len))
-        })
-        .zip(piece.iter_mut())
-        .for_each(|(from_byte, to_byte)| {
-            *to_byte = from_byte;
-        });
-}
+// `Sloth256` only supports 256-bit primes
+const_assert_eq!(PRIME_SIZE_BITS, 256);
 
 #[derive(Debug)]
 pub struct DataBiggerThanPrime;
 
-enum Feedback<'a> {
-    Iv(&'a Integer),
-    Block(Integer),
+/// Versions of how pieces are encoded, every node has to use the same one to validate blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingScheme {
+    /// Version 0: all pieces of a node share the IV of its node id, a single prime
+    NodeIv,
+    /// Version 1: IV of node id xor piece index, so identical pieces encode differently, a single
+    /// prime
+    PieceIv,
+    /// Version 2: IV of node id xor piece index, blocks cycle through a schedule of primes
+    PieceIvPrimeSchedule,
 }
 
-impl<'a> Deref for Feedback<'a> {
-    type Target = Integer;
-
-    fn deref(&self) -> &Self::Target {
+impl EncodingScheme {
+    pub fn version(self) -> u16 {
         match self {
-            Feedback::Iv(integer) => integer,
-            Feedback::Block(integer) => integer,
+            EncodingScheme::NodeIv => 0,
+            EncodingScheme::PieceIv => 1,
+            EncodingScheme::PieceIvPrimeSchedule => 2,
         }
     }
-}
-
-pub struct Sloth {
-    pub block_size_bits: usize,
-    pub block_size_bytes: usize,
-    prime: Integer,
-    exponent: Integer,
-}
-
-impl Sloth {
-    /// Inits sloth for a given prime size, deterministically deriving the largest prime and computing the exponent
-    pub fn init(bits: usize) -> Self {
-        let block_size_bits = bits;
-        let block_size_bytes = bits / 8;
 
-        let mut prime: Integer = Integer::from(Integer::u_pow_u(2, bits as u32)) - 1;
-        prev_prime(&mut prime);
-        while prime.mod_u(4) != 3 {
-            prev_prime(&mut prime)
-        }
-
-        let mut exponent: Integer = prime.clone() + 1;
-        exponent.div_exact_u_mut(4);
-
-        Self {
-            block_size_bits,
-            block_size_bytes,
-            prime,
-            exponent,
+    pub fn from_version(version: u16) -> Option<Self> {
+        match version {
+            0 => Some(EncodingScheme::NodeIv),
+            1 => Some(EncodingScheme::PieceIv),
+            2 => Some(EncodingScheme::PieceIvPrimeSchedule),
+            _ => None,
         }
     }
 
-    /// Computes the modular square root of data, for data smaller than prime (w.h.p.)
-    pub fn sqrt_permutation(&self, data: &mut Integer) -> Result<(), DataBiggerThanPrime> {
-        // better error handling
-        if data.as_ref() >= self.prime.as_ref() {
-            return Err(DataBiggerThanPrime);
-        }
-
-        if data.jacobi(&self.prime) == 1 {
-            data.pow_mod_mut(&self.exponent, &self.prime).unwrap();
-            if data.is_odd() {
-                data.neg_assign();
-                data.add_assign(&self.prime);
-            }
-        } else {
-            data.neg_assign();
-            data.add_assign(&self.prime);
-            data.pow_mod_mut(&self.exponent, &self.prime).unwrap();
-            if data.is_even() {
-                data.neg_assign();
-                data.add_assign(&self.prime);
+    /// Expanded IV the piece at `piece_index` is encoded with for `node_id`
+    pub fn expanded_iv(self, node_id: NodeID, piece_index: PieceIndex) -> ExpandedIV {
+        match self {
+            EncodingScheme::NodeIv => crypto::expand_iv(node_id),
+            EncodingScheme::PieceIv | EncodingScheme::PieceIvPrimeSchedule => {
+                crypto::expand_iv(crypto::piece_iv(node_id, piece_index))
             }
         }
-
-        Ok(())
-    }
-
-    /// Inverts the sqrt permutation with a single squaring mod prime
-    pub fn inverse_sqrt(&self, data: &mut Integer) {
-        let is_odd = data.is_odd();
-        data.square_mut();
-        data.pow_mod_mut(&Integer::from(1), &self.prime).unwrap();
-        if is_odd {
-            data.neg_assign();
-            data.add_assign(&self.prime);
-        }
     }
 
-    /// Sequentially encodes a 4096 byte piece s.t. a minimum amount of wall clock time elapses
-    pub fn encode(
-        &self,
-        piece: &mut Piece,
-        expanded_iv: &Integer,
-        layers: usize,
-    ) -> Result<(), DataBiggerThanPrime> {
-        // convert piece to integer representation
-        let mut integer_piece: Vec<Integer> = piece
-            .chunks_exact(self.block_size_bytes)
-            .map(|block| Integer::from_digits(&block, Order::Lsf))
-            .collect();
-
-        // init feedback as expanded IV
-        let mut feedback = Feedback::Iv(expanded_iv);
-
-        // apply the block cipher
-        for _ in 0..layers {
-            for block in integer_piece.iter_mut() {
-                // xor block with feedback
-                block.bitxor_from(feedback.deref());
-
-                // apply sqrt permutation
-                self.sqrt_permutation(block)?;
-
-                // carry forward the feedback
-                feedback = Feedback::Block(block.clone());
-            }
+    /// Encoder and decoder with the primes of this scheme
+    pub fn sloth(self) -> Sloth {
+        match self {
+            EncodingScheme::NodeIv | EncodingScheme::PieceIv => Sloth::init(),
+            EncodingScheme::PieceIvPrimeSchedule => Sloth::with_prime_schedule(),
         }
-
-        // transform integers back to bytes
-        write_integers_to_array(&integer_piece, piece, self.block_size_bytes);
-
-        Ok(())
     }
+}
 
-    /// Sequentially decodes a 4096 byte encoding in time << encode time
-    pub fn decode(&self, piece: &mut [u8], expanded_iv: ExpandedIV, layers: usize) {
-        // convert encoding to integer representation
-        let mut integer_piece: Vec<Integer> = piece
-            .chunks_exact(self.block_size_bytes)
-            .map(|block| Integer::from_digits(&block, Order::Lsf))
-            .collect();
-
-        for layer in 0..layers {
-            for i in (1..(PIECE_SIZE / self.block_size_bytes)).rev() {
-                let (block, feedback) = piece_to_block_and_feedback(&mut integer_piece, i);
-                self.inverse_sqrt(block);
-                block.bitxor_from(feedback);
-            }
-            let (block, feedback) = piece_to_first_block_and_feedback(&mut integer_piece);
-            self.inverse_sqrt(block);
-            if layer != layers - 1 {
-                block.bitxor_from(feedback);
-            }
-        }
-
-        // remove the IV (last round)
-        integer_piece[0].bitxor_from(&Integer::from_digits(&expanded_iv, Order::Lsf));
-
-        // transform integers back to bytes
-        write_integers_to_array(&integer_piece, piece, self.block_size_bytes);
+/// Slow direction of Sloth, used to plot pieces
+pub trait Encoder: Send + Sync {
+    /// Applies the sqrt permutation to a single little endian block, which must be smaller than
+    /// the prime
+    fn encode_block(&self, block: &mut [u8]) -> Result<(), DataBiggerThanPrime>;
+
+    /// Sequentially encodes a 4096 byte piece s.t. a minimum amount of wall clock time elapses,
+    /// blocks not smaller than the prime are left as they are
+    fn encode(&self, piece: &mut Piece, expanded_iv: ExpandedIV, layers: usize);
+
+    /// Encodes many pieces, each with its own IV, in parallel on all cores
+    fn encode_batch(&self, pieces: &mut [Piece], expanded_ivs: &[ExpandedIV], layers: usize) {
+        pieces
+            .par_iter_mut()
+            .zip(expanded_ivs)
+            .for_each(|(piece, &expanded_iv)| self.encode(piece, expanded_iv, layers));
     }
+}
 
-    /// Decodes a 4096 byte encoding in parallel in time << encode time
-    pub fn decode_parallel(&self, piece: &mut Piece, expanded_iv: ExpandedIV, layers: usize) {
-        // convert encoding to integer representation
-        let mut integer_piece: Vec<Integer> = piece
-            .chunks_exact(self.block_size_bytes)
-            .map(|block| Integer::from_digits(&block, Order::Lsf))
-            .collect();
-
-        for layer in 0..layers {
-            let integer_piece_copy = integer_piece.clone();
-            integer_piece
-                .iter_mut()
-                .skip(1)
-                .rev()
-                .zip(integer_piece_copy.iter().rev().skip(1))
-                .par_bridge()
-                .for_each(|(block, feedback)| {
-                    self.inverse_sqrt(block);
-                    block.bitxor_from(feedback);
-                });
-
-            let (block, feedback) = piece_to_first_block_and_feedback(&mut integer_piece);
-            self.inverse_sqrt(block);
-            if layer != layers - 1 {
-                block.bitxor_from(feedback);
-            }
-        }
-
-        // remove the IV (last round)
-        integer_piece[0].bitxor_from(&Integer::from_digits(&expanded_iv, Order::Lsf));
+/// Fast direction of Sloth, used to verify encodings
+pub trait Decoder: Send + Sync {
+    /// Inverts `Encoder::encode_block` for a single little endian block, blocks not smaller than
+    /// the prime are left as they are
+    fn decode_block(&self, block: &mut [u8]);
 
-        // transform integers back to bytes
-        write_integers_to_array(&integer_piece, piece, self.block_size_bytes);
+    /// Sequentially decodes a 4096 byte encoding in time << encode time
+    fn decode(&self, piece: &mut [u8], expanded_iv: ExpandedIV, layers: usize);
+
+    /// Decodes many encodings, each with its own IV, in parallel on all cores
+    fn decode_batch(&self, pieces: &mut [Piece], expanded_ivs: &[ExpandedIV], layers: usize) {
+        pieces
+            .par_iter_mut()
+            .zip(expanded_ivs)
+            .for_each(|(piece, &expanded_iv)| self.decode(piece, expanded_iv, layers));
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::crypto;
+    use crate::{ENCODING_LAYERS_TEST, PIECE_SIZE, PRIME_SIZE_BYTES};
 
     #[test]
-    fn test_random_data_for_all_primes() {
-        use rug::{rand::RandState, Integer};
-        use std::time::{SystemTime, UNIX_EPOCH};
-
-        for &bits in [256, 512, 1024, 2048, 4096].iter() {
-            let seed = Integer::from(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis(),
-            );
-            let mut rand = RandState::new();
-            rand.seed(&seed);
-            let data = Integer::from(Integer::random_bits(bits, &mut rand));
-            let sloth = Sloth::init(bits as usize);
-            let mut encoding = data.clone();
-            sloth.sqrt_permutation(&mut encoding).unwrap();
-            let mut decoding = encoding.clone();
-            sloth.inverse_sqrt(&mut decoding);
+    fn test_batch_matches_single_pieces() {
+        let sloth = Sloth::init();
+        let node_id = crypto::random_bytes_32();
+        let pieces: Vec<Piece> = (0..4).map(|_| crypto::generate_random_piece()).collect();
+        let expanded_ivs: Vec<ExpandedIV> = (0..4)
+            .map(|piece_index| EncodingScheme::PieceIv.expanded_iv(node_id, piece_index))
+            .collect();
 
-            println!("For prime and data of size {}", bits);
-            println!("Prime: {}", sloth.prime.to_string_radix(10));
-            println!("Data: {}", data.to_string_radix(10));
-            println!("Encoding: {}", encoding.to_string_radix(10));
-            println!("Decoding: {}\n\n", decoding.to_string_radix(10));
+        let mut encodings = pieces.clone();
+        sloth.encode_batch(&mut encodings, &expanded_ivs, ENCODING_LAYERS_TEST);
+        for ((piece, encoding), &expanded_iv) in
+            pieces.iter().zip(encodings.iter()).zip(expanded_ivs.iter())
+        {
+            let mut single_encoding = *piece;
+            sloth.encode(&mut single_encoding, expanded_iv, ENCODING_LAYERS_TEST);
+            assert_eq!(single_encoding.to_vec(), encoding.to_vec());
+        }
 
-            assert_eq!(&data, &decoding);
+        let mut decodings = encodings.clone();
+        sloth.decode_batch(&mut decodings, &expanded_ivs, ENCODING_LAYERS_TEST);
+        for (piece, decoding) in pieces.iter().zip(decodings.iter()) {
+            assert_eq!(piece.to_vec(), decoding.to_vec());
         }
     }
 
     #[test]
-    fn test_random_piece_for_all_primes() {
-        let iv = crypto::random_bytes_32();
-        let expanded_iv = crypto::expand_iv(iv);
-        let integer_expanded_iv = Integer::from_digits(&expanded_iv, Order::Lsf);
-
-        for &bits in [256, 512, 1024, 2048, 4096].iter() {
-            let piece = crypto::generate_random_piece();
-            let sloth = Sloth::init(bits);
-            let layers = PIECE_SIZE / sloth.block_size_bytes;
-            let mut encoding = piece.clone();
-            sloth
-                .encode(&mut encoding, &integer_expanded_iv, layers)
-                .unwrap();
-            let mut decoding = encoding.clone();
-            sloth.decode(&mut decoding, expanded_iv, layers);
+    fn test_block_round_trip() {
+        let sloth = Sloth::init();
+        let data = crypto::random_bytes_32();
+        let mut block = data;
+        // clear the top bit, so that the block is smaller than the prime
+        block[block.len() - 1] &= 0x7f;
+        let data = block;
+
+        sloth.encode_block(&mut block).unwrap();
+        assert_ne!(data, block);
+        sloth.decode_block(&mut block);
+        assert_eq!(data, block);
+    }
 
-            // println!("\nPiece is {:?}\n", piece.to_vec());
-            // println!("\nDecoding is {:?}\n", decoding.to_vec());
-            // println!("\nEncoding is {:?}\n", encoding.to_vec());
+    #[test]
+    fn test_encoding_schemes() {
+        let node_id = crypto::random_bytes_32();
+        let piece = crypto::generate_random_piece();
+
+        for &scheme in [
+            EncodingScheme::NodeIv,
+            EncodingScheme::PieceIv,
+            EncodingScheme::PieceIvPrimeSchedule,
+        ]
+        .iter()
+        {
+            assert_eq!(Some(scheme), EncodingScheme::from_version(scheme.version()));
+
+            let sloth = scheme.sloth();
+            let encodings: Vec<Piece> = (0..2)
+                .map(|piece_index| {
+                    let mut encoding = piece;
+                    sloth.encode(
+                        &mut encoding,
+                        scheme.expanded_iv(node_id, piece_index),
+                        ENCODING_LAYERS_TEST,
+                    );
+                    encoding
+                })
+                .collect();
+
+            // only the node IV scheme encodes the same piece at different indices the same way
+            assert_eq!(
+                scheme == EncodingScheme::NodeIv,
+                encodings[0].to_vec() == encodings[1].to_vec()
+            );
 
+            let mut decoding = encodings[1];
+            sloth.decode(
+                &mut decoding,
+                scheme.expanded_iv(node_id, 1),
+                ENCODING_LAYERS_TEST,
+            );
             assert_eq!(piece.to_vec(), decoding.to_vec());
+        }
 
-            let mut decoding = encoding.clone();
-            sloth.decode_parallel(&mut decoding, expanded_iv, layers);
+        assert_eq!(None, EncodingScheme::from_version(3));
+    }
 
-            assert_eq!(piece.to_vec(), decoding.to_vec());
+    #[test]
+    fn test_piece_with_blocks_bigger_than_prime() {
+        // with a zero IV, the all ones first block is bigger than every prime and is left as it
+        // is, so the second block is xored down to zero, and so on
+        let piece = [0xffu8; PIECE_SIZE];
+        let expanded_iv = [0u8; PRIME_SIZE_BYTES];
+
+        for sloth in [Sloth::init(), Sloth::with_prime_schedule()].iter() {
+            for &layers in [1, ENCODING_LAYERS_TEST].iter() {
+                let mut encoding = piece;
+                sloth.encode(&mut encoding, expanded_iv, layers);
+                let mut decoding = encoding;
+                sloth.decode(&mut decoding, expanded_iv, layers);
+                assert_eq!(piece.to_vec(), decoding.to_vec());
+            }
         }
     }
 }
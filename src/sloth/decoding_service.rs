@@ -0,0 +1,125 @@
+use super::Decoder;
+use crate::{crypto, ExpandedIV, PieceId};
+use futures::channel::oneshot;
+use rayon::prelude::*;
+use std::sync::Arc;
+
+/// Decodes encodings in batches on a dedicated thread pool, so that validating blocks and synced
+/// pieces uses all cores without blocking the async executor
+#[derive(Clone)]
+pub struct DecodingService {
+    decoder: Arc<dyn Decoder>,
+    layers: usize,
+    pool: Arc<rayon::ThreadPool>,
+}
+
+impl DecodingService {
+    /// Starts a pool with one thread per core, decoding with `layers` layers
+    pub fn new(decoder: Arc<dyn Decoder>, layers: usize) -> Self {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .thread_name(|index| format!("sloth-decoder-{}", index))
+            .build()
+            .expect("Failed to start the decoding thread pool");
+
+        DecodingService {
+            decoder,
+            layers,
+            pool: Arc::new(pool),
+        }
+    }
+
+    /// Decodes each encoding with its expanded IV, results are in the same order
+    pub async fn decode(&self, encodings: Vec<(Vec<u8>, ExpandedIV)>) -> Vec<Vec<u8>> {
+        let decoder = Arc::clone(&self.decoder);
+        let layers = self.layers;
+        self.run(move || {
+            encodings
+                .into_par_iter()
+                .map(|(mut encoding, expanded_iv)| {
+                    decoder.decode(&mut encoding, expanded_iv, layers);
+                    encoding
+                })
+                .collect()
+        })
+        .await
+    }
+
+    /// Decodes each encoding with its expanded IV and returns the hashes of the decoded pieces, in
+    /// the same order
+    pub async fn piece_hashes(&self, encodings: Vec<(Vec<u8>, ExpandedIV)>) -> Vec<PieceId> {
+        let decoder = Arc::clone(&self.decoder);
+        let layers = self.layers;
+        self.run(move || {
+            encodings
+                .into_par_iter()
+                .map(|(mut encoding, expanded_iv)| {
+                    decoder.decode(&mut encoding, expanded_iv, layers);
+                    crypto::digest_sha_256(&encoding)
+                })
+                .collect()
+        })
+        .await
+    }
+
+    /// Hash of a single decoded piece
+    pub async fn piece_hash(&self, encoding: Vec<u8>, expanded_iv: ExpandedIV) -> PieceId {
+        self.piece_hashes(vec![(encoding, expanded_iv)]).await[0]
+    }
+
+    /// Runs `job` on the pool and waits for its result without blocking the executor
+    async fn run<T, F>(&self, job: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.pool.spawn(move || {
+            // the receiver is only gone if the waiting task was dropped
+            let _ = sender.send(job());
+        });
+        receiver.await.expect("Decoding thread pool dropped a job")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sloth::{Encoder, EncodingScheme, Sloth};
+    use crate::{Piece, ENCODING_LAYERS_TEST};
+    use futures::executor;
+
+    #[test]
+    fn test_piece_hashes_match_single_decodings() {
+        let sloth = Sloth::init();
+        let node_id = crypto::random_bytes_32();
+        let pieces: Vec<Piece> = (0..4).map(|_| crypto::generate_random_piece()).collect();
+        let encodings: Vec<(Vec<u8>, ExpandedIV)> = pieces
+            .iter()
+            .enumerate()
+            .map(|(piece_index, piece)| {
+                let expanded_iv = EncodingScheme::PieceIv.expanded_iv(node_id, piece_index as u64);
+                let mut encoding = *piece;
+                sloth.encode(&mut encoding, expanded_iv, ENCODING_LAYERS_TEST);
+                (encoding.to_vec(), expanded_iv)
+            })
+            .collect();
+
+        let service = DecodingService::new(Arc::new(sloth), ENCODING_LAYERS_TEST);
+        executor::block_on(async {
+            let decodings = service.decode(encodings.clone()).await;
+            let piece_hashes = service.piece_hashes(encodings.clone()).await;
+            for ((piece, decoding), piece_hash) in
+                pieces.iter().zip(decodings.iter()).zip(piece_hashes.iter())
+            {
+                assert_eq!(piece.to_vec(), *decoding);
+                assert_eq!(crypto::digest_sha_256(piece), *piece_hash);
+            }
+
+            let (encoding, expanded_iv) = encodings[0].clone();
+            assert_eq!(
+                piece_hashes[0],
+                service.piece_hash(encoding, expanded_iv).await
+            );
+        });
+    }
+}
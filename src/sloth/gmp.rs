@@ -0,0 +1,457 @@
+/// Sloth backend on GMP big integers (through rug), works for any prime size
+/// based on pysloth C implementation by Mathias Michno
+/// https://github.com/randomchain/pysloth/blob/master/sloth.c
+use super::{DataBiggerThanPrime, Decoder, Encoder};
+use crate::{ExpandedIV, Piece, PIECE_SIZE};
+use rayon::prelude::*;
+use rug::ops::NegAssign;
+use rug::{integer::Order, ops::BitXorFrom, Integer};
+use std::iter;
+use std::ops::{AddAssign, Deref};
+
+/*  ToDo
+ * Ensure complies for Windows (Nazar)
+ * implement for GPU in CUDA with CGBN
+ * implement for GPU in OpenCL with ff-cl-gen
+ * should this also take an IV?
+*/
+
+// Largest primes 2^bits - offset that are 3 mod 4 for each supported prime size and their
+// exponents (prime + 1) / 4, in hex, as confirmed by `test_primes`
+const PRIME_256: &str = "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff43";
+const EXPONENT_256: &str = "3fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffd1";
+const PRIME_512: &str = concat!(
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffdc7",
+);
+const EXPONENT_512: &str = concat!(
+    "3fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff72",
+);
+const PRIME_1024: &str = concat!(
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff97",
+);
+const EXPONENT_1024: &str = concat!(
+    "3fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe6",
+);
+const PRIME_2048: &str = concat!(
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff9eb",
+);
+const EXPONENT_2048: &str = concat!(
+    "3fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe7b",
+);
+const PRIME_4096: &str = concat!(
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff60b",
+);
+const EXPONENT_4096: &str = concat!(
+    "3fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffd83",
+);
+
+/// Supported prime sizes in bits with their prime and exponent (prime + 1) / 4
+const PRIMES: [(usize, &str, &str); 5] = [
+    (256, PRIME_256, EXPONENT_256),
+    (512, PRIME_512, EXPONENT_512),
+    (1024, PRIME_1024, EXPONENT_1024),
+    (2048, PRIME_2048, EXPONENT_2048),
+    (4096, PRIME_4096, EXPONENT_4096),
+];
+
+/// Returns (block, feedback) tuple given block index in a piece
+fn piece_to_block_and_feedback(piece: &mut [Integer], index: usize) -> (&mut Integer, &Integer) {
+    let (ends_with_feedback, starts_with_block) = piece.split_at_mut(index);
+    let feedback = &ends_with_feedback[ends_with_feedback.len() - 1];
+    (&mut starts_with_block[0], &feedback)
+}
+
+/// Returns (block, feedback) tuple given piece and optional feedback
+fn piece_to_first_block_and_feedback(piece: &mut [Integer]) -> (&mut Integer, &Integer) {
+    let (first_block, remainder) = piece.split_at_mut(1);
+    // At this point last block is already decoded, so we can use it as an IV to previous iteration
+    let iv = &remainder[remainder.len() - 1];
+    (&mut first_block[0], &iv)
+}
+
+/// Converts a 4096 byte piece from an array of GMP big integers back to raw bytes
+fn write_integers_to_array(integer_piece: &[Integer], piece: &mut [u8], block_size_bytes: usize) {
+    integer_piece
+        .iter()
+        .flat_map(|integer| {
+            let integer_bytes = integer.to_digits::<u8>(Order::Lsf);
+            let integer_bytes_len = integer_bytes.len();
+            integer_bytes
+                .into_iter()
+                .chain(iter::repeat(0).take(block_size_bytes - integer_bytes_len))
+        })
+        .zip(piece.iter_mut())
+        .for_each(|(from_byte, to_byte)| {
+            *to_byte = from_byte;
+        });
+}
+
+enum Feedback<'a> {
+    Iv(&'a Integer),
+    Block(Integer),
+}
+
+impl<'a> Deref for Feedback<'a> {
+    type Target = Integer;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Feedback::Iv(integer) => integer,
+            Feedback::Block(integer) => integer,
+        }
+    }
+}
+
+pub struct GmpSloth {
+    pub block_size_bits: usize,
+    pub block_size_bytes: usize,
+    prime: Integer,
+    exponent: Integer,
+}
+
+impl GmpSloth {
+    /// Inits sloth for a supported prime size with its precomputed prime and exponent
+    pub fn init(bits: usize) -> Self {
+        let block_size_bits = bits;
+        let block_size_bytes = bits / 8;
+
+        let (prime, exponent) = PRIMES
+            .iter()
+            .find(|(prime_size, _, _)| *prime_size == bits)
+            .map(|(_, prime, exponent)| {
+                (
+                    Integer::from_str_radix(prime, 16).expect("Prime is valid hex"),
+                    Integer::from_str_radix(exponent, 16).expect("Exponent is valid hex"),
+                )
+            })
+            .unwrap_or_else(|| panic!("Unsupported prime size of {} bits", bits));
+
+        Self {
+            block_size_bits,
+            block_size_bytes,
+            prime,
+            exponent,
+        }
+    }
+
+    /// Computes the modular square root of data, for data smaller than prime (w.h.p.)
+    pub fn sqrt_permutation(&self, data: &mut Integer) -> Result<(), DataBiggerThanPrime> {
+        // better error handling
+        if data.as_ref() >= self.prime.as_ref() {
+            return Err(DataBiggerThanPrime);
+        }
+
+        // zero is a square (of itself), so that the permutation maps `[0, prime)` onto itself
+        if data.jacobi(&self.prime) != -1 {
+            data.pow_mod_mut(&self.exponent, &self.prime).unwrap();
+            if data.is_odd() {
+                data.neg_assign();
+                data.add_assign(&self.prime);
+            }
+        } else {
+            data.neg_assign();
+            data.add_assign(&self.prime);
+            data.pow_mod_mut(&self.exponent, &self.prime).unwrap();
+            if data.is_even() {
+                data.neg_assign();
+                data.add_assign(&self.prime);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sqrt permutation for data smaller than prime, leaves other data as it is
+    fn permute(&self, data: &mut Integer) {
+        if data.as_ref() < self.prime.as_ref() {
+            self.sqrt_permutation(data)
+                .expect("Data is smaller than prime");
+        }
+    }
+
+    /// Inverts the sqrt permutation with a single squaring mod prime, leaves data bigger than
+    /// prime as it is
+    pub fn inverse_sqrt(&self, data: &mut Integer) {
+        if data.as_ref() >= self.prime.as_ref() {
+            return;
+        }
+
+        let is_odd = data.is_odd();
+        data.square_mut();
+        data.pow_mod_mut(&Integer::from(1), &self.prime).unwrap();
+        if is_odd {
+            data.neg_assign();
+            data.add_assign(&self.prime);
+        }
+    }
+
+    /// Decodes a 4096 byte encoding in parallel in time << encode time
+    pub fn decode_parallel(&self, piece: &mut Piece, expanded_iv: ExpandedIV, layers: usize) {
+        // convert encoding to integer representation
+        let mut integer_piece: Vec<Integer> = piece
+            .chunks_exact(self.block_size_bytes)
+            .map(|block| Integer::from_digits(&block, Order::Lsf))
+            .collect();
+
+        for layer in 0..layers {
+            let integer_piece_copy = integer_piece.clone();
+            integer_piece
+                .iter_mut()
+                .skip(1)
+                .rev()
+                .zip(integer_piece_copy.iter().rev().skip(1))
+                .par_bridge()
+                .for_each(|(block, feedback)| {
+                    self.inverse_sqrt(block);
+                    block.bitxor_from(feedback);
+                });
+
+            let (block, feedback) = piece_to_first_block_and_feedback(&mut integer_piece);
+            self.inverse_sqrt(block);
+            if layer != layers - 1 {
+                block.bitxor_from(feedback);
+            }
+        }
+
+        // remove the IV (last round)
+        integer_piece[0].bitxor_from(&Integer::from_digits(&expanded_iv, Order::Lsf));
+
+        // transform integers back to bytes
+        write_integers_to_array(&integer_piece, piece, self.block_size_bytes);
+    }
+}
+
+impl Encoder for GmpSloth {
+    fn encode_block(&self, block: &mut [u8]) -> Result<(), DataBiggerThanPrime> {
+        let mut integer = Integer::from_digits(block, Order::Lsf);
+        self.sqrt_permutation(&mut integer)?;
+        let block_size_bytes = block.len();
+        write_integers_to_array(&[integer], block, block_size_bytes);
+
+        Ok(())
+    }
+
+    fn encode(&self, piece: &mut Piece, expanded_iv: ExpandedIV, layers: usize) {
+        let expanded_iv = Integer::from_digits(&expanded_iv, Order::Lsf);
+
+        // convert piece to integer representation
+        let mut integer_piece: Vec<Integer> = piece
+            .chunks_exact(self.block_size_bytes)
+            .map(|block| Integer::from_digits(&block, Order::Lsf))
+            .collect();
+
+        // init feedback as expanded IV
+        let mut feedback = Feedback::Iv(&expanded_iv);
+
+        // apply the block cipher
+        for _ in 0..layers {
+            for block in integer_piece.iter_mut() {
+                // xor block with feedback
+                block.bitxor_from(feedback.deref());
+
+                // apply sqrt permutation
+                self.permute(block);
+
+                // carry forward the feedback
+                feedback = Feedback::Block(block.clone());
+            }
+        }
+
+        // transform integers back to bytes
+        write_integers_to_array(&integer_piece, piece, self.block_size_bytes);
+    }
+}
+
+impl Decoder for GmpSloth {
+    fn decode_block(&self, block: &mut [u8]) {
+        let mut integer = Integer::from_digits(block, Order::Lsf);
+        self.inverse_sqrt(&mut integer);
+        let block_size_bytes = block.len();
+        write_integers_to_array(&[integer], block, block_size_bytes);
+    }
+
+    fn decode(&self, piece: &mut [u8], expanded_iv: ExpandedIV, layers: usize) {
+        // convert encoding to integer representation
+        let mut integer_piece: Vec<Integer> = piece
+            .chunks_exact(self.block_size_bytes)
+            .map(|block| Integer::from_digits(&block, Order::Lsf))
+            .collect();
+
+        for layer in 0..layers {
+            for i in (1..(PIECE_SIZE / self.block_size_bytes)).rev() {
+                let (block, feedback) = piece_to_block_and_feedback(&mut integer_piece, i);
+                self.inverse_sqrt(block);
+                block.bitxor_from(feedback);
+            }
+            let (block, feedback) = piece_to_first_block_and_feedback(&mut integer_piece);
+            self.inverse_sqrt(block);
+            if layer != layers - 1 {
+                block.bitxor_from(feedback);
+            }
+        }
+
+        // remove the IV (last round)
+        integer_piece[0].bitxor_from(&Integer::from_digits(&expanded_iv, Order::Lsf));
+
+        // transform integers back to bytes
+        write_integers_to_array(&integer_piece, piece, self.block_size_bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use rug::integer::IsPrime;
+
+    /// Finds the next smallest prime number
+    fn prev_prime(prime: &mut Integer) {
+        if prime.is_even() {
+            *prime -= 1
+        } else {
+            *prime -= 2
+        }
+        while prime.is_probably_prime(25) == IsPrime::No {
+            *prime -= 2
+        }
+    }
+
+    #[test]
+    fn test_primes() {
+        for &(bits, _, _) in PRIMES.iter() {
+            // search for the largest prime that is 3 mod 4
+            let mut prime: Integer = Integer::from(Integer::u_pow_u(2, bits as u32)) - 1;
+            prev_prime(&mut prime);
+            while prime.mod_u(4) != 3 {
+                prev_prime(&mut prime)
+            }
+
+            let sloth = GmpSloth::init(bits);
+            assert_eq!(prime, sloth.prime);
+            assert_eq!(Integer::from(&sloth.exponent * 4), prime + 1);
+        }
+    }
+
+    #[test]
+    fn test_random_data_for_all_primes() {
+        use rug::{rand::RandState, Integer};
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        for &bits in [256, 512, 1024, 2048, 4096].iter() {
+            let seed = Integer::from(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis(),
+            );
+            let mut rand = RandState::new();
+            rand.seed(&seed);
+            let data = Integer::from(Integer::random_bits(bits, &mut rand));
+            let sloth = GmpSloth::init(bits as usize);
+            let mut encoding = data.clone();
+            sloth.sqrt_permutation(&mut encoding).unwrap();
+            let mut decoding = encoding.clone();
+            sloth.inverse_sqrt(&mut decoding);
+
+            println!("For prime and data of size {}", bits);
+            println!("Prime: {}", sloth.prime.to_string_radix(10));
+            println!("Data: {}", data.to_string_radix(10));
+            println!("Encoding: {}", encoding.to_string_radix(10));
+            println!("Decoding: {}\n\n", decoding.to_string_radix(10));
+
+            assert_eq!(&data, &decoding);
+        }
+    }
+
+    #[test]
+    fn test_random_piece_for_all_primes() {
+        let iv = crypto::random_bytes_32();
+        let expanded_iv = crypto::expand_iv(iv);
+
+        for &bits in [256, 512, 1024, 2048, 4096].iter() {
+            let piece = crypto::generate_random_piece();
+            let sloth = GmpSloth::init(bits);
+            let layers = PIECE_SIZE / sloth.block_size_bytes;
+            let mut encoding = piece.clone();
+            sloth.encode(&mut encoding, expanded_iv, layers);
+            let mut decoding = encoding.clone();
+            sloth.decode(&mut decoding, expanded_iv, layers);
+
+            // println!("\nPiece is {:?}\n", piece.to_vec());
+            // println!("\nDecoding is {:?}\n", decoding.to_vec());
+            // println!("\nEncoding is {:?}\n", encoding.to_vec());
+
+            assert_eq!(piece.to_vec(), decoding.to_vec());
+
+            let mut decoding = encoding.clone();
+            sloth.decode_parallel(&mut decoding, expanded_iv, layers);
+
+            assert_eq!(piece.to_vec(), decoding.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_data_bigger_than_prime_fails() {
+        for &bits in [256, 512, 1024, 2048, 4096].iter() {
+            let sloth = GmpSloth::init(bits);
+            let mut data = sloth.prime.clone();
+            assert!(sloth.sqrt_permutation(&mut data).is_err());
+            let mut data = Integer::from(Integer::u_pow_u(2, bits as u32)) - 1;
+            assert!(sloth.sqrt_permutation(&mut data).is_err());
+            let mut block = vec![0xffu8; sloth.block_size_bytes];
+            assert!(sloth.encode_block(&mut block).is_err());
+        }
+    }
+}
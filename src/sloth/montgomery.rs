@@ -0,0 +1,551 @@
+/// Sloth backend for 256-bit primes on fixed-width integers in Montgomery form, nothing is
+/// allocated on the heap and no big integer library is needed
+use super::{DataBiggerThanPrime, Decoder, Encoder};
+use crate::{ExpandedIV, Piece, PIECE_SIZE};
+use std::convert::TryInto;
+
+const LIMBS: usize = 4;
+const BLOCK_SIZE_BYTES: usize = LIMBS * 8;
+const BLOCKS_PER_PIECE: usize = PIECE_SIZE / BLOCK_SIZE_BYTES;
+
+/// 256-bit unsigned integer as little endian 64-bit limbs
+type U256 = [u64; LIMBS];
+
+/// Primes 2^256 - offset that are 3 mod 4, from the largest one down, the first one is the same
+/// one `GmpSloth::init(256)` finds
+const PRIME_OFFSETS: [u64; 8] = [189, 357, 617, 1053, 2757, 3473, 3905, 4017];
+
+/// 2^256 - offset as limbs
+fn prime_from_offset(offset: u64) -> U256 {
+    [offset.wrapping_neg(), u64::MAX, u64::MAX, u64::MAX]
+}
+
+fn from_bytes(bytes: &[u8]) -> U256 {
+    let mut limbs = [0u64; LIMBS];
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+        *limb = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    limbs
+}
+
+fn to_bytes(limbs: &U256, bytes: &mut [u8]) {
+    for (limb, chunk) in limbs.iter().zip(bytes.chunks_exact_mut(8)) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+}
+
+fn is_odd(a: &U256) -> bool {
+    a[0] & 1 == 1
+}
+
+fn is_zero(a: &U256) -> bool {
+    a.iter().all(|&limb| limb == 0)
+}
+
+fn less_than(a: &U256, b: &U256) -> bool {
+    for i in (0..LIMBS).rev() {
+        if a[i] != b[i] {
+            return a[i] < b[i];
+        }
+    }
+    false
+}
+
+fn xor(a: &mut U256, b: &U256) {
+    for (a, b) in a.iter_mut().zip(b.iter()) {
+        *a ^= b;
+    }
+}
+
+/// Returns a + b and whether it overflowed
+fn add(a: &U256, b: &U256) -> (U256, bool) {
+    let mut sum = [0u64; LIMBS];
+    let mut carry = false;
+    for i in 0..LIMBS {
+        let (limb, carry_1) = a[i].overflowing_add(b[i]);
+        let (limb, carry_2) = limb.overflowing_add(carry as u64);
+        sum[i] = limb;
+        carry = carry_1 || carry_2;
+    }
+    (sum, carry)
+}
+
+/// Returns a - b and whether it underflowed
+fn sub(a: &U256, b: &U256) -> (U256, bool) {
+    let mut difference = [0u64; LIMBS];
+    let mut borrow = false;
+    for i in 0..LIMBS {
+        let (limb, borrow_1) = a[i].overflowing_sub(b[i]);
+        let (limb, borrow_2) = limb.overflowing_sub(borrow as u64);
+        difference[i] = limb;
+        borrow = borrow_1 || borrow_2;
+    }
+    (difference, borrow)
+}
+
+fn shift_right(a: &mut U256, bits: u32) {
+    for i in 0..LIMBS {
+        let high = if i + 1 < LIMBS {
+            a[i + 1] << (64 - bits)
+        } else {
+            0
+        };
+        a[i] = (a[i] >> bits) | high;
+    }
+}
+
+/// Jacobi symbol (a / n) for odd n, with the binary algorithm
+fn jacobi(a: &U256, n: &U256) -> i32 {
+    let (mut a, mut n) = (*a, *n);
+    let mut result = 1;
+    while !is_zero(&a) {
+        while !is_odd(&a) {
+            shift_right(&mut a, 1);
+            if n[0] & 7 == 3 || n[0] & 7 == 5 {
+                result = -result;
+            }
+        }
+        if less_than(&a, &n) {
+            std::mem::swap(&mut a, &mut n);
+            if a[0] & 3 == 3 && n[0] & 3 == 3 {
+                result = -result;
+            }
+        }
+        a = sub(&a, &n).0;
+    }
+
+    if n == [1, 0, 0, 0] {
+        result
+    } else {
+        0
+    }
+}
+
+/// Arithmetic mod a single prime
+struct PrimeField {
+    prime: U256,
+    /// -prime^-1 mod 2^64
+    prime_inverse: u64,
+    /// R mod prime, which is 1 in Montgomery form
+    one: U256,
+    /// R^2 mod prime, converts into Montgomery form
+    r_squared: U256,
+    /// (prime + 1) / 4
+    exponent: U256,
+}
+
+impl PrimeField {
+    /// Derives the Montgomery constants and the exponent for `prime`
+    fn new(prime: U256) -> Self {
+        // Newton's iteration doubles the number of correct low bits, starting from 1 bit
+        let mut inverse = 1u64;
+        for _ in 0..6 {
+            inverse = inverse.wrapping_mul(2u64.wrapping_sub(prime[0].wrapping_mul(inverse)));
+        }
+        let prime_inverse = inverse.wrapping_neg();
+
+        // R mod prime = 2^256 - prime, then doubled 256 times to get R^2 mod prime
+        let one = sub(&[0; LIMBS], &prime).0;
+        let mut r_squared = one;
+        for _ in 0..256 {
+            let (doubled, overflow) = add(&r_squared, &r_squared);
+            r_squared = if overflow || !less_than(&doubled, &prime) {
+                sub(&doubled, &prime).0
+            } else {
+                doubled
+            };
+        }
+
+        let mut exponent = add(&prime, &[1, 0, 0, 0]).0;
+        shift_right(&mut exponent, 2);
+
+        Self {
+            prime,
+            prime_inverse,
+            one,
+            r_squared,
+            exponent,
+        }
+    }
+
+    /// Montgomery multiplication a * b * R^-1 mod prime, for a * b < prime * R
+    fn multiply(&self, a: &U256, b: &U256) -> U256 {
+        let mut t = [0u64; LIMBS + 2];
+        for i in 0..LIMBS {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let product = t[j] as u128 + a[j] as u128 * b[i] as u128 + carry as u128;
+                t[j] = product as u64;
+                carry = (product >> 64) as u64;
+            }
+            let sum = t[LIMBS] as u128 + carry as u128;
+            t[LIMBS] = sum as u64;
+            t[LIMBS + 1] = (sum >> 64) as u64;
+
+            // add a multiple of prime that clears the lowest limb, then shift it out
+            let m = t[0].wrapping_mul(self.prime_inverse);
+            let product = t[0] as u128 + m as u128 * self.prime[0] as u128;
+            let mut carry = (product >> 64) as u64;
+            for j in 1..LIMBS {
+                let product = t[j] as u128 + m as u128 * self.prime[j] as u128 + carry as u128;
+                t[j - 1] = product as u64;
+                carry = (product >> 64) as u64;
+            }
+            let sum = t[LIMBS] as u128 + carry as u128;
+            t[LIMBS - 1] = sum as u64;
+            t[LIMBS] = t[LIMBS + 1] + (sum >> 64) as u64;
+            t[LIMBS + 1] = 0;
+        }
+
+        let result = [t[0], t[1], t[2], t[3]];
+        if t[LIMBS] != 0 || !less_than(&result, &self.prime) {
+            sub(&result, &self.prime).0
+        } else {
+            result
+        }
+    }
+
+    /// base^exponent mod prime with a fixed 4-bit window
+    fn pow(&self, base: &U256, exponent: &U256) -> U256 {
+        let mut table = [self.one; 16];
+        table[1] = self.multiply(base, &self.r_squared);
+        for i in 2..16 {
+            table[i] = self.multiply(&table[i - 1], &table[1]);
+        }
+
+        let mut result = self.one;
+        for limb in exponent.iter().rev() {
+            for window in (0..16).rev() {
+                for _ in 0..4 {
+                    result = self.multiply(&result, &result);
+                }
+                result = self.multiply(&result, &table[((limb >> (window * 4)) & 0xf) as usize]);
+            }
+        }
+
+        self.multiply(&result, &[1, 0, 0, 0])
+    }
+
+    fn negate(&self, data: &mut U256) {
+        *data = sub(&self.prime, data).0;
+    }
+
+    /// Computes the modular square root of data, for data smaller than prime (w.h.p.)
+    fn sqrt_permutation(&self, data: &mut U256) -> Result<(), DataBiggerThanPrime> {
+        if !less_than(data, &self.prime) {
+            return Err(DataBiggerThanPrime);
+        }
+
+        // zero is a square (of itself), so that the permutation maps `[0, prime)` onto itself
+        if jacobi(data, &self.prime) != -1 {
+            *data = self.pow(data, &self.exponent);
+            if is_odd(data) {
+                self.negate(data);
+            }
+        } else {
+            self.negate(data);
+            *data = self.pow(data, &self.exponent);
+            if !is_odd(data) {
+                self.negate(data);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sqrt permutation for blocks smaller than prime, leaves other blocks as they are
+    fn permute(&self, data: &mut U256) {
+        if less_than(data, &self.prime) {
+            self.sqrt_permutation(data)
+                .expect("Block is smaller than prime");
+        }
+    }
+
+    /// Inverts the sqrt permutation with a single squaring mod prime, leaves blocks bigger than
+    /// prime as they are
+    fn inverse_sqrt(&self, data: &mut U256) {
+        if !less_than(data, &self.prime) {
+            return;
+        }
+
+        let is_odd = is_odd(data);
+        // (data^2 * R^-1) * R^2 * R^-1 = data^2
+        *data = self.multiply(&self.multiply(data, data), &self.r_squared);
+        if is_odd {
+            self.negate(data);
+        }
+    }
+}
+
+pub struct Sloth256 {
+    /// block `i` of a piece is permuted mod `fields[i % fields.len()]`
+    fields: Vec<PrimeField>,
+}
+
+impl Sloth256 {
+    /// Inits sloth with the largest 256-bit prime for all blocks
+    pub fn init() -> Self {
+        Self {
+            fields: vec![PrimeField::new(prime_from_offset(PRIME_OFFSETS[0]))],
+        }
+    }
+
+    /// Inits sloth that cycles through all `PRIME_OFFSETS` primes block by block, so that
+    /// hardware has to implement arithmetic mod each of them
+    pub fn with_prime_schedule() -> Self {
+        Self {
+            fields: PRIME_OFFSETS
+                .iter()
+                .map(|&offset| PrimeField::new(prime_from_offset(offset)))
+                .collect(),
+        }
+    }
+
+    fn field(&self, block_index: usize) -> &PrimeField {
+        &self.fields[block_index % self.fields.len()]
+    }
+}
+
+impl Encoder for Sloth256 {
+    fn encode_block(&self, block: &mut [u8]) -> Result<(), DataBiggerThanPrime> {
+        let mut data = from_bytes(block);
+        self.field(0).sqrt_permutation(&mut data)?;
+        to_bytes(&data, block);
+
+        Ok(())
+    }
+
+    fn encode(&self, piece: &mut Piece, expanded_iv: ExpandedIV, layers: usize) {
+        let mut blocks = [[0u64; LIMBS]; BLOCKS_PER_PIECE];
+        for (block, bytes) in blocks.iter_mut().zip(piece.chunks_exact(BLOCK_SIZE_BYTES)) {
+            *block = from_bytes(bytes);
+        }
+
+        // init feedback as expanded IV
+        let mut feedback = from_bytes(&expanded_iv);
+
+        for _ in 0..layers {
+            for (i, block) in blocks.iter_mut().enumerate() {
+                xor(block, &feedback);
+                self.field(i).permute(block);
+                feedback = *block;
+            }
+        }
+
+        for (block, bytes) in blocks.iter().zip(piece.chunks_exact_mut(BLOCK_SIZE_BYTES)) {
+            to_bytes(block, bytes);
+        }
+    }
+}
+
+impl Decoder for Sloth256 {
+    fn decode_block(&self, block: &mut [u8]) {
+        let mut data = from_bytes(block);
+        self.field(0).inverse_sqrt(&mut data);
+        to_bytes(&data, block);
+    }
+
+    fn decode(&self, piece: &mut [u8], expanded_iv: ExpandedIV, layers: usize) {
+        let mut blocks = [[0u64; LIMBS]; BLOCKS_PER_PIECE];
+        for (block, bytes) in blocks.iter_mut().zip(piece.chunks_exact(BLOCK_SIZE_BYTES)) {
+            *block = from_bytes(bytes);
+        }
+
+        for layer in 0..layers {
+            for i in (1..BLOCKS_PER_PIECE).rev() {
+                self.field(i).inverse_sqrt(&mut blocks[i]);
+                let feedback = blocks[i - 1];
+                xor(&mut blocks[i], &feedback);
+            }
+            self.field(0).inverse_sqrt(&mut blocks[0]);
+            // At this point last block is already decoded, so we can use it as an IV to previous iteration
+            if layer != layers - 1 {
+                let feedback = blocks[BLOCKS_PER_PIECE - 1];
+                xor(&mut blocks[0], &feedback);
+            }
+        }
+
+        // remove the IV (last round)
+        xor(&mut blocks[0], &from_bytes(&expanded_iv));
+
+        for (block, bytes) in blocks.iter().zip(piece.chunks_exact_mut(BLOCK_SIZE_BYTES)) {
+            to_bytes(block, bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use rand_core::SeedableRng;
+
+    /// Generator for the randomized tests, its seed is printed so that a failure can be
+    /// reproduced by running the test again with `SLOTH_TEST_SEED` set to it
+    fn seeded_rng() -> StdRng {
+        let seed = std::env::var("SLOTH_TEST_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(|| rand::thread_rng().gen());
+        println!("SLOTH_TEST_SEED={}", seed);
+        StdRng::seed_from_u64(seed)
+    }
+
+    /// Random block that is smaller than all primes
+    fn random_block(rng: &mut StdRng) -> [u8; BLOCK_SIZE_BYTES] {
+        loop {
+            let block: [u8; BLOCK_SIZE_BYTES] = rng.gen();
+            if less_than(&from_bytes(&block), &prime_from_offset(PRIME_OFFSETS[7])) {
+                return block;
+            }
+        }
+    }
+
+    fn random_piece(rng: &mut StdRng) -> Piece {
+        let mut piece = [0u8; PIECE_SIZE];
+        rng.fill(&mut piece[..]);
+        piece
+    }
+
+    fn random_expanded_iv(rng: &mut StdRng) -> ExpandedIV {
+        crypto::expand_iv(rng.gen())
+    }
+
+    #[test]
+    fn test_block_round_trip() {
+        let mut rng = seeded_rng();
+        let sloth = Sloth256::init();
+        for _ in 0..1000 {
+            let data = random_block(&mut rng);
+            let mut block = data;
+            sloth.encode_block(&mut block).unwrap();
+            sloth.decode_block(&mut block);
+            assert_eq!(data, block);
+        }
+
+        // zero is a fixed point
+        let mut block = [0u8; BLOCK_SIZE_BYTES];
+        sloth.encode_block(&mut block).unwrap();
+        assert_eq!([0u8; BLOCK_SIZE_BYTES], block);
+        sloth.decode_block(&mut block);
+        assert_eq!([0u8; BLOCK_SIZE_BYTES], block);
+    }
+
+    #[test]
+    fn test_data_bigger_than_prime() {
+        let sloth = Sloth256::init();
+        let mut block = [0xffu8; BLOCK_SIZE_BYTES];
+        assert!(sloth.encode_block(&mut block).is_err());
+        let mut block = [0u8; BLOCK_SIZE_BYTES];
+        to_bytes(&prime_from_offset(PRIME_OFFSETS[0]), &mut block);
+        assert!(sloth.encode_block(&mut block).is_err());
+
+        // decoding leaves such blocks alone, as piece encoding does
+        let data = block;
+        sloth.decode_block(&mut block);
+        assert_eq!(data, block);
+    }
+
+    #[test]
+    fn test_piece_round_trip() {
+        let mut rng = seeded_rng();
+        let expanded_iv = random_expanded_iv(&mut rng);
+        for sloth in [Sloth256::init(), Sloth256::with_prime_schedule()].iter() {
+            for &layers in [1, 2, BLOCKS_PER_PIECE].iter() {
+                let piece = random_piece(&mut rng);
+                let mut encoding = piece;
+                sloth.encode(&mut encoding, expanded_iv, layers);
+                let mut decoding = encoding;
+                sloth.decode(&mut decoding, expanded_iv, layers);
+                assert_eq!(piece.to_vec(), decoding.to_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn test_prime_schedule_changes_encoding() {
+        let mut rng = seeded_rng();
+        let expanded_iv = random_expanded_iv(&mut rng);
+        let piece = random_piece(&mut rng);
+        let mut encoding = piece;
+        Sloth256::init().encode(&mut encoding, expanded_iv, 1);
+        let mut scheduled_encoding = piece;
+        Sloth256::with_prime_schedule().encode(&mut scheduled_encoding, expanded_iv, 1);
+
+        // the first block uses the same prime, the rest don't
+        assert_eq!(
+            encoding[..BLOCK_SIZE_BYTES],
+            scheduled_encoding[..BLOCK_SIZE_BYTES]
+        );
+        assert_ne!(
+            encoding[BLOCK_SIZE_BYTES..].to_vec(),
+            scheduled_encoding[BLOCK_SIZE_BYTES..].to_vec()
+        );
+    }
+
+    #[cfg(feature = "gmp")]
+    #[test]
+    fn test_prime_offsets() {
+        use rug::{integer::IsPrime, Integer};
+
+        // search for the largest primes that are 3 mod 4
+        let mut offsets = Vec::new();
+        let mut offset = 1u64;
+        while offsets.len() < PRIME_OFFSETS.len() {
+            let prime = Integer::from(Integer::u_pow_u(2, 256)) - offset;
+            if prime.mod_u(4) == 3 && prime.is_probably_prime(25) != IsPrime::No {
+                offsets.push(offset);
+            }
+            offset += 2;
+        }
+
+        assert_eq!(PRIME_OFFSETS.to_vec(), offsets);
+    }
+
+    #[cfg(feature = "gmp")]
+    #[test]
+    fn test_matches_gmp() {
+        use crate::sloth::GmpSloth;
+
+        let mut rng = seeded_rng();
+        let sloth = Sloth256::init();
+        let gmp_sloth = GmpSloth::init(256);
+
+        let mut blocks = vec![[0u8; BLOCK_SIZE_BYTES], [0u8; BLOCK_SIZE_BYTES]];
+        blocks[1][0] = 1;
+        blocks.extend((0..1000).map(|_| random_block(&mut rng)));
+        for data in blocks {
+            let mut block = data;
+            let mut gmp_block = data;
+            sloth.encode_block(&mut block).unwrap();
+            gmp_sloth.encode_block(&mut gmp_block).unwrap();
+            assert_eq!(gmp_block, block);
+
+            sloth.decode_block(&mut block);
+            gmp_sloth.decode_block(&mut gmp_block);
+            assert_eq!(gmp_block, block);
+        }
+
+        // the all ones piece has blocks bigger than the prime under a zero IV
+        let pieces = [
+            (random_piece(&mut rng), random_expanded_iv(&mut rng)),
+            ([0xffu8; PIECE_SIZE], [0u8; BLOCK_SIZE_BYTES]),
+        ];
+        for &(piece, expanded_iv) in pieces.iter() {
+            for &layers in [1, 2].iter() {
+                let mut encoding = piece;
+                let mut gmp_encoding = piece;
+                sloth.encode(&mut encoding, expanded_iv, layers);
+                gmp_sloth.encode(&mut gmp_encoding, expanded_iv, layers);
+                assert_eq!(gmp_encoding.to_vec(), encoding.to_vec());
+
+                sloth.decode(&mut encoding, expanded_iv, layers);
+                gmp_sloth.decode(&mut gmp_encoding, expanded_iv, layers);
+                assert_eq!(gmp_encoding.to_vec(), encoding.to_vec());
+                assert_eq!(piece.to_vec(), encoding.to_vec());
+            }
+        }
+    }
+}
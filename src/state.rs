@@ -1,6 +1,9 @@
 use crate::manager::ProtocolMessage;
-use crate::{crypto, NodeID, Piece, PIECES_PER_STATE_BLOCK, PIECE_SIZE, STATE_BLOCK_SIZE_IN_BYTES};
-use async_std::sync::Sender;
+use crate::{
+    crypto, NodeID, Piece, GENESIS_PIECE_COUNT, GENESIS_STATE_BLOCKS, GENESIS_STATE_SEED,
+    PIECES_PER_STATE_BLOCK, PIECE_SIZE, STATE_BLOCK_SIZE_IN_BYTES,
+};
+use async_std::sync::{channel, Sender};
 use itertools::izip;
 use log::warn;
 use serde::{Deserialize, Serialize};
@@ -257,6 +260,26 @@ impl State {
     pub fn get_state_block_by_id(&self, id: &StateBlockId) -> Option<&StateBlock> {
         self.blocks_by_id.get(id)
     }
+
+    /// Piece merkle roots of all state blocks this node has, created or synced, by height
+    pub fn merkle_roots(&self) -> HashMap<BlockHeight, MerkleRoot> {
+        self.blocks_by_id
+            .values()
+            .map(|state_block| (state_block.height, state_block.piece_merkle_root))
+            .collect()
+    }
+}
+
+/// Piece merkle roots of genesis state blocks by height, recomputed from the genesis seed
+pub async fn genesis_merkle_roots() -> HashMap<BlockHeight, MerkleRoot> {
+    // every genesis state block is also sent here, the channel must fit all of them
+    let (state_sender, _state_receiver) = channel::<ProtocolMessage>(GENESIS_STATE_BLOCKS);
+    let mut state = State::new(state_sender);
+    state
+        .create_genesis_state(GENESIS_STATE_SEED, GENESIS_PIECE_COUNT)
+        .await;
+
+    state.merkle_roots()
 }
 
 #[cfg(test)]
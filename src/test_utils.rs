@@ -0,0 +1,32 @@
+use async_std::path::PathBuf;
+use std::fs;
+use std::ops::Deref;
+
+/// Directory under `target` for files of a single test, removed when dropped
+pub(crate) struct TargetDirectory {
+    path: PathBuf,
+}
+
+impl Drop for TargetDirectory {
+    fn drop(&mut self) {
+        drop(fs::remove_dir_all(&self.path));
+    }
+}
+
+impl Deref for TargetDirectory {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &Self::Target {
+        &self.path
+    }
+}
+
+impl TargetDirectory {
+    pub(crate) fn new(test_name: &str) -> Self {
+        let path = PathBuf::from("target").join(test_name);
+
+        fs::create_dir_all(&path).unwrap();
+
+        Self { path }
+    }
+}
@@ -1,13 +1,13 @@
 mod epoch;
 mod epoch_tracker;
+use crate::clock::SharedClock;
 use crate::farmer::FarmerMessage;
 use crate::manager::SharedLedger;
 use crate::{TIMESLOTS_PER_EPOCH, TIMESLOT_DURATION};
 use async_std::sync::Sender;
-pub use epoch::Epoch;
-pub use epoch_tracker::EpochTracker;
+pub use epoch::{confirmed_branch, Epoch};
+pub use epoch_tracker::{EpochTracker, EpochTrackerError};
 use log::*;
-use std::time::{Duration, Instant, UNIX_EPOCH};
 
 pub async fn run(
     timer_to_farmer_tx: Sender<FarmerMessage>,
@@ -16,6 +16,7 @@ pub async fn run(
     genesis_timestamp: u64,
     next_timeslot: u64,
     ledger: SharedLedger,
+    clock: SharedClock,
 ) {
     info!(
         "Starting timer with genesis timestamp {}...",
@@ -26,28 +27,23 @@ pub async fn run(
     let mut current_epoch_index = epoch_tracker.get_current_epoch().await;
     let mut next_timeslot = next_timeslot;
 
-    let genesis_instant =
-        Instant::now() - (UNIX_EPOCH.elapsed().unwrap() - Duration::from_millis(genesis_timestamp));
-
     // advance through timeslots on set interval
     loop {
-        async_std::task::sleep(
-            (next_timeslot as u32 * Duration::from_millis(TIMESLOT_DURATION))
-                .checked_sub(genesis_instant.elapsed())
-                .unwrap_or_default(),
-        )
-        .await;
+        clock
+            .sleep_until(genesis_timestamp + next_timeslot * TIMESLOT_DURATION)
+            .await;
 
         debug!("Timer has arrived on timeslot: {}", next_timeslot);
 
         {
-            ledger.lock().await.next_timeslot().await;
+            let mut locked_ledger = ledger.lock().await;
+            locked_ledger.next_timeslot().await;
 
             // We are looking to epoch boundary, but also trying not to go ahead of clock
             if next_timeslot % TIMESLOTS_PER_EPOCH == 0
                 && (current_epoch_index < next_timeslot / TIMESLOTS_PER_EPOCH)
             {
-                current_epoch_index = epoch_tracker.advance_epoch().await;
+                current_epoch_index = locked_ledger.advance_epoch().await;
 
                 debug!(
                     "Timer is creating a new empty epoch at index {}",
@@ -56,16 +52,46 @@ pub async fn run(
             }
         }
 
-        let (randomness, slot_challenge) = epoch_tracker
+        let (randomness, slot_challenge) = match epoch_tracker
             .get_slot_challenge(current_epoch_index, next_timeslot)
-            .await;
+            .await
+        {
+            Ok(challenge) => challenge,
+            Err(error) => {
+                warn!(
+                    "Cannot derive slot challenge for timeslot {}, skipping it: {:?}",
+                    next_timeslot, error
+                );
+                next_timeslot += 1;
+                continue;
+            }
+        };
 
         if is_farming {
             // TODO: This doesn't wait until we solve, so in case disk is overloaded, this
             //  will cause DoS
 
             // TODO: this should not take effect until N timeslots after the new range has been calculated
-            let solution_range = ledger.lock().await.current_solution_range;
+            let (solution_range, salt, clock_offset, exceeds_max_drift) = {
+                let locked_ledger = ledger.lock().await;
+                let offset = locked_ledger.clock.offset();
+                (
+                    locked_ledger.current_solution_range,
+                    locked_ledger.salt_at(next_timeslot),
+                    offset.median(),
+                    offset.exceeds_max_drift(),
+                )
+            };
+
+            if exceeds_max_drift {
+                // blocks we create would be rejected by peers as too early or too late
+                warn!(
+                    "Local clock is {} ms off from the network, not farming on timeslot {}",
+                    clock_offset, next_timeslot
+                );
+                next_timeslot += 1;
+                continue;
+            }
 
             timer_to_farmer_tx
                 .send(FarmerMessage::SlotChallenge {
@@ -74,6 +100,7 @@ pub async fn run(
                     randomness,
                     slot_challenge,
                     solution_range,
+                    salt,
                 })
                 .await;
         }
@@ -81,3 +108,69 @@ pub async fn run(
         next_timeslot += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::ledger::Ledger;
+    use crate::manager::ProtocolMessage;
+    use crate::sloth::{DecodingService, Sloth};
+    use crate::{crypto, state, ENCODING_LAYERS_TEST};
+    use async_std::sync::channel;
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[async_std::test]
+    async fn test_timer_steps_through_epochs_on_manual_clock() {
+        let genesis_timestamp = 1_000_000;
+        let clock = Arc::new(ManualClock::new(genesis_timestamp));
+        let epoch_tracker = EpochTracker::new().await;
+        let (state_sender, _state_receiver) = channel::<ProtocolMessage>(32);
+        let ledger = Ledger::new(
+            crypto::gen_keys_random(),
+            epoch_tracker.clone(),
+            state::State::new(state_sender),
+            clock.clone(),
+            DecodingService::new(Arc::new(Sloth::init()), ENCODING_LAYERS_TEST),
+        );
+        let ledger = Arc::new(Mutex::new(ledger));
+        let (timer_to_farmer_tx, timer_to_farmer_rx) = channel::<FarmerMessage>(32);
+
+        async_std::task::spawn(run(
+            timer_to_farmer_tx,
+            epoch_tracker,
+            true,
+            genesis_timestamp,
+            1,
+            Arc::clone(&ledger),
+            clock.clone(),
+        ));
+
+        for timeslot in 1..=(2 * TIMESLOTS_PER_EPOCH) {
+            clock.advance(Duration::from_millis(TIMESLOT_DURATION));
+
+            match timer_to_farmer_rx.recv().await.unwrap() {
+                FarmerMessage::SlotChallenge {
+                    epoch_index,
+                    timeslot: challenge_timeslot,
+                    ..
+                } => {
+                    assert_eq!(challenge_timeslot, timeslot);
+                    assert_eq!(epoch_index, timeslot / TIMESLOTS_PER_EPOCH);
+                }
+                _ => panic!("Timer only sends slot challenges"),
+            }
+        }
+
+        assert_eq!(
+            ledger.lock().await.current_timeslot,
+            2 * TIMESLOTS_PER_EPOCH
+        );
+        assert_eq!(
+            clock.now(),
+            genesis_timestamp + 2 * TIMESLOTS_PER_EPOCH * TIMESLOT_DURATION
+        );
+    }
+}
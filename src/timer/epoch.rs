@@ -1,16 +1,14 @@
 use crate::EpochChallenge;
-use crate::{crypto, ProofId};
+use crate::{crypto, ProofId, CONFIRMATION_DEPTH};
 use log::*;
-use std::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
 pub struct Epoch {
+    pub index: u64,
     /// has the randomness been derived and the epoch closed?
     pub is_closed: bool,
     /// overall randomness for this epoch
     pub randomness: EpochChallenge,
-    /// All possible branches for this epoch
-    pub branches: Vec<Vec<ProofId>>,
 }
 
 impl Epoch {
@@ -18,90 +16,118 @@ impl Epoch {
         let initial_randomness = crypto::digest_sha_256(&index.to_le_bytes());
 
         Epoch {
+            index,
             is_closed: false,
             randomness: initial_randomness,
-            branches: Vec::new(),
         }
     }
 
-    /// Add a valid proof to a branch
-    pub(super) fn add_proof(&mut self, parent_proof_id: ProofId, new_proof_id: ProofId) {
-        // expected case on first call for epoch, first entry will always create a new branch
-        if self.branches.len() == 0 {
-            self.branches.push(vec![new_proof_id]);
-            trace!("Creating the first branch for epoch_tracker");
-            return;
+    /// Close the epoch and derive randomness from `branch`, the proof ids of its proposer blocks
+    /// on the ledger's longest chain that are confirmed, see `confirmed_branch`
+    ///
+    /// Hashing the whole branch means no single farmer decides the randomness, and leaving out
+    /// blocks that are not confirmed yet means the latest proposers can't steer it by choosing
+    /// what to publish.
+    pub(super) fn close(&mut self, branch: &[ProofId]) {
+        if branch.is_empty() {
+            // TODO: should this derive base randomness instead?
+            warn!(
+                "Epoch {} has no confirmed proposer blocks, deriving randomness from its index",
+                self.index
+            );
         }
 
-        for (branch_index, branch) in self.branches.clone().iter().enumerate() {
-            for (proof_index, proof_id) in branch.iter().rev().enumerate() {
-                if *proof_id == parent_proof_id {
-                    // we have found the parent
-                    if proof_index == 0 {
-                        // expected case on subsequent calls in this epoch, extends tail of a branch
-                        self.branches[branch_index].push(new_proof_id);
-                        trace!("Extending an existing branch for epoch_tracker");
-                        return;
-                    }
-
-                    // edge case 1, forks the branch and creates a new branch from subset
-                    let mut new_branch = branch.clone();
-                    new_branch.truncate(branch.len() - proof_index);
-                    new_branch.push(new_proof_id);
-                    self.branches.push(new_branch);
-                    trace!("Creating a new branch from existing branch for epoch_tracker");
-                    return;
-                }
-            }
-        }
+        self.randomness = derive_randomness(self.index, branch);
+        self.is_closed = true;
+    }
+}
+
+/// Proof ids of the proposer blocks of epoch `epoch_index` that have at least
+/// `CONFIRMATION_DEPTH` blocks on top of them, oldest first
+///
+/// `chain` yields the proof id and epoch of each block from the head of the longest chain back
+/// towards genesis.
+pub fn confirmed_branch<I>(chain: I, epoch_index: u64) -> Vec<ProofId>
+where
+    I: Iterator<Item = (ProofId, u64)>,
+{
+    let mut branch: Vec<ProofId> = chain
+        .skip(CONFIRMATION_DEPTH)
+        .take_while(|(_, epoch)| *epoch >= epoch_index)
+        .filter(|(_, epoch)| *epoch == epoch_index)
+        .map(|(proof_id, _)| proof_id)
+        .collect();
 
-        // edge case 2, parent not found, creates a new branch
-        self.branches.push(vec![new_proof_id]);
+    branch.reverse();
+    branch
+}
+
+/// Randomness of an epoch is the hash of its index followed by proof ids of the canonical branch
+fn derive_randomness(epoch_index: u64, branch: &[ProofId]) -> EpochChallenge {
+    let mut input = Vec::with_capacity(8 + branch.len() * 32);
+    input.extend_from_slice(&epoch_index.to_le_bytes());
+    for proof_id in branch {
+        input.extend_from_slice(proof_id);
     }
+    crypto::digest_sha_256(&input)
+}
 
-    /// Close the epoch and derive randomness from the proof with most confirmations
-    pub(super) fn close(&mut self, current_epoch: u64) {
-        let mut branch_indices_by_length: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
-        self.branches
-            .iter()
-            .enumerate()
-            .for_each(|(index, branch)| {
-                branch_indices_by_length
-                    .entry(branch.len())
-                    .and_modify(|indicies| indicies.push(index))
-                    .or_insert(vec![index]);
-            });
-
-        self.randomness = match branch_indices_by_length.first_key_value() {
-            Some((_, indices)) => {
-                if indices.len() == 1 {
-                    // single branch with most confirmations, take the first proof
-                    self.branches[indices[0]]
-                        .first()
-                        .expect("Has a proof")
-                        .clone()
-                } else {
-                    // multiple branches with the same length, check to see if they share the same first proof
-                    let first_proof_id = self.branches[indices[0]].first().expect("Has a proof");
-                    for branch_index in indices.iter().skip(1) {
-                        if self.branches[*branch_index].first().expect("Has a proof")
-                            != first_proof_id
-                        {
-                            error!("Epoch cannot be  properly closed due to a deep fork");
-                        }
-                    }
-                    first_proof_id.clone()
-                }
-            }
-            None => {
-                // TODO: should this derive base randomness instead?
-                error!("Epoch cannot be closed, no new proposer blocks have been found");
-                let randomness = crypto::digest_sha_256(&current_epoch.to_le_bytes()[..]);
-                randomness
-            }
-        };
-
-        self.branches.clear();
-        self.is_closed = true;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Randomness of epoch 1 with `chain` as the longest chain, given as `(proof_id, epoch)`
+    /// oldest block first
+    fn close_with_chain(chain: &[(ProofId, u64)]) -> EpochChallenge {
+        let mut epoch = Epoch::new(1);
+        epoch.close(&confirmed_branch(chain.iter().rev().copied(), 1));
+        epoch.randomness
+    }
+
+    /// Blocks `0..count` of the longest chain, five per epoch
+    fn chain(count: u8) -> Vec<(ProofId, u64)> {
+        (0..count)
+            .map(|index| ([index; 32], index as u64 / 5))
+            .collect()
+    }
+
+    #[test]
+    fn test_randomness_follows_confirmed_blocks() {
+        // blocks 5 to 9 are in epoch 1, but only 5 and 6 have enough blocks on top of them
+        assert_eq!(6, CONFIRMATION_DEPTH);
+        assert_eq!(
+            confirmed_branch(chain(13).iter().rev().copied(), 1),
+            vec![[5u8; 32], [6u8; 32]]
+        );
+
+        let confirmed: Vec<ProofId> = (5..10).map(|index| [index; 32]).collect();
+        assert_eq!(
+            close_with_chain(&chain(16)),
+            derive_randomness(1, &confirmed)
+        );
+
+        // without confirmed blocks it falls back to the epoch index
+        assert_eq!(close_with_chain(&chain(10)), derive_randomness(1, &[]));
+    }
+
+    #[test]
+    fn test_single_block_cannot_steer_randomness() {
+        let chain = chain(14);
+        let randomness = close_with_chain(&chain);
+        assert_eq!(
+            randomness,
+            derive_randomness(1, &[[5u8; 32], [6u8; 32], [7u8; 32]])
+        );
+
+        // the first proof no longer decides the randomness on its own
+        assert_ne!(randomness, derive_randomness(1, &[[5u8; 32]]));
+
+        // none of the latest proposers, including the last ones of the epoch, can steer it by
+        // publishing a different block, since the epoch closes before their blocks are confirmed
+        for index in chain.len() - CONFIRMATION_DEPTH..chain.len() {
+            let mut ground_chain = chain.clone();
+            ground_chain[index].0 = [0xffu8; 32];
+            assert_eq!(randomness, close_with_chain(&ground_chain));
+        }
     }
 }
@@ -1,44 +1,201 @@
 use crate::timer::Epoch;
-use crate::{crypto, ProofId, CHALLENGE_LOOKBACK_EPOCHS, EPOCH_CLOSE_WAIT_TIME};
+use crate::{crypto, EpochChallenge, ProofId, CHALLENGE_LOOKBACK_EPOCHS, EPOCH_CLOSE_WAIT_TIME};
 use async_std::sync::Mutex;
 use log::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::sync::Arc;
 
+/// Size of a persisted record: epoch index (u64 LE) followed by epoch randomness
+const RANDOMNESS_RECORD_SIZE: usize = 8 + 32;
+/// Closed epochs whose randomness is kept in memory before the current one: the lookback epoch of
+/// the current epoch and of the previous one, which late blocks may still be for
+const RETAINED_EPOCHS: u64 = CHALLENGE_LOOKBACK_EPOCHS + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EpochTrackerError {
+    /// epoch used for randomness has not been closed yet, usually due to clock skew or late sync
+    EpochNotClosed(u64),
+}
+
 #[derive(Default)]
 struct Inner {
     current_epoch: u64,
+    /// epochs that are still open, closed epochs are pruned once their randomness is derived
     epochs: HashMap<u64, Epoch>,
+    /// randomness of closed epochs within `RETAINED_EPOCHS` of the current one, older epochs are
+    /// looked up in the log if persisted
+    closed_randomness: BTreeMap<u64, EpochChallenge>,
+    /// log of closed epoch randomness, if persisted
+    randomness_log: Option<RandomnessLog>,
+}
+
+/// Append-only log of the randomness of every closed epoch, such that a node syncing from genesis
+/// can look up any epoch
+struct RandomnessLog {
+    file: File,
+    /// offset of the record of each epoch in the file
+    offsets: HashMap<u64, u64>,
+    /// size of the file up to the last complete record
+    size: u64,
+}
+
+impl RandomnessLog {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let mut offsets = HashMap::new();
+        let records = contents.chunks_exact(RANDOMNESS_RECORD_SIZE);
+        let size = (records.len() * RANDOMNESS_RECORD_SIZE) as u64;
+        for (record_index, record) in records.enumerate() {
+            let epoch_index = u64::from_le_bytes(record[..8].try_into().unwrap());
+            offsets.insert(epoch_index, (record_index * RANDOMNESS_RECORD_SIZE) as u64);
+        }
+
+        // an incomplete trailing record is left over from an interrupted write, drop it so that
+        // records appended next stay aligned
+        if size < contents.len() as u64 {
+            file.set_len(size)?;
+        }
+
+        Ok(Self {
+            file,
+            offsets,
+            size,
+        })
+    }
+
+    fn contains(&self, epoch_index: u64) -> bool {
+        self.offsets.contains_key(&epoch_index)
+    }
+
+    fn append(&mut self, epoch_index: u64, randomness: EpochChallenge) -> io::Result<()> {
+        let record = [&epoch_index.to_le_bytes()[..], &randomness[..]].concat();
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.offsets.insert(epoch_index, self.size);
+        self.size += RANDOMNESS_RECORD_SIZE as u64;
+        Ok(())
+    }
+
+    fn read(&mut self, epoch_index: u64) -> io::Result<Option<EpochChallenge>> {
+        let offset = match self.offsets.get(&epoch_index) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+
+        let mut record = [0u8; RANDOMNESS_RECORD_SIZE];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut record)?;
+        Ok(Some(record[8..].try_into().unwrap()))
+    }
 }
 
 impl Inner {
-    fn advance_epoch(&mut self) -> u64 {
+    /// Returns the epoch, creating it if it was not seen before
+    fn get_or_create_epoch(&mut self, epoch_index: u64) -> &mut Epoch {
+        self.epochs
+            .entry(epoch_index)
+            .or_insert_with(|| Epoch::new(epoch_index))
+    }
+
+    fn advance_epoch<F>(&mut self, confirmed_branch: F) -> u64
+    where
+        F: FnOnce(u64) -> Vec<ProofId>,
+    {
         if self.epochs.is_empty() {
             self.current_epoch = 0;
         } else {
             self.current_epoch += 1;
         }
 
-        // Create new epoch
+        // Create new epoch, unless it was already created on request
         let current_epoch = self.current_epoch;
-        self.epochs.insert(current_epoch, Epoch::new(current_epoch));
+        self.get_or_create_epoch(current_epoch);
 
         // Close epoch at lookback offset if it exists
         if current_epoch >= EPOCH_CLOSE_WAIT_TIME {
             let close_epoch_index = current_epoch - EPOCH_CLOSE_WAIT_TIME;
-            let epoch = self.epochs.get_mut(&close_epoch_index).unwrap();
+            let mut epoch = self
+                .epochs
+                .remove(&close_epoch_index)
+                .unwrap_or_else(|| Epoch::new(close_epoch_index));
 
-            epoch.close(current_epoch);
+            let already_closed = self.closed_randomness.contains_key(&close_epoch_index)
+                || matches!(&self.randomness_log, Some(log) if log.contains(close_epoch_index));
+            if already_closed {
+                // closed before a restart and replayed during sync, the persisted randomness was
+                // derived from all of its blocks while the replayed epoch may miss some
+                debug!(
+                    "Epoch with index {} was already closed, keeping its randomness",
+                    close_epoch_index
+                );
+            } else {
+                epoch.close(&confirmed_branch(close_epoch_index));
 
-            debug!(
-                "Closed epoch with index {}, randomness is {}",
-                current_epoch - EPOCH_CLOSE_WAIT_TIME,
-                &hex::encode(epoch.randomness)[0..8]
-            );
+                debug!(
+                    "Closed epoch with index {}, randomness is {}",
+                    close_epoch_index,
+                    &hex::encode(epoch.randomness)[0..8]
+                );
+
+                self.save_randomness(close_epoch_index, epoch.randomness);
+            }
         }
 
+        self.prune_randomness(current_epoch);
+
         current_epoch
     }
+
+    fn save_randomness(&mut self, epoch_index: u64, randomness: EpochChallenge) {
+        self.closed_randomness.insert(epoch_index, randomness);
+
+        if let Some(randomness_log) = &mut self.randomness_log {
+            if let Err(error) = randomness_log.append(epoch_index, randomness) {
+                error!(
+                    "Failed to persist randomness for epoch {}: {}",
+                    epoch_index, error
+                );
+            }
+        }
+    }
+
+    /// Forgets randomness of epochs that blocks of the current epoch are no longer validated with,
+    /// persisted randomness stays in the log
+    fn prune_randomness(&mut self, current_epoch: u64) {
+        let oldest_retained = current_epoch.saturating_sub(RETAINED_EPOCHS);
+        self.closed_randomness = self.closed_randomness.split_off(&oldest_retained);
+    }
+
+    fn get_epoch_randomness(&mut self, epoch_index: u64) -> Option<EpochChallenge> {
+        if let Some(randomness) = self.closed_randomness.get(&epoch_index) {
+            return Some(*randomness);
+        }
+
+        let randomness_log = self.randomness_log.as_mut()?;
+        randomness_log
+            .read(epoch_index)
+            .map_err(|error| {
+                error!(
+                    "Failed to read randomness for epoch {}: {}",
+                    epoch_index, error
+                );
+            })
+            .ok()
+            .flatten()
+    }
 }
 
 #[derive(Clone)]
@@ -47,14 +204,36 @@ pub struct EpochTracker {
 }
 
 impl EpochTracker {
+    /// Creates a tracker that keeps randomness of closed epochs in memory only
     pub async fn new() -> Self {
-        let inner = Inner::default();
+        Self::from_inner(Inner::default()).await
+    }
+
+    /// Creates a tracker that persists randomness of closed epochs at `path`, so that it is
+    /// available for validating old blocks after a restart
+    pub async fn open_or_create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let randomness_log = RandomnessLog::open(&path.as_ref().join("epochs.bin"))?;
+
+        debug!(
+            "Loaded randomness index for {} closed epochs",
+            randomness_log.offsets.len()
+        );
+
+        let inner = Inner {
+            randomness_log: Some(randomness_log),
+            ..Inner::default()
+        };
 
+        Ok(Self::from_inner(inner).await)
+    }
+
+    async fn from_inner(inner: Inner) -> Self {
         let tracker = Self {
             inner: Arc::new(Mutex::new(inner)),
         };
 
-        tracker.advance_epoch().await;
+        // the first epoch has nothing to close
+        tracker.advance_epoch(|_| Vec::new()).await;
         tracker
     }
 
@@ -62,66 +241,177 @@ impl EpochTracker {
         self.inner.lock().await.current_epoch
     }
 
-    async fn get_epoch(&self, epoch_index: u64) -> Epoch {
-        self.inner
-            .lock()
-            .await
-            .epochs
-            .get(&epoch_index)
-            .unwrap()
-            .clone()
+    /// Randomness of a closed epoch, older epochs and epochs closed before a restart are only
+    /// available if persisted
+    pub async fn get_epoch_randomness(&self, epoch_index: u64) -> Option<EpochChallenge> {
+        self.inner.lock().await.get_epoch_randomness(epoch_index)
     }
 
     pub async fn get_slot_challenge(
         &self,
         epoch_index: u64,
         timeslot: u64,
-    ) -> ([u8; 32], [u8; 32]) {
+    ) -> Result<([u8; 32], [u8; 32]), EpochTrackerError> {
         let randomness: [u8; 32] = if epoch_index == 0 {
             // return the genesis randomness
             // TODO: make this work off the seed
             [0u8; 32]
         } else {
             // get the randomness from the previous closed epoch
-            let lookback_epoch = self
-                .get_epoch(epoch_index - CHALLENGE_LOOKBACK_EPOCHS)
-                .await;
-
-            // TODO: this can cause panic if clocks are even slightly out of sync
-            if !lookback_epoch.is_closed {
-                panic!(
-                    "Epoch {} being used for randomness is still open!",
-                    epoch_index - CHALLENGE_LOOKBACK_EPOCHS
-                );
+            let lookback_epoch_index = epoch_index - CHALLENGE_LOOKBACK_EPOCHS;
+            match self.get_epoch_randomness(lookback_epoch_index).await {
+                Some(randomness) => randomness,
+                None => {
+                    return Err(EpochTrackerError::EpochNotClosed(lookback_epoch_index));
+                }
             }
-
-            lookback_epoch.randomness
         };
 
         let slot_challenge =
             crypto::digest_sha_256(&[&randomness[..], &timeslot.to_le_bytes()[..]].concat());
-        (randomness, slot_challenge)
+        Ok((randomness, slot_challenge))
     }
 
-    /// Move to the next epoch
+    /// Move to the next epoch, closing the epoch `EPOCH_CLOSE_WAIT_TIME` epochs back with the
+    /// proof ids `confirmed_branch` returns for its index, see `Ledger::advance_epoch`
     ///
     /// Returns current epoch index
-    pub async fn advance_epoch(&self) -> u64 {
-        self.inner.lock().await.advance_epoch()
+    pub async fn advance_epoch<F>(&self, confirmed_branch: F) -> u64
+    where
+        F: FnOnce(u64) -> Vec<ProofId>,
+    {
+        self.inner.lock().await.advance_epoch(confirmed_branch)
     }
+}
 
-    pub async fn add_proof_to_epoch(
-        &self,
-        epoch_index: u64,
-        parent_proof_id: ProofId,
-        proof_id: ProofId,
-    ) {
-        self.inner
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TargetDirectory;
+    use std::fs;
+
+    #[async_std::test]
+    async fn test_unclosed_lookback_epoch_is_not_fatal() {
+        let epoch_tracker = EpochTracker::new().await;
+
+        assert_eq!(
+            epoch_tracker.get_slot_challenge(1, 32).await,
+            Err(EpochTrackerError::EpochNotClosed(0))
+        );
+
+        assert_eq!(epoch_tracker.advance_epoch(|_| Vec::new()).await, 1);
+        assert!(epoch_tracker.get_slot_challenge(1, 32).await.is_ok());
+        assert_eq!(
+            epoch_tracker.get_slot_challenge(3, 96).await,
+            Err(EpochTrackerError::EpochNotClosed(2))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_randomness_survives_restart() {
+        let path = TargetDirectory::new("test_randomness_survives_restart");
+        let records_size = || fs::metadata(path.join("epochs.bin")).unwrap().len();
+
+        let epoch_tracker = EpochTracker::open_or_create(&*path).await.unwrap();
+        for _ in 0..3 {
+            epoch_tracker.advance_epoch(|_| Vec::new()).await;
+        }
+        let randomness = epoch_tracker.get_epoch_randomness(1).await.unwrap();
+        // closed epochs are pruned, only the current one is kept
+        assert_eq!(epoch_tracker.inner.lock().await.epochs.len(), 1);
+        // and so is the randomness of epochs behind the lookback, but only from memory
+        assert!(!epoch_tracker
+            .inner
             .lock()
             .await
-            .epochs
-            .get_mut(&epoch_index)
-            .unwrap()
-            .add_proof(parent_proof_id, proof_id);
+            .closed_randomness
+            .contains_key(&0));
+        assert!(epoch_tracker.get_epoch_randomness(0).await.is_some());
+        assert_eq!(records_size(), 3 * RANDOMNESS_RECORD_SIZE as u64);
+        drop(epoch_tracker);
+
+        let epoch_tracker = EpochTracker::open_or_create(&*path).await.unwrap();
+        assert_eq!(epoch_tracker.get_current_epoch().await, 0);
+        assert_eq!(
+            epoch_tracker.get_epoch_randomness(1).await,
+            Some(randomness)
+        );
+        assert!(epoch_tracker.get_slot_challenge(3, 96).await.is_ok());
+
+        // replaying epochs after the restart keeps the persisted randomness and doesn't log it again
+        for _ in 0..2 {
+            epoch_tracker.advance_epoch(|_| Vec::new()).await;
+        }
+        assert_eq!(
+            epoch_tracker.get_epoch_randomness(1).await,
+            Some(randomness)
+        );
+        assert_eq!(records_size(), 3 * RANDOMNESS_RECORD_SIZE as u64);
+    }
+
+    #[async_std::test]
+    async fn test_randomness_log_keeps_every_epoch() {
+        let path = TargetDirectory::new("test_randomness_log_keeps_every_epoch");
+
+        let epoch_tracker = EpochTracker::open_or_create(&*path).await.unwrap();
+        for _ in 0..64 {
+            epoch_tracker.advance_epoch(|_| Vec::new()).await;
+        }
+        let current_epoch = epoch_tracker.get_current_epoch().await;
+        let closed_epochs = current_epoch - EPOCH_CLOSE_WAIT_TIME + 1;
+        let randomness: Vec<EpochChallenge> = {
+            let mut randomness = Vec::new();
+            for epoch_index in 0..closed_epochs {
+                randomness.push(
+                    epoch_tracker
+                        .get_epoch_randomness(epoch_index)
+                        .await
+                        .unwrap(),
+                );
+            }
+            randomness
+        };
+        drop(epoch_tracker);
+
+        // Leave behind what an interrupted append could
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(path.join("epochs.bin"))
+            .unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+
+        // A node syncing from genesis can look up every epoch
+        let epoch_tracker = EpochTracker::open_or_create(&*path).await.unwrap();
+        for (epoch_index, randomness) in randomness.iter().enumerate() {
+            assert_eq!(
+                epoch_tracker.get_epoch_randomness(epoch_index as u64).await,
+                Some(*randomness)
+            );
+        }
+        assert!(epoch_tracker
+            .get_slot_challenge(CHALLENGE_LOOKBACK_EPOCHS, 32)
+            .await
+            .is_ok());
+
+        // Records appended after the incomplete one can be read back
+        for _ in 0..closed_epochs + 1 {
+            epoch_tracker.advance_epoch(|_| Vec::new()).await;
+        }
+        let new_randomness = epoch_tracker
+            .get_epoch_randomness(closed_epochs)
+            .await
+            .unwrap();
+        drop(epoch_tracker);
+
+        assert_eq!(
+            fs::metadata(path.join("epochs.bin")).unwrap().len(),
+            (closed_epochs + 1) * RANDOMNESS_RECORD_SIZE as u64
+        );
+        let epoch_tracker = EpochTracker::open_or_create(&*path).await.unwrap();
+        assert_eq!(
+            epoch_tracker.get_epoch_randomness(closed_epochs).await,
+            Some(new_randomness)
+        );
     }
 }
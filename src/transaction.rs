@@ -65,7 +65,8 @@ pub enum Transaction {
 pub struct CoinbaseTx {
     /// the current coinbase reward specified by the protocol
     pub reward: AccountBalance,
-    /// hash of the public key of the farmer who created the block
+    /// hash of the public key the farmer who created the block is rewarded to, its wallet key
+    /// rather than the key of the plot the block was farmed with
     pub to_address: AccountAddress,
     /// hash of the new full block that has been created
     pub proof_id: ProofId,
@@ -109,13 +110,8 @@ impl CoinbaseTx {
             return false;
         }
 
-        // does the to_address match the farmers public key
-        if self.to_address != crypto::digest_sha_256(&proof.public_key) {
-            error!(
-                "Invalid coinbase transaction, reward address does not match farmers public key"
-            );
-            return false;
-        }
+        // the to_address is chosen by the farmer, it is bound to the block by the content
+        // signature of the plot key
 
         true
     }